@@ -0,0 +1,123 @@
+//! Runs a short match between two peer-to-peer sessions in the same process,
+//! connected directly via an in-memory transport.
+//!
+//! Each player's input is a scripted number, and the game state is a running
+//! total of every input seen. Every time a frame is confirmed, both sessions
+//! print their state for it, which is guaranteed to match.
+//!
+//! Run with `cargo run -p backroll --example echo_match`.
+
+use backroll::{
+    command::{Command, Commands},
+    transport::Peer,
+    BackrollError, Config, Event, P2PSession, Player, PlayerHandle,
+};
+use bevy_tasks::TaskPool;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const FRAMES: i32 = 120;
+
+struct EchoConfig;
+
+impl Config for EchoConfig {
+    type Input = u8;
+    type State = EchoState;
+}
+
+#[derive(Clone, Debug, Default, Hash)]
+struct EchoState {
+    frame: i32,
+    totals: [u32; 2],
+}
+
+struct Client {
+    name: &'static str,
+    session: P2PSession<EchoConfig>,
+    local: PlayerHandle,
+    state: EchoState,
+    /// The latest state saved for each frame, used to print confirmed frames.
+    history: HashMap<i32, EchoState>,
+}
+
+impl Client {
+    fn new(name: &'static str, players: Vec<Player>, pool: &TaskPool) -> Self {
+        let mut builder = P2PSession::<EchoConfig>::build().with_frame_delay(2);
+        for player in players {
+            builder.add_player(player);
+        }
+        let session = builder.start(pool.clone()).unwrap();
+        let local = session.local_player().unwrap();
+        Self {
+            name,
+            session,
+            local,
+            state: Default::default(),
+            history: HashMap::new(),
+        }
+    }
+
+    /// Runs a frame of the game loop: polls the network, then adds the local
+    /// input and advances the simulation once all players are synchronized.
+    fn tick(&mut self) {
+        let commands = self.session.poll();
+        self.run(commands);
+        if !self.session.is_synchronized() {
+            return;
+        }
+
+        let frame = self.session.current_frame();
+        let input = (frame % 10) as u8 + self.local.0 as u8 * 100;
+        match self.session.add_local_input(self.local, input) {
+            Ok(()) => {
+                let commands = self.session.advance_frame();
+                self.run(commands);
+            }
+            // The remote player is too far behind. Skip this frame.
+            Err(BackrollError::ReachedPredictionBarrier) => {}
+            Err(err) => panic!("{}: failed to add input: {:?}", self.name, err),
+        }
+    }
+
+    fn run(&mut self, commands: Commands<EchoConfig>) {
+        for command in commands {
+            match command {
+                Command::Save(save) => {
+                    self.history.insert(self.state.frame, self.state.clone());
+                    save.save(self.state.clone());
+                }
+                Command::Load(load) => self.state = load.load(),
+                Command::AdvanceFrame(inputs) => {
+                    self.state.frame += 1;
+                    for (player, total) in self.state.totals.iter_mut().enumerate() {
+                        if !inputs.is_disconnected(PlayerHandle(player)).unwrap() {
+                            *total += *inputs.get(PlayerHandle(player)).unwrap() as u32;
+                        }
+                    }
+                }
+                Command::Event(Event::ConfirmedFrameAdvanced(frame)) => {
+                    if let Some(state) = self.history.get(&frame) {
+                        println!(
+                            "[{}] confirmed frame {}: {:?}",
+                            self.name, frame, state.totals
+                        );
+                    }
+                }
+                Command::Event(event) => println!("[{}] {:?}", self.name, event),
+            }
+        }
+    }
+}
+
+fn main() {
+    let pool = TaskPool::new();
+    let (peer_a, peer_b) = Peer::create_unbounded_pair();
+    let mut a = Client::new("a", vec![Player::Local, Player::Remote(peer_a)], &pool);
+    let mut b = Client::new("b", vec![Player::Remote(peer_b), Player::Local], &pool);
+
+    while a.session.current_frame() < FRAMES || b.session.current_frame() < FRAMES {
+        a.tick();
+        b.tick();
+        std::thread::sleep(Duration::from_millis(16));
+    }
+}
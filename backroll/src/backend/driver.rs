@@ -0,0 +1,177 @@
+use super::{BackrollError, P2PSession};
+use crate::{command::Commands, Config, Frame};
+use std::time::Duration;
+use tracing::warn;
+
+/// The maximum number of frames advanced by a single call to [FixedTimestepDriver::update].
+/// Any further accumulated time is dropped, so that a long hitch does not cause the game
+/// to spend the following render frames catching up.
+pub const MAX_FRAMES_PER_UPDATE: u32 = 8;
+
+/// Drives a [P2PSession] at a fixed rate of [Config::FPS] frames per second from a
+/// variable rate render loop.
+///
+/// Each call to [update] accumulates the real time that has passed, then polls the
+/// session and advances it once for every full frame of accumulated time. Frames the
+/// session asks to skip via [Event::TimeSync] are spent waiting instead of advancing,
+/// spread out the same way as [P2PSession::apply_timesync_stall]. Frames that cannot be advanced because the session is not yet synchronized, is
+/// paused, or has reached the prediction barrier are skipped the same way. Once the
+/// limit set by [P2PSession::set_max_frames_per_poll] is reached, the remaining
+/// accumulated time is kept for the next call instead.
+///
+/// [update]: Self::update
+/// [Event::TimeSync]: crate::Event::TimeSync
+pub struct FixedTimestepDriver<T: Config> {
+    session: P2PSession<T>,
+    accumulator: Duration,
+}
+
+impl<T: Config> FixedTimestepDriver<T> {
+    pub fn new(session: P2PSession<T>) -> Self {
+        Self {
+            session,
+            accumulator: Duration::ZERO,
+        }
+    }
+
+    /// Gets the session being driven.
+    pub fn session(&self) -> &P2PSession<T> {
+        &self.session
+    }
+
+    /// Gets the duration of a single frame.
+    pub fn frame_duration() -> Duration {
+        Duration::from_secs(1) / T::FPS
+    }
+
+    /// Gets how far the accumulated time is into the next frame, from 0.0 to 1.0. This
+    /// can be passed to [P2PSession::render_state] to interpolate between frames.
+    ///
+    /// [P2PSession::render_state]: crate::P2PSession::render_state
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / Self::frame_duration().as_secs_f32()
+    }
+
+    /// Accumulates `elapsed` real time and advances the session for every full frame
+    /// that has accumulated. `input` is called with the frame number to get the local
+    /// player's input for every frame that is advanced.
+    ///
+    /// All of the returned commands must be executed in order, the same as the ones
+    /// returned from [P2PSession::poll] and [P2PSession::advance_frame].
+    ///
+    /// [P2PSession::poll]: crate::P2PSession::poll
+    /// [P2PSession::advance_frame]: crate::P2PSession::advance_frame
+    pub fn update(
+        &mut self,
+        elapsed: Duration,
+        mut input: impl FnMut(Frame) -> T::Input,
+    ) -> Commands<T> {
+        let frame_duration = Self::frame_duration();
+        self.accumulator = std::cmp::min(
+            self.accumulator + elapsed,
+            frame_duration * MAX_FRAMES_PER_UPDATE,
+        );
+
+        let mut commands = Commands::default();
+        append(&mut commands, self.session.poll());
+
+        while self.accumulator >= frame_duration {
+            self.accumulator -= frame_duration;
+            if self.session.apply_timesync_stall() {
+                continue;
+            }
+            let player = match self.session.local_player() {
+                Some(player) if self.session.is_synchronized() => player,
+                _ => continue,
+            };
+            let frame = self.session.current_frame();
+            match self.session.add_local_input(player, input(frame)) {
+                Ok(()) => append(&mut commands, self.session.advance_frame()),
+                Err(BackrollError::ReachedFrameLimit(_)) => {
+                    self.accumulator += frame_duration;
+                    break;
+                }
+                Err(BackrollError::ReachedPredictionBarrier)
+                | Err(BackrollError::NotSynchronized)
+                | Err(BackrollError::Paused) => {}
+                Err(err) => warn!("Failed to add local input for frame {}: {}", frame, err),
+            }
+        }
+        commands
+    }
+}
+
+fn append<T: Config>(commands: &mut Commands<T>, other: Commands<T>) {
+    for command in other {
+        commands.push(command);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{P2PSessionBuilder, Player, TaskPool};
+
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type Input = u32;
+        type State = u64;
+    }
+
+    fn start() -> FixedTimestepDriver<TestConfig> {
+        let mut builder = P2PSessionBuilder::<TestConfig>::new();
+        builder.add_player(Player::Local);
+        FixedTimestepDriver::new(builder.start(TaskPool::new()).unwrap())
+    }
+
+    #[test]
+    pub fn test_advances_once_per_accumulated_frame() {
+        let mut driver = start();
+        let frame = FixedTimestepDriver::<TestConfig>::frame_duration();
+        driver.update(frame / 2, |_| 0);
+        assert_eq!(driver.session().current_frame(), 0);
+        driver.update(frame / 2, |_| 0);
+        assert_eq!(driver.session().current_frame(), 1);
+        driver.update(frame * 3, |_| 0);
+        assert_eq!(driver.session().current_frame(), 4);
+    }
+
+    #[test]
+    pub fn test_long_hitches_are_capped() {
+        let mut driver = start();
+        driver.update(Duration::from_secs(10), |_| 0);
+        assert_eq!(
+            driver.session().current_frame(),
+            MAX_FRAMES_PER_UPDATE as Frame
+        );
+        assert!(driver.alpha() < 1.0);
+    }
+
+    #[test]
+    pub fn test_frame_limit_keeps_accumulated_time() {
+        let mut driver = start();
+        driver.session().set_max_frames_per_poll(Some(2));
+        let frame = FixedTimestepDriver::<TestConfig>::frame_duration();
+        driver.update(frame * 5, |_| 0);
+        assert_eq!(driver.session().current_frame(), 2);
+        assert!(driver.alpha() >= 3.0);
+        driver.update(Duration::ZERO, |_| 0);
+        assert_eq!(driver.session().current_frame(), 4);
+        driver.update(Duration::ZERO, |_| 0);
+        assert_eq!(driver.session().current_frame(), 5);
+        assert!(driver.alpha() < 1.0);
+    }
+
+    #[test]
+    pub fn test_time_sync_stalls_advancing() {
+        let mut driver = start();
+        driver.session().set_timesync_stall(2);
+        let frame = FixedTimestepDriver::<TestConfig>::frame_duration();
+        // The stalled frames are spread out instead of being skipped in a row.
+        driver.update(frame * 3, |_| 0);
+        assert_eq!(driver.session().current_frame(), 1);
+        driver.update(frame * 2, |_| 0);
+        assert_eq!(driver.session().current_frame(), 3);
+    }
+}
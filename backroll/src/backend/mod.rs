@@ -1,5 +1,10 @@
 use super::{BackrollError, BackrollResult, Player, PlayerHandle};
 
+mod driver;
 mod p2p;
 
-pub use p2p::{P2PSession, P2PSessionBuilder};
+pub use driver::{FixedTimestepDriver, MAX_FRAMES_PER_UPDATE};
+pub use p2p::{
+    DiagnosticSnapshot, P2PSession, P2PSessionBuilder, PlayerDiagnostics, PollSummary,
+    INPUT_STREAM_CAPACITY,
+};
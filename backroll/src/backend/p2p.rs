@@ -1,31 +1,37 @@
 use super::{BackrollError, BackrollResult, Player, PlayerHandle};
 use crate::{
     command::{Command, Commands},
-    input::FrameInput,
+    input::{FrameInput, GameInput},
     is_null,
-    protocol::{ConnectionStatus, Event as ProtocolEvent, Peer, PeerConfig},
+    protocol::{ConnectionStatus, DecodeError, Event as ProtocolEvent, Peer, PeerConfig},
     sync::{self, Sync},
     transport::Peer as TransportPeer,
-    Config, Event, Frame, NetworkStats, TaskPool, MAX_PLAYERS,
+    Config, DisconnectPolicy, DisconnectReason, Event, Frame, Interpolate, NetworkStats,
+    PredictionBarrierPolicy, SyncState, TaskPool, MAX_PLAYERS,
 };
 use async_channel::TryRecvError;
+use futures::Stream;
 use parking_lot::RwLock;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::debug;
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
 
 const RECOMMENDATION_INTERVAL: Frame = 240;
 const DEFAULT_FRAME_DELAY: Frame = 3;
 const DEFAULT_DISCONNECT_TIMEOUT: Duration = Duration::from_millis(5000);
 const DEFAULT_DISCONNECT_NOTIFY_START: Duration = Duration::from_millis(750);
 
+/// The maximum number of confirmed frames buffered by [P2PSession::input_stream].
+pub const INPUT_STREAM_CAPACITY: usize = 600;
+
 enum PlayerType<T>
 where
     T: Config,
 {
     Local,
     Remote {
-        peer: Peer<T>,
+        peer: Box<Peer<T>>,
         rx: async_channel::Receiver<ProtocolEvent<T::Input>>,
     },
 }
@@ -54,7 +60,10 @@ impl<T: Config> PlayerType<T> {
             Player::Local => Self::Local,
             Player::Remote(peer) => {
                 let (peer, rx) = Self::make_peer(queue, peer, builder, connect, task_pool);
-                PlayerType::<T>::Remote { peer, rx }
+                PlayerType::<T>::Remote {
+                    peer: Box::new(peer),
+                    rx,
+                }
             }
         }
     }
@@ -70,6 +79,8 @@ impl<T: Config> PlayerType<T> {
             peer: peer.clone(),
             disconnect_timeout: builder.disconnect_timeout,
             disconnect_notify_start: builder.disconnect_notify_start,
+            adaptive_notify_multiplier: builder.adaptive_notify_multiplier,
+            frame_delay: builder.frame_delay,
             task_pool: pool,
         };
 
@@ -105,9 +116,9 @@ impl<T: Config> PlayerType<T> {
         }
     }
 
-    pub fn disconnect(&mut self) {
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
         if let Some(peer) = self.peer() {
-            peer.disconnect();
+            peer.disconnect_with_reason(reason);
         }
     }
 
@@ -127,6 +138,9 @@ where
     frame_delay: Frame,
     disconnect_timeout: Duration,
     disconnect_notify_start: Duration,
+    adaptive_notify_multiplier: Option<f32>,
+    auto_frame_delay: bool,
+    network_stats_history: usize,
     marker_: std::marker::PhantomData<T>,
 }
 
@@ -152,6 +166,9 @@ where
             frame_delay: DEFAULT_FRAME_DELAY,
             disconnect_timeout: DEFAULT_DISCONNECT_TIMEOUT,
             disconnect_notify_start: DEFAULT_DISCONNECT_NOTIFY_START,
+            adaptive_notify_multiplier: None,
+            auto_frame_delay: false,
+            network_stats_history: 0,
             marker_: Default::default(),
         }
     }
@@ -175,7 +192,55 @@ where
     ///
     /// [Event]: crate::Event
     pub fn with_disconnect_notify_start(mut self, timeout: Duration) -> Self {
-        self.disconnect_timeout = timeout;
+        self.disconnect_notify_start = timeout;
+        self
+    }
+
+    /// Adapts the time the client will wait before firing a [Event::ConnectionInterrupted]
+    /// event to each connection. Once enough packets have been received from a remote
+    /// player, the wait is raised to the mean time between their packets plus
+    /// `multiplier` standard deviations, so that jittery but otherwise working
+    /// connections do not report brief interruptions. The value set by
+    /// [with_disconnect_notify_start] is still used as the minimum wait, and the wait
+    /// never exceeds the disconnect timeout.
+    ///
+    /// Disabled by default, in which case the fixed value is always used.
+    ///
+    /// [Event::ConnectionInterrupted]: crate::Event::ConnectionInterrupted
+    /// [with_disconnect_notify_start]: Self::with_disconnect_notify_start
+    pub fn with_adaptive_disconnect_notify(mut self, multiplier: f32) -> Self {
+        self.adaptive_notify_multiplier = Some(multiplier);
+        self
+    }
+
+    /// If enabled, the frame delay of the local player is set from the measured ping as
+    /// soon as the initial synchronization with all remote players completes, before
+    /// any inputs are added. The delay covers the one way trip time to the remote player
+    /// with the highest round trip time, converted to frames using [Config::FPS] the same
+    /// way as [P2PSession::set_input_delay_ms], and replaces the value set by
+    /// [with_frame_delay] for the local player.
+    ///
+    /// The frame delay of every remote player is taken from the delay that player
+    /// reports alongside its first inputs, so that all peers apply the same delay to
+    /// the same player. All players in the session must enable this for the delays to
+    /// agree. Disabled by default.
+    ///
+    /// [Config::FPS]: crate::Config::FPS
+    /// [with_frame_delay]: Self::with_frame_delay
+    pub fn auto_frame_delay_after_sync(mut self, enabled: bool) -> Self {
+        self.auto_frame_delay = enabled;
+        self
+    }
+
+    /// Keeps the last `len` [NetworkStats] snapshots of every remote player, sampled
+    /// once per call to [P2PSession::poll], for graphing trends such as ping or
+    /// bandwidth over time. See [P2PSession::network_stats_history].
+    ///
+    /// Disabled by default.
+    ///
+    /// [NetworkStats]: crate::NetworkStats
+    pub fn with_network_stats_history(mut self, len: usize) -> Self {
+        self.network_stats_history = len;
         self
     }
 
@@ -192,12 +257,147 @@ where
     /// Returns [BackrollError::MultipleLocalPlayers] if there are multiple local players.
     /// Backroll currently only supports one local player.
     ///
+    /// Returns [BackrollError::InvalidConfig] if more than [MAX_PLAYERS] players were
+    /// added, or if any of the constants in [Config] are invalid.
+    ///
     /// [BackrolLError]: crate::BackrolLError
     pub fn start(self, pool: TaskPool) -> BackrollResult<P2PSession<T>> {
         P2PSession::new_internal(self, pool)
     }
 }
 
+/// A summary of the decisions made by a [P2PSession] when polling.
+///
+/// [P2PSession]: self::P2PSession
+#[derive(Clone, Debug, Default)]
+pub struct PollSummary {
+    /// The most recent frame that all players have confirmed inputs for.
+    /// This is `None` if no frame has been confirmed yet.
+    pub confirmed_frame: Option<Frame>,
+    /// Players that were disconnected during the poll.
+    pub disconnected: Vec<PlayerHandle>,
+    /// The number of frames the local client was told to stall, if a
+    /// [Event::TimeSync] was issued during the poll.
+    ///
+    /// [Event::TimeSync]: crate::Event::TimeSync
+    pub timesync_frames_ahead: Option<u8>,
+}
+
+/// A snapshot of the internal state of a [P2PSession], intended to be attached to
+/// bug reports. The [Display] implementation prints a table with one row per player.
+///
+/// [P2PSession]: self::P2PSession
+/// [Display]: std::fmt::Display
+#[derive(Clone, Debug)]
+pub struct DiagnosticSnapshot {
+    /// The current frame of the game.
+    pub frame_count: Frame,
+    /// The most recent frame that all players have confirmed inputs for.
+    pub confirmed_frame: Option<Frame>,
+    /// Whether the session is in the middle of a rollback.
+    pub in_rollback: bool,
+    /// Whether the session is still waiting for remote players to synchronize.
+    pub synchronizing: bool,
+    /// The state of each player in the session, ordered by player handle.
+    pub players: Vec<PlayerDiagnostics>,
+}
+
+/// The per-player portion of a [DiagnosticSnapshot].
+///
+/// [DiagnosticSnapshot]: self::DiagnosticSnapshot
+#[derive(Clone, Debug)]
+pub struct PlayerDiagnostics {
+    pub handle: PlayerHandle,
+    pub is_local: bool,
+    /// Whether the player has been disconnected from the session.
+    pub disconnected: bool,
+    /// The last frame the local connect status has recorded for the player.
+    pub last_frame: Option<Frame>,
+    /// The ring buffer index of the head of the player's input queue.
+    pub queue_head: usize,
+    /// The ring buffer index of the tail of the player's input queue.
+    pub queue_tail: usize,
+    /// The number of inputs currently stored in the player's input queue.
+    pub queue_length: usize,
+    /// The most recent frame added to the player's input queue.
+    pub last_added_frame: Option<Frame>,
+    /// The first frame that was mispredicted and has yet to be rolled back.
+    pub first_incorrect_frame: Option<Frame>,
+    /// The last local input frame acknowledged by the remote player.
+    /// Always `None` for local players.
+    pub last_acked_frame: Option<Frame>,
+    /// The last local input frame sent to the remote player.
+    /// Always `None` for local players.
+    pub last_encoded_frame: Option<Frame>,
+    /// The number of local inputs that have yet to be acknowledged by the remote player.
+    /// Always zero for local players.
+    pub pending_inputs: usize,
+    /// The last input frame received from the remote player.
+    /// Always `None` for local players.
+    pub last_decoded_frame: Option<Frame>,
+    /// The largest number of inputs received from the remote player in a single packet.
+    /// Always zero for local players.
+    pub decode_high_water_mark: usize,
+}
+
+impl std::fmt::Display for DiagnosticSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn frame(frame: Option<Frame>) -> String {
+            frame
+                .map(|f| f.to_string())
+                .unwrap_or_else(|| "-".to_owned())
+        }
+
+        writeln!(
+            f,
+            "frame: {}, confirmed: {}, rollback: {}, synchronizing: {}",
+            self.frame_count,
+            frame(self.confirmed_frame),
+            self.in_rollback,
+            self.synchronizing
+        )?;
+        writeln!(
+            f,
+            "{:>6} {:>6} {:>12} {:>10} {:>5} {:>5} {:>6} {:>10} {:>9} {:>6} {:>7} {:>7} {:>7} {:>5}",
+            "player",
+            "type",
+            "disconnected",
+            "last_frame",
+            "head",
+            "tail",
+            "length",
+            "last_added",
+            "incorrect",
+            "acked",
+            "encoded",
+            "pending",
+            "decoded",
+            "peak"
+        )?;
+        for player in self.players.iter() {
+            writeln!(
+                f,
+                "{:>6} {:>6} {:>12} {:>10} {:>5} {:>5} {:>6} {:>10} {:>9} {:>6} {:>7} {:>7} {:>7} {:>5}",
+                player.handle.0,
+                if player.is_local { "local" } else { "remote" },
+                player.disconnected,
+                frame(player.last_frame),
+                player.queue_head,
+                player.queue_tail,
+                player.queue_length,
+                frame(player.last_added_frame),
+                frame(player.first_incorrect_frame),
+                frame(player.last_acked_frame),
+                frame(player.last_encoded_frame),
+                player.pending_inputs,
+                frame(player.last_decoded_frame),
+                player.decode_high_water_mark
+            )?;
+        }
+        Ok(())
+    }
+}
+
 struct P2PSessionRef<T>
 where
     T: Config,
@@ -206,7 +406,27 @@ where
     players: Vec<PlayerType<T>>,
 
     synchronizing: bool,
+    /// When the session started running, i.e. finished synchronizing with all remote
+    /// players.
+    running_since: Option<Instant>,
     next_recommended_sleep: Frame,
+    /// The number of frames left to skip from the last [Event::TimeSync].
+    timesync_stall: u8,
+    /// Whether the last call to [P2PSession::apply_timesync_stall] skipped a frame.
+    stalled_last_call: bool,
+    /// Whether local input was discarded at the prediction barrier since the last frame
+    /// advance. See [PredictionBarrierPolicy::Stall].
+    barrier_stall: bool,
+    auto_frame_delay: bool,
+    /// The last [NetworkStats] snapshots of every player, oldest first. Only
+    /// populated for remote players.
+    network_stats_history: Vec<VecDeque<NetworkStats>>,
+    network_stats_history_len: usize,
+    max_frames_per_poll: Option<usize>,
+    frames_since_poll: usize,
+    /// Bitmask of the local queues that have had inputs added since the last frame advance.
+    local_inputs_added: u8,
+    paused: bool,
 
     local_connect_status: Arc<[RwLock<ConnectionStatus>]>,
 }
@@ -216,8 +436,7 @@ impl<T: Config> P2PSessionRef<T> {
         self.players
             .iter()
             .filter(|player| player.is_remote_player())
-            .map(|player| player.peer())
-            .flatten()
+            .filter_map(|player| player.peer())
     }
 
     fn player_handle_to_queue(&self, player: PlayerHandle) -> BackrollResult<usize> {
@@ -228,10 +447,129 @@ impl<T: Config> P2PSessionRef<T> {
         Ok(offset)
     }
 
+    fn add_local_input(&mut self, player: PlayerHandle, input: T::Input) -> BackrollResult<()> {
+        if self.sync.in_rollback() {
+            return Err(BackrollError::InRollback);
+        }
+        if self.synchronizing {
+            return Err(BackrollError::NotSynchronized);
+        }
+        if self.paused {
+            return Err(BackrollError::Paused);
+        }
+        if let Some(max_frames) = self.max_frames_per_poll {
+            if self.frames_since_poll >= max_frames {
+                return Err(BackrollError::ReachedFrameLimit(max_frames));
+            }
+        }
+
+        let queue = self.player_handle_to_queue(player)?;
+        assert!(
+            self.players[queue].is_local(),
+            "{:?} is not a local player!",
+            player
+        );
+        let current_frame = self.sync.frame_count();
+        let frame = match self.sync.add_local_input(queue, input) {
+            Err(BackrollError::ReachedPredictionBarrier)
+                if T::ON_PREDICTION_BARRIER == PredictionBarrierPolicy::Stall =>
+            {
+                debug!(
+                    "Reached the prediction barrier, stalling frame {}.",
+                    current_frame
+                );
+                self.barrier_stall = true;
+                self.local_inputs_added |= 1 << queue;
+                return Ok(());
+            }
+            result => result?,
+        };
+        self.local_inputs_added |= 1 << queue;
+        if !is_null(frame) {
+            // The confirmed frame never passes the last input added by the local player,
+            // so a new local input can never change a frame that is already confirmed.
+            debug_assert!(frame >= self.sync.last_confirmed_frame());
+            // Update the local connect status state to indicate that we've got a
+            // confirmed local frame for this player.  this must come first so it
+            // gets incorporated into the next packet we send.
+
+            debug!(
+                "setting local connect status for local queue {} to {}",
+                queue, frame
+            );
+            self.local_connect_status[queue].write().last_frame = frame;
+
+            for player in self.players.iter_mut() {
+                player.send_input(FrameInput::<T::Input> { frame, input });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Advances the simulation by a frame without polling. The local inputs for the frame
+    /// must have already been added.
+    fn increment_frame(&mut self, commands: &mut Commands<T>) {
+        debug!("End of frame ({})...", self.sync.frame_count());
+        if self.synchronizing {
+            return;
+        }
+        if cfg!(debug_assertions) {
+            for (queue, player) in self.players.iter().enumerate() {
+                let expected =
+                    player.is_local() && !self.local_connect_status[queue].read().disconnected;
+                debug_assert!(
+                    !expected || self.local_inputs_added & (1 << queue) != 0,
+                    "advance_frame was called without calling add_local_input for {:?} \
+                     since the last frame advance.",
+                    PlayerHandle(queue)
+                );
+            }
+        }
+        self.local_inputs_added = 0;
+        if std::mem::take(&mut self.barrier_stall) {
+            debug!("Skipping frame advance at the prediction barrier.");
+        } else {
+            self.sync.increment_frame(commands);
+            self.frames_since_poll += 1;
+        }
+    }
+
     fn check_initial_sync(&mut self, commands: &mut Commands<T>) {
         if self.synchronizing && self.is_synchronized() {
+            if self.auto_frame_delay {
+                self.apply_auto_frame_delay();
+            }
             commands.push(Command::Event(Event::Running));
             self.synchronizing = false;
+            self.running_since = Some(Instant::now());
+        }
+    }
+
+    /// Sets the frame delay of the local players from the highest round trip time
+    /// measured during synchronization, and reports it to every remote player.
+    fn apply_auto_frame_delay(&mut self) {
+        let round_trip_time = self
+            .players()
+            .map(|peer| peer.round_trip_time())
+            .max()
+            .unwrap_or_default();
+        let delay = latency_to_frames::<T>(round_trip_time / 2);
+        debug!(
+            "Setting local frame delay to {} from round trip time {:?}.",
+            delay, round_trip_time
+        );
+        self.set_local_frame_delay(delay);
+    }
+
+    fn set_local_frame_delay(&mut self, delay: Frame) {
+        for queue in 0..self.players.len() {
+            if self.players[queue].is_local() {
+                self.sync.set_frame_delay(queue, delay);
+            }
+        }
+        for peer in self.players() {
+            peer.set_local_frame_delay(delay);
         }
     }
 
@@ -239,6 +577,7 @@ impl<T: Config> P2PSessionRef<T> {
         &mut self,
         commands: &mut Commands<T>,
         player: PlayerHandle,
+        reason: DisconnectReason,
     ) -> BackrollResult<()> {
         let queue = self.player_handle_to_queue(player)?;
         let (last_frame, disconnected) = {
@@ -261,7 +600,7 @@ impl<T: Config> P2PSessionRef<T> {
             );
             for i in 0..self.players.len() {
                 if !self.players[i].is_local() {
-                    self.disconnect_player_queue(commands, i, current_frame);
+                    self.disconnect_player_queue(commands, i, current_frame, reason);
                 }
             }
         } else {
@@ -269,24 +608,32 @@ impl<T: Config> P2PSessionRef<T> {
                 "Disconnecting queue {} at frame {} by user request.",
                 queue, last_frame
             );
-            self.disconnect_player_queue(commands, queue, last_frame);
+            self.disconnect_player_queue(commands, queue, last_frame, reason);
         }
         Ok(())
     }
 
-    fn disconnect_player_queue(&mut self, commands: &mut Commands<T>, queue: usize, syncto: Frame) {
+    fn disconnect_player_queue(
+        &mut self,
+        commands: &mut Commands<T>,
+        queue: usize,
+        syncto: Frame,
+        reason: DisconnectReason,
+    ) {
         let frame_count = self.sync.frame_count();
 
-        self.players[queue].disconnect();
+        self.players[queue].disconnect(reason);
 
         debug!("Changing queue {} local connect status for last frame from {} to {} on disconnect request (current: {}).",
                queue, self.local_connect_status[queue].read().last_frame, syncto, frame_count);
 
-        {
+        let was_disconnected = {
             let mut status = self.local_connect_status[queue].write();
+            let was_disconnected = status.disconnected;
             status.disconnected = true;
             status.last_frame = syncto;
-        }
+            was_disconnected
+        };
 
         if syncto < frame_count {
             debug!(
@@ -297,11 +644,33 @@ impl<T: Config> P2PSessionRef<T> {
             debug!("Finished adjusting simulation.");
         }
 
-        commands.push(Command::Event(Event::Disconnected(PlayerHandle(queue))));
+        commands.push(Command::Event(Event::Disconnected {
+            player: PlayerHandle(queue),
+            reason,
+        }));
+
+        if !was_disconnected && self.players[queue].is_remote_player() {
+            if T::ON_DISCONNECT == DisconnectPolicy::PauseAndVote {
+                debug!("Pausing session after queue {} disconnected.", queue);
+                self.paused = true;
+            }
+            if self.is_orphaned() {
+                commands.push(Command::Event(Event::SessionOrphaned));
+            }
+        }
 
         self.check_initial_sync(commands);
     }
 
+    /// Checks if every remote player in the session has been disconnected.
+    fn is_orphaned(&self) -> bool {
+        self.players
+            .iter()
+            .zip(self.local_connect_status.iter())
+            .filter(|(player, _)| player.is_remote_player())
+            .all(|(_, status)| status.read().disconnected)
+    }
+
     fn flush_events(&mut self, commands: &mut Commands<T>) {
         for (queue, player) in self.players.clone().iter().enumerate() {
             if let PlayerType::<T>::Remote { rx, .. } = player {
@@ -321,8 +690,14 @@ impl<T: Config> P2PSessionRef<T> {
                 Ok(evt) => self.handle_event(commands, queue, evt),
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Closed) => {
-                    self.disconnect_player(commands, PlayerHandle(queue))
+                    if !self.local_connect_status[queue].read().disconnected {
+                        self.disconnect_player(
+                            commands,
+                            PlayerHandle(queue),
+                            DisconnectReason::Unspecified,
+                        )
                         .expect("Disconnecting should not error on closing connection");
+                    }
                     break;
                 }
             }
@@ -350,8 +725,24 @@ impl<T: Config> P2PSessionRef<T> {
             ProtocolEvent::<T::Input>::Inputs(inputs) => {
                 let mut status = self.local_connect_status[queue].write();
                 if status.disconnected {
+                    // Inputs past the disconnect frame must not be applied, as peers that
+                    // did not receive them simulate the disconnected input instead.
+                    if let Some(latest) = inputs.last() {
+                        debug!(
+                            "Dropping late inputs up to frame {} for disconnected queue {} (disconnected at: {}).",
+                            latest.frame, queue, status.last_frame
+                        );
+                    }
                     return;
                 }
+                if self.auto_frame_delay && crate::is_null(status.last_frame) {
+                    let remote_delay = self.players[queue]
+                        .peer()
+                        .and_then(|peer| peer.remote_frame_delay());
+                    if let Some(delay) = remote_delay {
+                        self.sync.set_frame_delay(queue, delay);
+                    }
+                }
 
                 for input in inputs {
                     let current_remote_frame = status.last_frame;
@@ -375,6 +766,12 @@ impl<T: Config> P2PSessionRef<T> {
                 commands.push(Command::Event(Event::Synchronized(player)));
                 self.check_initial_sync(commands);
             }
+            ProtocolEvent::<T::Input>::SynchronizationFailed(reason) => {
+                commands.push(Command::Event(Event::SynchronizationFailed {
+                    player,
+                    reason,
+                }));
+            }
             ProtocolEvent::<T::Input>::NetworkInterrupted { disconnect_timeout } => {
                 commands.push(Command::Event(Event::ConnectionInterrupted {
                     player,
@@ -384,18 +781,73 @@ impl<T: Config> P2PSessionRef<T> {
             ProtocolEvent::<T::Input>::NetworkResumed => {
                 commands.push(Command::Event(Event::Synchronized(player)));
             }
+            ProtocolEvent::<T::Input>::Disconnected { reason } => {
+                let status = self.local_connect_status[queue].read().clone();
+                if !status.disconnected {
+                    debug!(
+                        "Disconnecting queue {} at frame {}: {:?}.",
+                        queue, status.last_frame, reason
+                    );
+                    self.disconnect_player_queue(commands, queue, status.last_frame, reason);
+                }
+            }
         }
     }
 
-    fn do_poll(&mut self, commands: &mut Commands<T>) {
-        if self.sync.in_rollback() {
+    fn do_poll(&mut self, commands: &mut Commands<T>) -> PollSummary {
+        let previously_disconnected: Vec<bool> = self
+            .local_connect_status
+            .iter()
+            .map(|status| status.read().disconnected)
+            .collect();
+
+        let timesync_frames_ahead = self.poll_network(commands);
+
+        let disconnected = self
+            .local_connect_status
+            .iter()
+            .zip(previously_disconnected)
+            .enumerate()
+            .filter(|(_, (status, previous))| status.read().disconnected && !previous)
+            .map(|(queue, _)| PlayerHandle(queue))
+            .collect();
+
+        PollSummary {
+            confirmed_frame: crate::non_null(self.sync.last_confirmed_frame()),
+            disconnected,
+            timesync_frames_ahead,
+        }
+    }
+
+    fn sample_network_stats(&mut self) {
+        if self.network_stats_history_len == 0 {
             return;
         }
+        for (queue, player) in self.players.iter().enumerate() {
+            if self.local_connect_status[queue].read().disconnected {
+                continue;
+            }
+            if let Some(stats) = player.get_network_stats() {
+                let history = &mut self.network_stats_history[queue];
+                if history.len() >= self.network_stats_history_len {
+                    history.pop_front();
+                }
+                history.push_back(stats);
+            }
+        }
+    }
+
+    /// Handles all pending network events and updates the confirmed frame. Returns the
+    /// number of frames to stall if a [Event::TimeSync] was issued.
+    fn poll_network(&mut self, commands: &mut Commands<T>) -> Option<u8> {
+        if self.sync.in_rollback() {
+            return None;
+        }
 
         self.flush_events(commands);
 
         if self.synchronizing {
-            return;
+            return None;
         }
 
         self.sync.check_simulation(commands);
@@ -415,17 +867,28 @@ impl<T: Config> P2PSessionRef<T> {
 
         let min_frame = if remote_player_count == 0 {
             current_frame
-        } else if self.players().count() <= 2 {
+        } else if self.players.len() <= 2 {
             self.poll_2_players(commands)
         } else {
             self.poll_n_players(commands)
         };
 
-        debug!("last confirmed frame in p2p backend is {}.", min_frame);
+        let disconnected_queues: smallvec::SmallVec<[usize; MAX_PLAYERS]> = (0..self.players.len())
+            .filter(|queue| self.local_connect_status[*queue].read().disconnected)
+            .collect();
+        debug!(
+            min_frame,
+            current_frame,
+            disconnected_queues = ?disconnected_queues.as_slice(),
+            "Confirmed frame decided."
+        );
         if min_frame >= 0 {
             debug_assert!(min_frame != Frame::MAX);
-            debug!("setting confirmed frame in sync to {}.", min_frame);
+            let previous = self.sync.last_confirmed_frame();
             self.sync.set_last_confirmed_frame(min_frame);
+            if min_frame > previous {
+                commands.push(Command::Event(Event::ConfirmedFrameAdvanced(min_frame)));
+            }
         }
 
         // send timesync notifications if now is the proper time
@@ -435,12 +898,14 @@ impl<T: Config> P2PSessionRef<T> {
                 .map(|player| player.recommend_frame_delay())
                 .max();
             if let Some(interval) = interval {
-                commands.push(Command::Event(Event::TimeSync {
-                    frames_ahead: interval as u8,
-                }));
-                self.next_recommended_sleep = current_frame + RECOMMENDATION_INTERVAL;
+                let frames_ahead = timesync_frames_ahead(interval);
+                commands.push(Command::Event(Event::TimeSync { frames_ahead }));
+                self.timesync_stall = frames_ahead;
+                self.next_recommended_sleep = current_frame.saturating_add(RECOMMENDATION_INTERVAL);
+                return Some(frames_ahead);
             }
         }
+        None
     }
 
     fn poll_2_players(&mut self, commands: &mut Commands<T>) -> Frame {
@@ -458,15 +923,17 @@ impl<T: Config> P2PSessionRef<T> {
             if !local_status.disconnected {
                 min_frame = std::cmp::min(local_status.last_frame, min_frame);
             }
-            debug!(
+            trace!(
                 "local endp: connected = {}, last_received = {}, total_min_confirmed = {}.",
-                !local_status.disconnected, local_status.last_frame, min_frame
+                !local_status.disconnected,
+                local_status.last_frame,
+                min_frame
             );
             if !queue_connected && !local_status.disconnected {
-                debug!("disconnecting player {} by remote request.", i);
-                self.disconnect_player_queue(commands, i, min_frame);
+                trace!("disconnecting player {} by remote request.", i);
+                self.disconnect_player_queue(commands, i, min_frame, DisconnectReason::Unspecified);
             }
-            debug!("min_frame = {}.", min_frame);
+            trace!("min_frame = {}.", min_frame);
         }
         min_frame
     }
@@ -477,7 +944,7 @@ impl<T: Config> P2PSessionRef<T> {
         for queue in 0..self.players.len() {
             let mut queue_connected = true;
             let mut queue_min_confirmed = Frame::MAX;
-            debug!("considering queue {}.", queue);
+            trace!("considering queue {}.", queue);
             for (i, player) in self.players.iter().enumerate() {
                 // we're going to do a lot of logic here in consideration of endpoint i.
                 // keep accumulating the minimum confirmed point for all n*n packets and
@@ -487,10 +954,10 @@ impl<T: Config> P2PSessionRef<T> {
                     let status = peer.get_peer_connect_status(queue);
                     queue_connected = queue_connected && !status.disconnected;
                     queue_min_confirmed = std::cmp::min(status.last_frame, queue_min_confirmed);
-                    debug!("endpoint {}: connected = {}, last_received = {}, queue_min_confirmed = {}.", 
+                    trace!("endpoint {}: connected = {}, last_received = {}, queue_min_confirmed = {}.", 
                           i, queue_connected, status.last_frame, queue_min_confirmed);
                 } else {
-                    debug!("endpoint {}: ignoring... not running.", i);
+                    trace!("endpoint {}: ignoring... not running.", i);
                 }
             }
 
@@ -499,9 +966,11 @@ impl<T: Config> P2PSessionRef<T> {
             if !local_status.disconnected {
                 queue_min_confirmed = std::cmp::min(local_status.last_frame, queue_min_confirmed);
             }
-            debug!(
+            trace!(
                 "local endp: connected = {}, last_received = {}, queue_min_confirmed = {}.",
-                !local_status.disconnected, local_status.last_frame, queue_min_confirmed
+                !local_status.disconnected,
+                local_status.last_frame,
+                queue_min_confirmed
             );
 
             if queue_connected {
@@ -511,11 +980,16 @@ impl<T: Config> P2PSessionRef<T> {
                 // so, we need to re-adjust.  This can happen when we detect our own disconnect at frame n
                 // and later receive a disconnect notification for frame n-1.
                 if !local_status.disconnected || local_status.last_frame > queue_min_confirmed {
-                    debug!("disconnecting queue {} by remote request.", queue);
-                    self.disconnect_player_queue(commands, queue, queue_min_confirmed);
+                    trace!("disconnecting queue {} by remote request.", queue);
+                    self.disconnect_player_queue(
+                        commands,
+                        queue,
+                        queue_min_confirmed,
+                        DisconnectReason::Unspecified,
+                    );
                 }
             }
-            debug!("min_frame = {}.", min_frame);
+            trace!("min_frame = {}.", min_frame);
         }
         min_frame
     }
@@ -535,6 +1009,49 @@ impl<T: Config> P2PSessionRef<T> {
     }
 }
 
+/// Checks the session configuration for values that would otherwise only fail
+/// once the session is running. Reports the first invalid value found.
+fn validate_config<T: Config>(player_count: usize) -> BackrollResult<()> {
+    if player_count > MAX_PLAYERS {
+        return Err(BackrollError::InvalidConfig(
+            "more than MAX_PLAYERS players were added",
+        ));
+    }
+    if T::FRAME_ADVANTAGE_WINDOW == 0 {
+        return Err(BackrollError::InvalidConfig(
+            "Config::FRAME_ADVANTAGE_WINDOW must not be zero",
+        ));
+    }
+    if T::MAX_PACKET_BYTES == 0 {
+        return Err(BackrollError::InvalidConfig(
+            "Config::MAX_PACKET_BYTES must not be zero",
+        ));
+    }
+    if T::SYNC_RETRY_INTERVAL.is_zero() {
+        return Err(BackrollError::InvalidConfig(
+            "Config::SYNC_RETRY_INTERVAL must not be zero",
+        ));
+    }
+    if T::FPS == 0 {
+        return Err(BackrollError::InvalidConfig("Config::FPS must not be zero"));
+    }
+    Ok(())
+}
+
+/// Converts an input latency to a frame delay using [Config::FPS]. Rounds to the
+/// nearest whole millisecond then frame, with exact halves rounded up, and clamps to
+/// the maximum number of frames that can be predicted.
+fn latency_to_frames<T: Config>(latency: Duration) -> Frame {
+    let frames = (latency.as_millis() as u64 * T::FPS as u64 + 500) / 1000;
+    std::cmp::min(frames, sync::MAX_PREDICTION_FRAMES as u64) as Frame
+}
+
+/// Converts a recommended frame delay to the `frames_ahead` of an [Event::TimeSync],
+/// clamping it to the range of a `u8` instead of wrapping around.
+fn timesync_frames_ahead(interval: Frame) -> u8 {
+    interval.clamp(0, u8::MAX as Frame) as u8
+}
+
 /// The main peer-to-peer Backroll session.
 ///
 /// This type internally wraps an Arc<RwLock<...>>, so it is safe to
@@ -555,6 +1072,7 @@ impl<T: Config> P2PSession<T> {
     }
 
     fn new_internal(builder: P2PSessionBuilder<T>, task_pool: TaskPool) -> BackrollResult<Self> {
+        validate_config::<T>(builder.players.len())?;
         let local_player_count = builder
             .players
             .iter()
@@ -595,7 +1113,22 @@ impl<T: Config> P2PSession<T> {
             sync,
             players,
             synchronizing,
+            running_since: if synchronizing {
+                None
+            } else {
+                Some(Instant::now())
+            },
             next_recommended_sleep: 0,
+            timesync_stall: 0,
+            stalled_last_call: false,
+            barrier_stall: false,
+            auto_frame_delay: builder.auto_frame_delay,
+            network_stats_history: vec![VecDeque::new(); player_count],
+            network_stats_history_len: builder.network_stats_history,
+            max_frames_per_poll: None,
+            frames_since_poll: 0,
+            local_inputs_added: 0,
+            paused: false,
             local_connect_status: connect_status,
         }))))
     }
@@ -616,6 +1149,194 @@ impl<T: Config> P2PSession<T> {
         self.0.read().sync.frame_count()
     }
 
+    /// Gets the number of frames simulated since the start of the session, i.e. for
+    /// showing how long a match lasted. Sessions always start at frame 0, so this is
+    /// the same as [current_frame].
+    ///
+    /// [current_frame]: Self::current_frame
+    pub fn elapsed_frames(&self) -> Frame {
+        self.current_frame()
+    }
+
+    /// Gets the wall clock time since the session started running, i.e. since
+    /// [Event::Running] was fired. Sessions without remote players start running as
+    /// soon as they are created. Returns zero while still synchronizing.
+    ///
+    /// [Event::Running]: crate::Event::Running
+    pub fn elapsed_time(&self) -> Duration {
+        self.0
+            .read()
+            .running_since
+            .map(|since| since.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Gets the most recent frame that inputs from all players have been
+    /// received for. Frames up to and including this one will never be
+    /// rolled back.
+    ///
+    /// Returns `None` if no frame has been confirmed yet.
+    pub fn confirmed_frame(&self) -> Option<Frame> {
+        crate::non_null(self.0.read().sync.last_confirmed_frame())
+    }
+
+    /// Checks if the inputs for a given frame have been received from all players.
+    /// Confirmed frames will never be rolled back, so this can be used to gate
+    /// irreversible game events.
+    pub fn is_frame_confirmed(&self, frame: Frame) -> bool {
+        self.confirmed_frame()
+            .map(|confirmed| !is_null(frame) && frame <= confirmed)
+            .unwrap_or(false)
+    }
+
+    /// Gets a copy of the state the client saved for a given frame. Only the most
+    /// recent frames are kept, so older states should be copied out when handling
+    /// [Command::Save] instead.
+    ///
+    /// Returns `None` if the state for the frame is no longer kept or has not been
+    /// saved yet.
+    ///
+    /// [Command::Save]: crate::command::Command::Save
+    pub fn saved_state(&self, frame: Frame) -> Option<T::State> {
+        self.0.read().sync.saved_state(frame)
+    }
+
+    /// Gets the inputs of all players for the newest confirmed frame, i.e.
+    /// [confirmed_frame]. These inputs will never be rolled back. Returns `None` if no
+    /// frame has been confirmed yet.
+    ///
+    /// [confirmed_frame]: Self::confirmed_frame
+    pub fn current_confirmed_input(&self) -> Option<GameInput<T::Input>> {
+        let session_ref = self.0.read();
+        let frame = crate::non_null(session_ref.sync.last_confirmed_frame())?;
+        session_ref.sync.confirmed_inputs(frame)
+    }
+
+    /// Gets the inputs that the next call to [advance_frame] would simulate the current
+    /// frame with, using predictions for any remote inputs that have not been received.
+    /// Unlike [advance_frame], this has no side effects, and can be called any number of
+    /// times, i.e. from a render path. Local inputs for the frame should be added first.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InRollback] if called during a rollback.
+    ///
+    /// Returns [BackrollError::NotSynchronized] if the all of the remote peers have not yet
+    /// synchornized.
+    ///
+    /// [advance_frame]: Self::advance_frame
+    pub fn peek_input(&self) -> BackrollResult<GameInput<T::Input>> {
+        let session_ref = self.0.read();
+        if session_ref.sync.in_rollback() {
+            return Err(BackrollError::InRollback);
+        }
+        if session_ref.synchronizing {
+            return Err(BackrollError::NotSynchronized);
+        }
+        Ok(session_ref.sync.peek_inputs())
+    }
+
+    /// Gets the players whose received inputs differed from the predicted ones in the
+    /// most recent rollback. Useful for tuning [Config::Input] prediction.
+    ///
+    /// Empty if no rollback has happened yet, or if the most recent rollback was caused
+    /// by a player disconnecting rather than by a misprediction.
+    pub fn last_rollback_cause(&self) -> Vec<PlayerHandle> {
+        self.0
+            .read()
+            .sync
+            .last_rollback_cause()
+            .iter()
+            .map(|queue| PlayerHandle(*queue))
+            .collect()
+    }
+
+    /// Gets the number of simulated frames that were advanced using at least one
+    /// predicted input, i.e. how far the current frame is ahead of the confirmed frame.
+    /// This is zero when every simulated frame has been confirmed.
+    pub fn prediction_depth(&self) -> Frame {
+        let session_ref = self.0.read();
+        let current_frame = session_ref.sync.frame_count();
+        let confirmed_frame = session_ref.sync.last_confirmed_frame();
+        std::cmp::max(0, current_frame - confirmed_frame - 1)
+    }
+
+    /// Creates a stream of the inputs for every frame as it is confirmed for all
+    /// players, in increasing frame order. Frames confirmed before this is called are
+    /// not included. The stream is fed from within [poll] and [advance_frame], the same
+    /// way as [Config::on_frame_confirmed], and ends when the session is dropped.
+    ///
+    /// At most [INPUT_STREAM_CAPACITY] frames are buffered. If the stream falls further
+    /// behind than that, it ends after the buffered frames instead of skipping frames.
+    ///
+    /// [poll]: Self::poll
+    /// [advance_frame]: Self::advance_frame
+    pub fn input_stream(&self) -> impl Stream<Item = (Frame, GameInput<T::Input>)> {
+        self.0
+            .write()
+            .sync
+            .subscribe_confirmed(INPUT_STREAM_CAPACITY)
+    }
+
+    /// Gets how many more frames can be advanced before [add_local_input] starts
+    /// returning [BackrollError::ReachedPredictionBarrier], assuming no new inputs are
+    /// received from remote players. This is zero if the barrier has been reached.
+    ///
+    /// [add_local_input]: Self::add_local_input
+    pub fn frames_until_prediction_barrier(&self) -> Frame {
+        self.0.read().sync.frames_until_prediction_barrier()
+    }
+
+    /// Checks if the session is within `threshold` frames of the prediction barrier.
+    /// Sessions that stay near the barrier are likely to stall waiting on a remote
+    /// player. See [frames_until_prediction_barrier].
+    ///
+    /// [frames_until_prediction_barrier]: Self::frames_until_prediction_barrier
+    pub fn is_near_barrier(&self, threshold: Frame) -> bool {
+        self.frames_until_prediction_barrier() <= threshold
+    }
+
+    /// Applies the stall recommended by the last [Event::TimeSync] without the caller
+    /// needing to track it. This should be called once every iteration of the game loop,
+    /// after [poll]. If it returns true, [add_local_input] and [advance_frame] should not
+    /// be called for that iteration.
+    ///
+    /// To keep the stall from being noticeable, the skipped frames are spread out by
+    /// never skipping two iterations in a row. Any remaining stall is replaced by the
+    /// next [Event::TimeSync]. The event is still issued by [poll] as normal.
+    ///
+    /// [poll]: Self::poll
+    /// [add_local_input]: Self::add_local_input
+    /// [advance_frame]: Self::advance_frame
+    /// [Event::TimeSync]: crate::Event::TimeSync
+    pub fn apply_timesync_stall(&self) -> bool {
+        let mut session_ref = self.0.write();
+        let stall = session_ref.timesync_stall > 0 && !session_ref.stalled_last_call;
+        if stall {
+            session_ref.timesync_stall -= 1;
+        }
+        session_ref.stalled_last_call = stall;
+        stall
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_timesync_stall(&self, frames: u8) {
+        self.0.write().timesync_stall = frames;
+    }
+
+    /// Gets the handle of the first local player in the session, if any. Sessions with
+    /// remote players may only have one local player, so this is the handle to pass to
+    /// [add_local_input] for those sessions.
+    ///
+    /// [add_local_input]: Self::add_local_input
+    pub fn local_player(&self) -> Option<PlayerHandle> {
+        self.0
+            .read()
+            .players
+            .iter()
+            .position(|player| player.is_local())
+            .map(PlayerHandle)
+    }
+
     pub fn local_players(&self) -> smallvec::SmallVec<[PlayerHandle; MAX_PLAYERS]> {
         self.0
             .read()
@@ -644,6 +1365,27 @@ impl<T: Config> P2PSession<T> {
         self.0.read().is_synchronized()
     }
 
+    /// Lists the remote players that are preventing the session from synchronizing,
+    /// alongside the progress of their synchronization handshake. Players that have
+    /// been disconnected do not block synchronization and are not included.
+    ///
+    /// Empty once [is_synchronized] returns true.
+    ///
+    /// [is_synchronized]: Self::is_synchronized
+    pub fn sync_blockers(&self) -> Vec<(PlayerHandle, SyncState)> {
+        let session_ref = self.0.read();
+        session_ref
+            .players
+            .iter()
+            .enumerate()
+            .filter(|(queue, _)| !session_ref.local_connect_status[*queue].read().disconnected)
+            .filter_map(|(queue, player)| {
+                let state = player.peer()?.sync_state()?;
+                Some((PlayerHandle(queue), state))
+            })
+            .collect()
+    }
+
     /// Adds a local input for the current frame. This will register the input in the local
     /// input queues, as well as queue the input to be sent to all remote players. If called multiple
     /// times for the same player without advancing the session with [advance_frame], the previously
@@ -658,48 +1400,32 @@ impl<T: Config> P2PSession<T> {
     /// Returns [BackrollError::NotSynchronized] if the all of the remote peers have not yet
     /// synchornized.
     ///
-    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point a vali
-    /// player.
+    /// Returns [BackrollError::ReachedPredictionBarrier] if the simulation is as far ahead of
+    /// the confirmed frame as it may predict, unless [Config::ON_PREDICTION_BARRIER] is set to
+    /// [PredictionBarrierPolicy::Stall].
+    ///
+    /// Returns [BackrollError::ReachedFrameLimit] if the limit set by [set_max_frames_per_poll]
+    /// has been reached since the last call to [poll].
+    ///
+    /// Returns [BackrollError::Paused] if the session was paused by a disconnect. See
+    /// [DisconnectPolicy::PauseAndVote].
+    ///
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
     ///
     /// # Panics
     /// This function will panic if the player is not a local player.
     ///
     /// [BackrollError]: crate::BackrollError
     /// [advance_frame]: self::P2PSession::advance_frame
+    /// [set_max_frames_per_poll]: self::P2PSession::set_max_frames_per_poll
+    /// [poll]: self::P2PSession::poll
+    /// [DisconnectPolicy::PauseAndVote]: crate::DisconnectPolicy::PauseAndVote
+    /// [Config::ON_PREDICTION_BARRIER]: crate::Config::ON_PREDICTION_BARRIER
+    /// [PredictionBarrierPolicy::Stall]: crate::PredictionBarrierPolicy::Stall
     pub fn add_local_input(&self, player: PlayerHandle, input: T::Input) -> BackrollResult<()> {
-        let mut session_ref = self.0.write();
-        if session_ref.sync.in_rollback() {
-            return Err(BackrollError::InRollback);
-        }
-        if session_ref.synchronizing {
-            return Err(BackrollError::NotSynchronized);
-        }
-
-        let queue = session_ref.player_handle_to_queue(player)?;
-        assert!(
-            session_ref.players[queue].is_local(),
-            "{:?} is not a local player!",
-            player
-        );
-        let frame = session_ref.sync.add_local_input(queue, input)?;
-        if !is_null(frame) {
-            // Update the local connect status state to indicate that we've got a
-            // confirmed local frame for this player.  this must come first so it
-            // gets incorporated into the next packet we send.
-
-            debug!(
-                "setting local connect status for local queue {} to {}",
-                queue, frame
-            );
-            session_ref.local_connect_status[queue].write().last_frame = frame;
-
-            for player in session_ref.players.iter_mut() {
-                player.send_input(FrameInput::<T::Input> { frame, input });
-            }
-        }
-
-        Ok(())
-    }
+        self.0.write().add_local_input(player, input)
+    }
 
     /// Advances the game simulation by a single frame. This will issue a [Command::AdvanceFrame]
     /// then check if the simulation is consistent with the inputs sent by remote players. If not, a
@@ -710,29 +1436,122 @@ impl<T: Config> P2PSession<T> {
     ///
     /// All of the provided commands must be executed in order, and must not be reordered or skipped.
     ///
+    /// # Panics
+    /// In debug builds, this function will panic if [add_local_input] has not been called for
+    /// every connected local player since the last call.
+    ///
     /// [add_local_input]: self::P2PSession::add_local_input
     /// [Command]: crate::command::Command
     pub fn advance_frame(&self) -> Commands<T> {
         let mut session_ref = self.0.write();
         let mut commands = Commands::<T>::default();
-        debug!("End of frame ({})...", session_ref.sync.frame_count());
-        if !session_ref.synchronizing {
-            session_ref.sync.increment_frame(&mut commands);
-        }
+        session_ref.increment_frame(&mut commands);
         session_ref.do_poll(&mut commands);
         commands
     }
 
+    /// Adds local inputs and advances the simulation for up to `frames` frames in a single
+    /// call, i.e. for catching up after a pause. `input` is called with each local player
+    /// and the frame number to get the player's input for every frame that is advanced.
+    /// Returns the number of frames that were advanced and the error that stopped it early,
+    /// if any, alongside the commands for all of them.
+    ///
+    /// Unlike calling [add_local_input] and [advance_frame] for every frame, the network is
+    /// only polled when the prediction barrier is reached, and once at the end. Remote
+    /// inputs, rollbacks and disconnects are only handled when polling, so skipping the
+    /// intermediate polls does not change the simulated frames.
+    ///
+    /// Stops early with [BackrollError::ReachedPredictionBarrier] if the prediction barrier
+    /// is still reached after polling, or with the error returned by [add_local_input] for
+    /// any other reason, i.e. the session is paused or has not yet synchronized. Also stops
+    /// early without an error if a player is disconnected while polling.
+    ///
+    /// Every advanced frame counts towards the limit set by [set_max_frames_per_poll], and
+    /// the polls made here do not reset it, so this stops with
+    /// [BackrollError::ReachedFrameLimit] once the limit is reached. Call [poll] first to
+    /// allow advancing up to the full limit.
+    ///
+    /// All of the provided commands must be executed in order, and must not be reordered or skipped.
+    ///
+    /// [add_local_input]: Self::add_local_input
+    /// [advance_frame]: Self::advance_frame
+    /// [set_max_frames_per_poll]: Self::set_max_frames_per_poll
+    /// [poll]: Self::poll
+    pub fn advance_frames(
+        &self,
+        frames: usize,
+        mut input: impl FnMut(PlayerHandle, Frame) -> T::Input,
+    ) -> (usize, Option<BackrollError>, Commands<T>) {
+        let mut session_ref = self.0.write();
+        let mut commands = Commands::<T>::default();
+        let mut advanced = 0;
+        let mut error = None;
+        'frames: while advanced < frames {
+            if session_ref.sync.frames_until_prediction_barrier() == 0 {
+                let summary = session_ref.do_poll(&mut commands);
+                if !summary.disconnected.is_empty() {
+                    break;
+                }
+                if session_ref.sync.frames_until_prediction_barrier() == 0 {
+                    error = Some(BackrollError::ReachedPredictionBarrier);
+                    break;
+                }
+            }
+
+            let frame = session_ref.sync.frame_count();
+            let local_players: smallvec::SmallVec<[PlayerHandle; MAX_PLAYERS]> = session_ref
+                .players
+                .iter()
+                .enumerate()
+                .filter(|(queue, player)| {
+                    player.is_local()
+                        && !session_ref.local_connect_status[*queue].read().disconnected
+                })
+                .map(|(queue, _)| PlayerHandle(queue))
+                .collect();
+            for player in local_players {
+                let result = session_ref.add_local_input(player, input(player, frame));
+                if result.is_err() || session_ref.barrier_stall {
+                    error = Some(
+                        result
+                            .err()
+                            .unwrap_or(BackrollError::ReachedPredictionBarrier),
+                    );
+                    session_ref.barrier_stall = false;
+                    session_ref.local_inputs_added = 0;
+                    break 'frames;
+                }
+            }
+            session_ref.increment_frame(&mut commands);
+            advanced += 1;
+        }
+        session_ref.do_poll(&mut commands);
+        (advanced, error, commands)
+    }
+
     /// Flushes lower level network events. This should always be called before adding local
     /// inputs every frame of the game regardless of if the game is advancing it's state or
     /// not.
     ///
     /// All of the provided commands must be executed in order, and must not be reordered or skipped.
     pub fn poll(&self) -> Commands<T> {
+        self.poll_with_summary().0
+    }
+
+    /// Identical to [poll], but also returns a [PollSummary] of the decisions made
+    /// during the poll.
+    ///
+    /// All of the provided commands must be executed in order, and must not be reordered or skipped.
+    ///
+    /// [poll]: self::P2PSession::poll
+    pub fn poll_with_summary(&self) -> (Commands<T>, PollSummary) {
         let mut session_ref = self.0.write();
         let mut commands = Commands::default();
-        session_ref.do_poll(&mut commands);
-        commands
+        session_ref.frames_since_poll = 0;
+        let summary = session_ref.do_poll(&mut commands);
+        // Only sampled here, as advancing frames also polls the network.
+        session_ref.sample_network_stats();
+        (commands, summary)
     }
 
     /// Disconnects a player from the game.
@@ -741,66 +1560,2038 @@ impl<T: Config> P2PSession<T> {
     ///
     /// If called on a remote player, this will disconnect the connection with only that player.
     ///
+    /// Identical to [disconnect_player_with_reason] with [DisconnectReason::Unspecified].
+    ///
     /// # Errors
-    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point a vali
-    /// player.
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
     ///
     /// Returns [BackrollError::PlayerDisconnected] if the provided player is already disconnected.
+    ///
+    /// [disconnect_player_with_reason]: self::P2PSession::disconnect_player_with_reason
     pub fn disconnect_player(&self, player: PlayerHandle) -> BackrollResult<Commands<T>> {
-        let mut session_ref = self.0.write();
+        self.disconnect_player_with_reason(player, DisconnectReason::Unspecified)
+    }
+
+    /// Disconnects a player from the game, notifying the affected remote peers of the
+    /// reason. Remote peers will see the reason in their [Event::Disconnected] event.
+    ///
+    /// If called on a local player, this will disconnect the client from all remote peers.
+    ///
+    /// If called on a remote player, this will disconnect the connection with only that player.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// Returns [BackrollError::PlayerDisconnected] if the provided player is already disconnected.
+    ///
+    /// [Event::Disconnected]: crate::Event::Disconnected
+    pub fn disconnect_player_with_reason(
+        &self,
+        player: PlayerHandle,
+        reason: DisconnectReason,
+    ) -> BackrollResult<Commands<T>> {
+        let mut commands = Commands::<T>::default();
+        self.0
+            .write()
+            .disconnect_player(&mut commands, player, reason)?;
+        Ok(commands)
+    }
+
+    /// Replaces the transport used to reach a remote player, i.e. after the player's NAT
+    /// rebinds to a new port mid-match. All synchronization and input state with the
+    /// player is kept, so the match continues without resynchronizing. The old transport
+    /// is disconnected.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid remote player.
+    ///
+    /// Returns [BackrollError::PlayerDisconnected] if the provided player is already disconnected.
+    pub fn replace_peer(&self, player: PlayerHandle, peer: TransportPeer) -> BackrollResult<()> {
+        let session_ref = self.0.read();
         let queue = session_ref.player_handle_to_queue(player)?;
         if session_ref.local_connect_status[queue].read().disconnected {
             return Err(BackrollError::PlayerDisconnected(player));
         }
-
-        let mut commands = Commands::<T>::default();
-        let last_frame = session_ref.local_connect_status[queue].read().last_frame;
-        if session_ref.players[queue].is_local() {
-            // The player is local. This should disconnect the local player from the rest
-            // of the game. All other players need to be disconnected.
-            // that if the endpoint is not initalized, this must be the local player.
-            let current_frame = session_ref.sync.frame_count();
-            debug!(
-                "Disconnecting local player {} at frame {} by user request.",
-                queue, last_frame
-            );
-            for i in 0..session_ref.players.len() {
-                if !session_ref.players[i].is_local() {
-                    session_ref.disconnect_player_queue(&mut commands, i, current_frame);
-                }
-            }
-        } else {
-            debug!(
-                "Disconnecting queue {} at frame {} by user request.",
-                queue, last_frame
-            );
-            session_ref.disconnect_player_queue(&mut commands, queue, last_frame);
-        }
-        Ok(commands)
+        session_ref.players[queue]
+            .peer()
+            .ok_or(BackrollError::InvalidPlayer(player))?
+            .replace_transport(peer);
+        Ok(())
     }
 
     /// Gets network statistics with a remote player.
     ///
     /// # Errors
-    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point a vali
-    /// player.
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
     pub fn get_network_stats(&self, player: PlayerHandle) -> BackrollResult<NetworkStats> {
         let session_ref = self.0.read();
         let queue = session_ref.player_handle_to_queue(player)?;
         Ok(session_ref.players[queue]
             .get_network_stats()
-            .unwrap_or_else(Default::default))
+            .unwrap_or_default())
+    }
+
+    /// Gets the recorded [NetworkStats] snapshots of a remote player, from oldest to
+    /// newest. A snapshot is taken on every call to [poll], up to the length set by
+    /// [P2PSessionBuilder::with_network_stats_history], after which the oldest snapshot
+    /// is dropped. Snapshots stop being taken once the player disconnects.
+    ///
+    /// Returns an empty history for local players, or if the history is disabled.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [poll]: Self::poll
+    pub fn network_stats_history(&self, player: PlayerHandle) -> BackrollResult<Vec<NetworkStats>> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.network_stats_history[queue]
+            .iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Resets the cumulative network statistics for a remote player, i.e. at the start of
+    /// a new match. This zeroes the packet and byte counters that [NetworkStats::kbps_sent]
+    /// is averaged over, as well as [NetworkStats::decode_errors] and the
+    /// [last_decode_error]. Live values such as [NetworkStats::ping] are unaffected. Does
+    /// nothing for local players.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [NetworkStats::kbps_sent]: crate::NetworkStats::kbps_sent
+    /// [NetworkStats::decode_errors]: crate::NetworkStats::decode_errors
+    /// [NetworkStats::ping]: crate::NetworkStats::ping
+    /// [last_decode_error]: Self::last_decode_error
+    pub fn reset_network_stats(&self, player: PlayerHandle) -> BackrollResult<()> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        if let Some(peer) = session_ref.players[queue].peer() {
+            peer.reset_network_stats();
+        }
+        Ok(())
+    }
+
+    /// Gets the most recent error encountered while decoding inputs from a remote player.
+    /// Packets that fail to decode are dropped. The total number of such failures is
+    /// available via [NetworkStats::decode_errors].
+    ///
+    /// Returns `Ok(None)` for local players or if no decode errors have occured.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [NetworkStats::decode_errors]: crate::NetworkStats::decode_errors
+    pub fn last_decode_error(&self, player: PlayerHandle) -> BackrollResult<Option<DecodeError>> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .and_then(|peer| peer.last_decode_error()))
+    }
+
+    /// Gets the number of frames the session would currently recommend waiting for a
+    /// remote player via [Event::TimeSync], alongside the round trip time that was
+    /// used to estimate how far ahead of the player the local simulation is.
+    ///
+    /// The recommendation is derived from the frame advantages averaged over the last
+    /// [Config::FRAME_ADVANTAGE_WINDOW] frames, so it may lag behind changes in the
+    /// round trip time. Returns a zero delay and round trip time for local players.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [Event::TimeSync]: crate::Event::TimeSync
+    pub fn recommended_delay_inputs(
+        &self,
+        player: PlayerHandle,
+    ) -> BackrollResult<(Frame, Duration)> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .map(|peer| (peer.recommend_frame_delay(), peer.frame_advantage_rtt()))
+            .unwrap_or((0, Duration::ZERO)))
+    }
+
+    /// Gets the frame advantage samples that are averaged to produce the recommendation
+    /// for [Event::TimeSync], from oldest to newest. Each sample is a pair of the local
+    /// frame advantage over the remote player, and the remote player's reported frame
+    /// advantage over the local one. The recommendation depends on the difference
+    /// between the two, so both are returned.
+    ///
+    /// The length is always [Config::FRAME_ADVANTAGE_WINDOW]. Returns an empty history
+    /// for local players.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [Event::TimeSync]: crate::Event::TimeSync
+    pub fn frame_advantage_history(
+        &self,
+        player: PlayerHandle,
+    ) -> BackrollResult<Vec<(Frame, Frame)>> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .map(|peer| peer.frame_advantage_history())
+            .unwrap_or_default())
+    }
+
+    /// Estimates how many more frames a remote player advances per 1000 frames than
+    /// expected at [Config::FPS], measured from the inputs received from them over the
+    /// whole session. A large positive or negative value indicates the remote player
+    /// is running a different frame rate or has a broken fixed timestep.
+    ///
+    /// Remote players that are stalled by [Event::TimeSync] or the prediction barrier
+    /// also advance more slowly, so short term values should not be relied upon.
+    /// Returns zero for local players and until inputs have been received for at least
+    /// a second.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [Event::TimeSync]: crate::Event::TimeSync
+    pub fn peer_clock_drift(&self, player: PlayerHandle) -> BackrollResult<f32> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .map(|peer| peer.clock_drift())
+            .unwrap_or(0.0))
+    }
+
+    /// Marks all local inputs up to and including `frame` as already received by a
+    /// remote player, without waiting for an acknowledgement. Inputs for those frames
+    /// will no longer be sent to the player.
+    ///
+    /// This is useful when the remote player is known to already have the inputs, i.e.
+    /// when resuming a connection, so that the next input packet only contains newer
+    /// inputs. Does nothing for local players or frames that have already been
+    /// acknowledged. The remote player must already have every input up to `frame`:
+    /// it drops any inputs that would leave a gap after the last one it received, and
+    /// the skipped inputs are never sent again.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// Returns [BackrollError::InvalidBaseline] if the frame is ahead of the latest local
+    /// input sent to the player.
+    pub fn set_input_baseline(&self, player: PlayerHandle, frame: Frame) -> BackrollResult<()> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        if let Some(peer) = session_ref.players[queue].peer() {
+            peer.set_input_baseline(frame)
+                .map_err(|_| BackrollError::InvalidBaseline(frame))?;
+        }
+        Ok(())
+    }
+
+    /// Encodes all local inputs that have not yet been acknowledged by a remote player,
+    /// exactly as they would be sent in an input packet, without sending them. Returns
+    /// the frame of the first encoded input alongside the encoded bytes.
+    ///
+    /// This allows custom transports to wrap the inputs in their own framing. Once the
+    /// remote player is known to have received the inputs, call [set_input_baseline]
+    /// to acknowledge them so they are no longer encoded or sent.
+    ///
+    /// Returns `Ok(None)` for local players or if there are no unacknowledged inputs.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [set_input_baseline]: Self::set_input_baseline
+    pub fn encode_pending_for(
+        &self,
+        player: PlayerHandle,
+    ) -> BackrollResult<Option<(Frame, Vec<u8>)>> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .and_then(|peer| peer.encode_pending()))
+    }
+
+    /// Handles a raw packet received from a remote player, for transports that are
+    /// pumped by the caller instead of through the player's [transport::Peer]. The
+    /// packet is processed the same way as one received through the peer: malformed
+    /// packets are logged and dropped. Its effects become visible after the next
+    /// call to [poll].
+    ///
+    /// Outgoing packets are still sent through the player's peer. Callers can pass
+    /// one end of a [transport::Peer::create_unbounded_pair] to the session and read
+    /// outgoing packets from the other end. Does nothing for local players.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [transport::Peer]: crate::transport::Peer
+    /// [transport::Peer::create_unbounded_pair]: crate::transport::Peer::create_unbounded_pair
+    /// [poll]: Self::poll
+    pub fn receive_packet(&self, player: PlayerHandle, bytes: &[u8]) -> BackrollResult<()> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        if let Some(peer) = session_ref.players[queue].peer() {
+            peer.receive_packet(bytes);
+        }
+        Ok(())
+    }
+
+    /// Captures every player's view of the connection status of every queue, indexed by
+    /// player handle, then by queue. Rows for remote players hold the statuses the player
+    /// last reported in its input packets. Rows for local players hold the local view.
+    ///
+    /// Queues are disconnected when any player reports them as disconnected, so when
+    /// players disconnect inconsistently across peers, comparing the rows shows which
+    /// player's report caused it.
+    pub fn connect_status_matrix(&self) -> Vec<Vec<ConnectionStatus>> {
+        let session_ref = self.0.read();
+        let local: Vec<ConnectionStatus> = session_ref
+            .local_connect_status
+            .iter()
+            .map(|status| status.read().clone())
+            .collect();
+        session_ref
+            .players
+            .iter()
+            .map(|player| match player.peer() {
+                Some(peer) => peer.peer_connect_statuses(),
+                None => local.clone(),
+            })
+            .collect()
+    }
+
+    /// Captures a snapshot of the internal synchronization state of the session for
+    /// debugging purposes.
+    pub fn diagnostic_snapshot(&self) -> DiagnosticSnapshot {
+        let session_ref = self.0.read();
+        let players = session_ref
+            .players
+            .iter()
+            .enumerate()
+            .map(|(queue, player)| {
+                let status = session_ref.local_connect_status[queue].read().clone();
+                let input_queue = session_ref.sync.input_queue(queue);
+                let peer = player.peer();
+                PlayerDiagnostics {
+                    handle: PlayerHandle(queue),
+                    is_local: player.is_local(),
+                    disconnected: status.disconnected,
+                    last_frame: crate::non_null(status.last_frame),
+                    queue_head: input_queue.head(),
+                    queue_tail: input_queue.tail(),
+                    queue_length: input_queue.len(),
+                    last_added_frame: crate::non_null(input_queue.last_confirmed_frame()),
+                    first_incorrect_frame: crate::non_null(input_queue.first_incorrect_frame()),
+                    last_acked_frame: peer.and_then(|p| crate::non_null(p.last_acked_frame())),
+                    last_encoded_frame: peer.and_then(|p| crate::non_null(p.last_encoded_frame())),
+                    pending_inputs: peer.map(|p| p.pending_input_count()).unwrap_or(0),
+                    last_decoded_frame: peer.and_then(|p| crate::non_null(p.last_decoded_frame())),
+                    decode_high_water_mark: peer.map(|p| p.decode_high_water_mark()).unwrap_or(0),
+                }
+            })
+            .collect();
+
+        DiagnosticSnapshot {
+            frame_count: session_ref.sync.frame_count(),
+            confirmed_frame: crate::non_null(session_ref.sync.last_confirmed_frame()),
+            in_rollback: session_ref.sync.in_rollback(),
+            synchronizing: session_ref.synchronizing,
+            players,
+        }
+    }
+
+    /// Limits how many frames may be advanced between calls to [poll]. Once the limit
+    /// is reached, [add_local_input] returns [BackrollError::ReachedFrameLimit] until
+    /// the next call to [poll]. This acts as a safety valve for game loops that call
+    /// [advance_frame] without any pacing. `None` removes the limit, which is the default.
+    ///
+    /// [poll]: self::P2PSession::poll
+    /// [add_local_input]: self::P2PSession::add_local_input
+    /// [advance_frame]: self::P2PSession::advance_frame
+    pub fn set_max_frames_per_poll(&self, max_frames: Option<usize>) {
+        self.0.write().max_frames_per_poll = max_frames;
+    }
+
+    /// Checks if the session has been paused by a remote player disconnecting. Only
+    /// used with [DisconnectPolicy::PauseAndVote].
+    ///
+    /// [DisconnectPolicy::PauseAndVote]: crate::DisconnectPolicy::PauseAndVote
+    pub fn is_paused(&self) -> bool {
+        self.0.read().paused
+    }
+
+    /// Resumes a session paused by a remote player disconnecting, continuing with the
+    /// remaining players. Does nothing if the session is not paused.
+    pub fn resume(&self) {
+        self.0.write().paused = false;
+    }
+
+    /// Gets how many frames of local inputs have been added but not yet sent to a
+    /// remote player. A value that keeps growing means sending is falling behind the
+    /// local simulation. This is distinct from inputs that were sent but not yet
+    /// acknowledged by the remote player. Always zero for local players.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    pub fn send_lag(&self, player: PlayerHandle) -> BackrollResult<Frame> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .map(|peer| peer.send_lag())
+            .unwrap_or(0))
+    }
+
+    /// Gets when the last packet from a remote player was received. Returns `None` for
+    /// local players, or if nothing has been received from the player yet.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    pub fn last_recv_time(&self, player: PlayerHandle) -> BackrollResult<Option<Instant>> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .and_then(|peer| peer.last_recv_time()))
+    }
+
+    /// Gets how long the session currently waits without receiving a packet from a
+    /// remote player before firing a [Event::ConnectionInterrupted] event. This is the
+    /// value set by [with_disconnect_notify_start], unless
+    /// [with_adaptive_disconnect_notify] was used. Returns `None` for local players.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    ///
+    /// [Event::ConnectionInterrupted]: crate::Event::ConnectionInterrupted
+    /// [with_disconnect_notify_start]: crate::P2PSessionBuilder::with_disconnect_notify_start
+    /// [with_adaptive_disconnect_notify]: crate::P2PSessionBuilder::with_adaptive_disconnect_notify
+    pub fn disconnect_notify_threshold(
+        &self,
+        player: PlayerHandle,
+    ) -> BackrollResult<Option<Duration>> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.players[queue]
+            .peer()
+            .map(|peer| peer.disconnect_notify_threshold()))
+    }
+
+    /// Gets the frame delay of a given player.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    pub fn frame_delay(&self, player: PlayerHandle) -> BackrollResult<Frame> {
+        let session_ref = self.0.read();
+        let queue = session_ref.player_handle_to_queue(player)?;
+        Ok(session_ref.sync.input_queue(queue).frame_delay())
     }
 
-    /// Sets the frame delay for a given player.
+    /// Sets the frame delay for a given player. The frame delay of a local player is
+    /// also reported to remote players alongside its inputs.
     ///
     /// # Errors
-    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point a vali
-    /// player.
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
     pub fn set_frame_delay(&self, player: PlayerHandle, delay: Frame) -> BackrollResult<()> {
         let mut session_ref = self.0.write();
         let queue = session_ref.player_handle_to_queue(player)?;
         session_ref.sync.set_frame_delay(queue, delay);
+        if session_ref.players[queue].is_local() {
+            for peer in session_ref.players() {
+                peer.set_local_frame_delay(delay);
+            }
+        }
         Ok(())
     }
+
+    /// Sets the frame delay for a given player from a target input latency in
+    /// milliseconds, using [Config::FPS] to convert it to frames. The latency is rounded
+    /// to the nearest whole frame, with exact halves rounded up, then clamped to at most
+    /// the maximum number of frames that can be predicted. Returns the frame delay that
+    /// was set.
+    ///
+    /// # Errors
+    /// Returns [BackrollError::InvalidPlayer] if the provided player handle does not point to a
+    /// valid player.
+    pub fn set_input_delay_ms(&self, player: PlayerHandle, ms: u32) -> BackrollResult<Frame> {
+        let delay = latency_to_frames::<T>(Duration::from_millis(ms as u64));
+        self.set_frame_delay(player, delay)?;
+        Ok(delay)
+    }
+}
+
+impl<T: Interpolate> P2PSession<T> {
+    /// Interpolates between the last two simulated states for rendering. `alpha`
+    /// is clamped to the range `[0.0, 1.0]`, where 0.0 is the previous frame and
+    /// 1.0 is the current frame.
+    ///
+    /// The returned state is render-only and must not be fed back into the
+    /// simulation.
+    ///
+    /// Returns `None` if the client has not yet saved the states for both frames.
+    pub fn render_state(&self, alpha: f32) -> Option<T::RenderState> {
+        let session_ref = self.0.read();
+        let current_frame = session_ref.sync.frame_count();
+        let from = session_ref.sync.saved_state(current_frame - 1)?;
+        let to = session_ref.sync.saved_state(current_frame)?;
+        Some(T::interpolate(&from, &to, alpha.clamp(0.0, 1.0)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Player, SynchronizationFailure};
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type Input = u32;
+        type State = u64;
+    }
+
+    /// Drops every third packet and resends synchronization packets quickly.
+    struct LossyConfig;
+
+    impl Config for LossyConfig {
+        type Input = u32;
+        type State = u64;
+
+        const SYNC_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+        const SYNC_MAX_RETRIES: Option<u32> = Some(10);
+    }
+
+    struct PauseConfig;
+
+    impl Config for PauseConfig {
+        type Input = u32;
+        type State = u64;
+
+        const ON_DISCONNECT: DisconnectPolicy = DisconnectPolicy::PauseAndVote;
+    }
+
+    struct StallConfig;
+
+    impl Config for StallConfig {
+        type Input = u32;
+        type State = u64;
+
+        const ON_PREDICTION_BARRIER: PredictionBarrierPolicy = PredictionBarrierPolicy::Stall;
+    }
+
+    fn start_session<T: Config>(players: Vec<Player>, pool: &TaskPool) -> P2PSession<T> {
+        let mut builder = P2PSessionBuilder::<T>::new();
+        for player in players {
+            builder.add_player(player);
+        }
+        builder.start(pool.clone()).unwrap()
+    }
+
+    /// Polls a session, returning only the events. Sessions that have not started
+    /// running will not issue any other commands.
+    fn poll_events<T: Config>(session: &P2PSession<T>) -> Vec<Event> {
+        session
+            .poll()
+            .into_iter()
+            .filter_map(|command| match command {
+                Command::Event(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "Relies on wall clock handshake retries. Run with --ignored."]
+    pub fn test_mismatched_player_counts_fail_to_synchronize() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        // Never connected to anything, just pads out the second session.
+        let (unused, _unused_remote) = TransportPeer::create_unbounded_pair();
+
+        let session_a =
+            start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer_a)], &pool);
+        let session_b = start_session::<TestConfig>(
+            vec![
+                Player::Remote(peer_b),
+                Player::Local,
+                Player::Remote(unused),
+            ],
+            &pool,
+        );
+
+        let mut failed = [false, false];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while failed != [true, true] && Instant::now() < deadline {
+            for (idx, session) in [&session_a, &session_b].iter().enumerate() {
+                for event in poll_events(session) {
+                    match event {
+                        Event::SynchronizationFailed { reason, .. } => {
+                            assert!(matches!(reason, SynchronizationFailure::PlayerCount { .. }));
+                            failed[idx] = true;
+                        }
+                        Event::Synchronized(_) => panic!("Mismatched sessions synchronized."),
+                        _ => {}
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(failed, [true, true]);
+        assert!(!session_b.is_synchronized());
+    }
+
+    #[test]
+    pub fn test_invalid_config_is_rejected_on_start() {
+        struct ZeroWindowConfig;
+
+        impl Config for ZeroWindowConfig {
+            type Input = u32;
+            type State = u64;
+
+            const FRAME_ADVANTAGE_WINDOW: usize = 0;
+        }
+
+        let pool = TaskPool::new();
+        let mut builder = P2PSessionBuilder::<ZeroWindowConfig>::new();
+        builder.add_player(Player::Local);
+        assert!(matches!(
+            builder.start(pool.clone()),
+            Err(BackrollError::InvalidConfig(_))
+        ));
+
+        let mut builder = P2PSessionBuilder::<TestConfig>::new();
+        for _ in 0..=MAX_PLAYERS {
+            let (peer, _remote) = TransportPeer::create_unbounded_pair();
+            builder.add_player(Player::Remote(peer));
+        }
+        assert!(matches!(
+            builder.start(pool),
+            Err(BackrollError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_local_player_is_found_by_handle() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Remote(peer), Player::Local], &pool);
+        assert_eq!(session.local_player().map(|handle| handle.0), Some(1));
+
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Remote(peer)], &pool);
+        assert!(session.local_player().is_none());
+    }
+
+    /// Forwards all pending packets from one relay endpoint to another, dropping
+    /// every third packet.
+    fn relay_lossy(from: &TransportPeer, to: &TransportPeer, count: &mut usize) {
+        while let Ok(packet) = from.try_recv() {
+            *count += 1;
+            if !count.is_multiple_of(3) {
+                let _ = to.try_send(packet);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "Relies on wall clock handshake retries. Run with --ignored."]
+    pub fn test_handshake_completes_over_lossy_link() {
+        let pool = TaskPool::new();
+        let (peer_a, relay_a) = TransportPeer::create_unbounded_pair();
+        let (peer_b, relay_b) = TransportPeer::create_unbounded_pair();
+
+        let session_a =
+            start_session::<LossyConfig>(vec![Player::Local, Player::Remote(peer_a)], &pool);
+        let session_b =
+            start_session::<LossyConfig>(vec![Player::Remote(peer_b), Player::Local], &pool);
+
+        let mut sent = [0, 0];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !(session_a.is_synchronized() && session_b.is_synchronized())
+            && Instant::now() < deadline
+        {
+            relay_lossy(&relay_a, &relay_b, &mut sent[0]);
+            relay_lossy(&relay_b, &relay_a, &mut sent[1]);
+            for session in [&session_a, &session_b] {
+                for event in poll_events(session) {
+                    if let Event::SynchronizationFailed { reason, .. } = event {
+                        panic!("Handshake failed: {:?}", reason);
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        assert!(session_a.is_synchronized());
+        assert!(session_b.is_synchronized());
+    }
+
+    #[test]
+    #[ignore = "Relies on wall clock handshake retries. Run with --ignored."]
+    pub fn test_handshake_fails_after_max_retries() {
+        let pool = TaskPool::new();
+        // The remote end is never read from, so no packet is ever answered.
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session =
+            start_session::<LossyConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+
+        let mut failure = None;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while failure.is_none() && Instant::now() < deadline {
+            for event in poll_events(&session) {
+                if let Event::SynchronizationFailed { reason, .. } = event {
+                    failure = Some(reason);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            failure,
+            Some(SynchronizationFailure::RetriesExhausted { retries: 10 })
+        );
+    }
+
+    #[test]
+    pub fn test_remote_disconnect_pauses_until_resumed() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session =
+            start_session::<PauseConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        assert!(!session.is_paused());
+
+        session.disconnect_player(PlayerHandle(1)).unwrap();
+        assert!(session.is_paused());
+        assert!(matches!(
+            session.add_local_input(PlayerHandle(0), 1),
+            Err(BackrollError::Paused)
+        ));
+
+        session.resume();
+        assert!(!session.is_paused());
+        session.add_local_input(PlayerHandle(0), 1).unwrap();
+    }
+
+    #[test]
+    pub fn test_confirmed_frame_advance_is_reported_once() {
+        let pool = TaskPool::new();
+        let session = start_session::<TestConfig>(vec![Player::Local], &pool);
+        let confirmed = |events: Vec<Event>| -> Vec<Frame> {
+            events
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::ConfirmedFrameAdvanced(frame) => Some(frame),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert_eq!(confirmed(poll_events(&session)), vec![0]);
+        assert_eq!(confirmed(poll_events(&session)), vec![]);
+        // Advancing a frame also polls the session.
+        for frame in 1..3 {
+            session.add_local_input(PlayerHandle(0), 1).unwrap();
+            let events = session
+                .advance_frame()
+                .into_iter()
+                .filter_map(|command| match command {
+                    Command::Event(event) => Some(event),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(confirmed(events), vec![frame]);
+        }
+        assert_eq!(confirmed(poll_events(&session)), vec![]);
+    }
+
+    #[test]
+    pub fn test_input_stream_ends_when_it_falls_behind() {
+        use futures::{FutureExt, StreamExt};
+
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        let session = builder.start(TaskPool::new()).unwrap();
+        let mut stream = Box::pin(session.input_stream());
+        for _ in 0..INPUT_STREAM_CAPACITY + 2 {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            run_commands(session.advance_frame());
+        }
+
+        let mut received = 0;
+        while let Some(Some((frame, _))) = stream.next().now_or_never() {
+            assert_eq!(frame, received as Frame);
+            received += 1;
+        }
+        assert_eq!(received, INPUT_STREAM_CAPACITY);
+        assert!(matches!(stream.next().now_or_never(), Some(None)));
+    }
+
+    #[test]
+    pub fn test_input_stream_yields_confirmed_frames() {
+        use futures::{FutureExt, StreamExt};
+
+        let pool = TaskPool::new();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        let session = builder.start(pool).unwrap();
+        let mut stream = Box::pin(session.input_stream());
+        for input in 1..4 {
+            session.add_local_input(PlayerHandle(0), input).unwrap();
+            session.advance_frame();
+        }
+        poll_events(&session);
+
+        let mut confirmed = Vec::new();
+        while let Some(Some((frame, inputs))) = stream.next().now_or_never() {
+            assert_eq!(frame, inputs.frame);
+            confirmed.push(*inputs.get(PlayerHandle(0)).unwrap());
+        }
+        assert_eq!(confirmed, vec![1, 2, 3]);
+    }
+
+    #[test]
+    pub fn test_current_confirmed_input_tracks_newest_confirmed_frame() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        let session_a =
+            start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer_a)], &pool);
+        let session_b =
+            start_session::<TestConfig>(vec![Player::Remote(peer_b), Player::Local], &pool);
+        assert!(session_a.current_confirmed_input().is_none());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while session_a.confirmed_frame().unwrap_or(-1) < 10 && Instant::now() < deadline {
+            for (player, session) in [&session_a, &session_b].iter().enumerate() {
+                session.poll();
+                if session.is_synchronized()
+                    && session
+                        .add_local_input(PlayerHandle(player), player as u32 + 1)
+                        .is_ok()
+                {
+                    session.advance_frame();
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let inputs = session_a.current_confirmed_input().unwrap();
+        assert_eq!(Some(inputs.frame), session_a.confirmed_frame());
+        assert_eq!(*inputs.get(PlayerHandle(0)).unwrap(), 1);
+        assert_eq!(*inputs.get(PlayerHandle(1)).unwrap(), 2);
+    }
+
+    #[test]
+    pub fn test_silent_peer_is_interrupted_then_disconnected() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>| {
+            let mut builder = P2PSessionBuilder::<TestConfig>::new()
+                .with_disconnect_timeout(Duration::from_millis(1000));
+            for player in players {
+                builder.add_player(player);
+            }
+            builder.start(pool.clone()).unwrap()
+        };
+        let session_a = start(vec![Player::Local, Player::Remote(peer_a.clone())]);
+        let session_b = start(vec![Player::Remote(peer_b), Player::Local]);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !(session_a.is_synchronized() && session_b.is_synchronized())
+            && Instant::now() < deadline
+        {
+            poll_events(&session_a);
+            poll_events(&session_b);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(session_a.is_synchronized());
+
+        peer_a.set_paused(true);
+        let mut interrupted = false;
+        let mut disconnected = None;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while disconnected.is_none() && Instant::now() < deadline {
+            for event in poll_events(&session_a) {
+                match event {
+                    Event::ConnectionInterrupted { .. } => interrupted = true,
+                    Event::Disconnected { reason, .. } => disconnected = Some(reason),
+                    _ => {}
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(interrupted);
+        assert_eq!(disconnected, Some(DisconnectReason::Timeout));
+    }
+
+    #[test]
+    pub fn test_input_delay_ms_rounds_to_nearest_frame() {
+        let pool = TaskPool::new();
+        let session = start_session::<TestConfig>(vec![Player::Local], &pool);
+        let player = PlayerHandle(0);
+        assert_eq!(session.set_input_delay_ms(player, 0).unwrap(), 0);
+        assert_eq!(session.set_input_delay_ms(player, 8).unwrap(), 0);
+        assert_eq!(session.set_input_delay_ms(player, 9).unwrap(), 1);
+        assert_eq!(session.set_input_delay_ms(player, 50).unwrap(), 3);
+        assert_eq!(session.set_input_delay_ms(player, 10_000).unwrap(), 8);
+        assert!(session.set_input_delay_ms(PlayerHandle(1), 50).is_err());
+    }
+
+    #[test]
+    pub fn test_disconnect_notify_threshold_uses_builder_value() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new()
+            .with_disconnect_timeout(Duration::from_millis(2000))
+            .with_disconnect_notify_start(Duration::from_millis(300))
+            .with_adaptive_disconnect_notify(4.0);
+        builder.add_player(Player::Local);
+        builder.add_player(Player::Remote(peer));
+        let session = builder.start(pool).unwrap();
+        assert_eq!(
+            session
+                .disconnect_notify_threshold(PlayerHandle(0))
+                .unwrap(),
+            None
+        );
+        // Nothing has been received yet, so the fixed value is used.
+        assert_eq!(
+            session
+                .disconnect_notify_threshold(PlayerHandle(1))
+                .unwrap(),
+            Some(Duration::from_millis(300))
+        );
+        assert!(session
+            .disconnect_notify_threshold(PlayerHandle(2))
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_frame_advantage_history_covers_window() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        assert!(session
+            .frame_advantage_history(PlayerHandle(0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            session
+                .frame_advantage_history(PlayerHandle(1))
+                .unwrap()
+                .len(),
+            TestConfig::FRAME_ADVANTAGE_WINDOW
+        );
+        assert!(session.frame_advantage_history(PlayerHandle(2)).is_err());
+    }
+
+    #[test]
+    pub fn test_prediction_barrier_stall_skips_advancing() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session =
+            start_session::<StallConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        // The remote player never sends any inputs, so the barrier is eventually reached.
+        session.0.write().synchronizing = false;
+
+        for _ in 0..20 {
+            session.poll();
+            session.add_local_input(PlayerHandle(0), 1).unwrap();
+            for command in session.advance_frame() {
+                if let Command::Save(save) = command {
+                    save.save(0);
+                }
+            }
+        }
+        assert_eq!(session.frames_until_prediction_barrier(), 0);
+        let stalled_frame = session.current_frame();
+        assert!(stalled_frame < 20);
+
+        session.add_local_input(PlayerHandle(0), 1).unwrap();
+        assert!(session
+            .advance_frame()
+            .into_iter()
+            .all(|command| matches!(command, Command::Event(_))));
+        assert_eq!(session.current_frame(), stalled_frame);
+    }
+
+    #[test]
+    pub fn test_prediction_barrier_errors_by_default() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        session.0.write().synchronizing = false;
+
+        let mut result = Ok(());
+        for _ in 0..20 {
+            session.poll();
+            result = session.add_local_input(PlayerHandle(0), 1);
+            if result.is_ok() {
+                for command in session.advance_frame() {
+                    if let Command::Save(save) = command {
+                        save.save(0);
+                    }
+                }
+            }
+        }
+        assert!(matches!(
+            result,
+            Err(BackrollError::ReachedPredictionBarrier)
+        ));
+    }
+
+    #[test]
+    pub fn test_elapsed_time_starts_once_running() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(session.elapsed_time(), Duration::ZERO);
+
+        let local = start_session::<TestConfig>(vec![Player::Local], &pool);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(local.elapsed_time() >= Duration::from_millis(10));
+        for _ in 0..3 {
+            local.add_local_input(PlayerHandle(0), 0).unwrap();
+            local.advance_frame();
+        }
+        assert_eq!(local.elapsed_frames(), 3);
+    }
+
+    /// Executes the commands returned by a session, returning the frames that were
+    /// advanced alongside the input of the first player.
+    fn run_commands(commands: Commands<TestConfig>) -> Vec<(Frame, u32)> {
+        let mut advanced = Vec::new();
+        for command in commands {
+            match command {
+                Command::Save(save) => save.save(0),
+                Command::AdvanceFrame(inputs) => {
+                    advanced.push((inputs.frame, *inputs.get(PlayerHandle(0)).unwrap()))
+                }
+                _ => {}
+            }
+        }
+        advanced
+    }
+
+    fn run_commands_counting_timesyncs(commands: Commands<TestConfig>) -> usize {
+        let mut timesyncs = 0;
+        for command in commands {
+            match command {
+                Command::Save(save) => save.save(0),
+                Command::Event(Event::TimeSync { .. }) => timesyncs += 1,
+                _ => {}
+            }
+        }
+        timesyncs
+    }
+
+    #[test]
+    pub fn test_advance_frames_batches_local_inputs() {
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        let session = builder.start(TaskPool::new()).unwrap();
+        session.poll();
+        let (advanced, error, commands) = session.advance_frames(10, |player, frame| {
+            assert_eq!(player.0, 0);
+            frame as u32 * 2
+        });
+        assert_eq!(advanced, 10);
+        assert!(error.is_none());
+        assert_eq!(session.current_frame(), 10);
+        assert_eq!(
+            run_commands(commands),
+            (0..10)
+                .map(|frame| (frame, frame as u32 * 2))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    pub fn test_advance_frames_stops_at_prediction_barrier() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        let (advanced, error, _) = session.advance_frames(5, |_, _| 0);
+        assert_eq!(advanced, 0);
+        assert!(matches!(error, Some(BackrollError::NotSynchronized)));
+
+        // The remote player never sends any inputs, so the barrier is eventually reached.
+        session.0.write().synchronizing = false;
+        let (advanced, error, commands) = session.advance_frames(20, |_, _| 1);
+        run_commands(commands);
+        assert!(advanced < 20);
+        assert!(matches!(
+            error,
+            Some(BackrollError::ReachedPredictionBarrier)
+        ));
+        assert_eq!(session.current_frame(), advanced as Frame);
+        assert_eq!(session.frames_until_prediction_barrier(), 0);
+    }
+
+    #[test]
+    pub fn test_advance_frames_stops_at_frame_limit() {
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        let session = builder.start(TaskPool::new()).unwrap();
+        session.set_max_frames_per_poll(Some(3));
+        session.poll();
+        let (advanced, error, commands) = session.advance_frames(10, |_, _| 0);
+        run_commands(commands);
+        assert_eq!(advanced, 3);
+        assert!(matches!(error, Some(BackrollError::ReachedFrameLimit(3))));
+
+        // The poll at the end of the call does not reset the limit.
+        let (advanced, error, _) = session.advance_frames(10, |_, _| 0);
+        assert_eq!(advanced, 0);
+        assert!(matches!(error, Some(BackrollError::ReachedFrameLimit(3))));
+    }
+
+    #[test]
+    pub fn test_late_inputs_for_disconnected_queue_are_dropped() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        session.0.write().synchronizing = false;
+        let inputs = |frames: std::ops::Range<Frame>| {
+            ProtocolEvent::Inputs(frames.map(|frame| FrameInput { frame, input: 7 }).collect())
+        };
+
+        let mut commands = Commands::default();
+        session
+            .0
+            .write()
+            .handle_event(&mut commands, 1, inputs(0..3));
+        run_commands(commands);
+        run_commands(session.disconnect_player(PlayerHandle(1)).unwrap());
+        let last_frame = session.0.read().local_connect_status[1].read().last_frame;
+        assert_eq!(last_frame, 2);
+
+        let mut commands = Commands::default();
+        session
+            .0
+            .write()
+            .handle_event(&mut commands, 1, inputs(3..6));
+        assert!(commands.into_iter().next().is_none());
+        assert_eq!(
+            session.0.read().local_connect_status[1].read().last_frame,
+            last_frame
+        );
+
+        // Frames past the disconnect frame still use the disconnected input.
+        let (advanced, error, commands) = session.advance_frames(6, |_, _| 1);
+        assert_eq!(advanced, 6);
+        assert!(error.is_none());
+        for command in commands {
+            match command {
+                Command::Save(save) => save.save(0),
+                Command::AdvanceFrame(inputs) if inputs.frame > last_frame => {
+                    assert!(inputs.is_disconnected(PlayerHandle(1)).unwrap());
+                    assert_eq!(*inputs.get(PlayerHandle(1)).unwrap(), 0);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_timesync_frames_ahead_is_clamped() {
+        assert_eq!(timesync_frames_ahead(3), 3);
+        assert_eq!(timesync_frames_ahead(-2), 0);
+        assert_eq!(timesync_frames_ahead(256), u8::MAX);
+        assert_eq!(timesync_frames_ahead(Frame::MAX), u8::MAX);
+    }
+
+    #[test]
+    pub fn test_timesync_is_issued_once_per_interval() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        session.0.write().synchronizing = false;
+        let (advanced, _, commands) = session.advance_frames(1, |_, _| 0);
+        assert_eq!(advanced, 1);
+        let mut timesyncs = run_commands_counting_timesyncs(commands);
+        for _ in 0..5 {
+            timesyncs += run_commands_counting_timesyncs(session.poll());
+        }
+        assert_eq!(timesyncs, 1);
+        assert_eq!(
+            session.0.read().next_recommended_sleep,
+            1 + RECOMMENDATION_INTERVAL
+        );
+    }
+
+    #[test]
+    pub fn test_timesync_interval_saturates_near_max_frame() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        {
+            let mut session_ref = session.0.write();
+            session_ref.synchronizing = false;
+            session_ref.sync.set_frame_count(Frame::MAX - 1);
+        }
+        assert_eq!(run_commands_counting_timesyncs(session.poll()), 1);
+        assert_eq!(session.0.read().next_recommended_sleep, Frame::MAX);
+        assert_eq!(run_commands_counting_timesyncs(session.poll()), 0);
+    }
+
+    #[test]
+    pub fn test_timesync_stall_is_spread_across_iterations() {
+        let pool = TaskPool::new();
+        let session = start_session::<TestConfig>(vec![Player::Local], &pool);
+        assert!(!session.apply_timesync_stall());
+        session.0.write().timesync_stall = 2;
+
+        let mut skipped = Vec::new();
+        for _ in 0..6 {
+            session.poll();
+            let stall = session.apply_timesync_stall();
+            if !stall {
+                session.add_local_input(PlayerHandle(0), 0).unwrap();
+                session.advance_frame();
+            }
+            skipped.push(stall);
+        }
+        assert_eq!(skipped, vec![true, false, true, false, false, false]);
+        assert_eq!(session.current_frame(), 4);
+    }
+
+    #[test]
+    pub fn test_recommended_delay_is_zero_without_samples() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        for player in 0..2 {
+            assert_eq!(
+                session
+                    .recommended_delay_inputs(PlayerHandle(player))
+                    .unwrap(),
+                (0, Duration::ZERO)
+            );
+        }
+        assert!(session.recommended_delay_inputs(PlayerHandle(2)).is_err());
+    }
+
+    #[test]
+    pub fn test_reset_network_stats_zeroes_counters() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer)], &pool);
+        session.reset_network_stats(PlayerHandle(0)).unwrap();
+        session.reset_network_stats(PlayerHandle(1)).unwrap();
+        let stats = session.get_network_stats(PlayerHandle(1)).unwrap();
+        assert_eq!(stats.kbps_sent, 0);
+        assert_eq!(stats.decode_errors, 0);
+        assert!(session.reset_network_stats(PlayerHandle(2)).is_err());
+    }
+
+    #[test]
+    pub fn test_sync_blockers_lists_unresponsive_players() {
+        let pool = TaskPool::new();
+        let (peer_a, _remote_a) = TransportPeer::create_unbounded_pair();
+        // Never answers, so the session can never synchronize.
+        let (silent, _silent_remote) = TransportPeer::create_unbounded_pair();
+        let (dropped, _dropped_remote) = TransportPeer::create_unbounded_pair();
+        let session = start_session::<TestConfig>(
+            vec![
+                Player::Local,
+                Player::Remote(peer_a),
+                Player::Remote(silent),
+                Player::Remote(dropped),
+            ],
+            &pool,
+        );
+        assert_eq!(session.sync_blockers().len(), 3);
+
+        // Synchronized and disconnected players no longer block the session.
+        session.0.read().players[1].peer().unwrap().set_running();
+        session.disconnect_player(PlayerHandle(3)).unwrap();
+
+        let blockers = session.sync_blockers();
+        assert_eq!(blockers.len(), 1);
+        assert_eq!(blockers[0].0 .0, 2);
+        assert_eq!(blockers[0].1, SyncState::AwaitingReply);
+        assert!(!session.is_synchronized());
+    }
+
+    /// A minimal game client used to drive a session in tests. Records the final
+    /// inputs simulated for every frame, including resimulations.
+    struct MeshClient {
+        session: P2PSession<TestConfig>,
+        handle: PlayerHandle,
+        state: u64,
+        frames: HashMap<Frame, GameInput<u32>>,
+    }
+
+    impl MeshClient {
+        fn start(
+            mut builder: P2PSessionBuilder<TestConfig>,
+            players: Vec<Player>,
+            handle: usize,
+            pool: &TaskPool,
+        ) -> Self {
+            for player in players {
+                builder.add_player(player);
+            }
+            Self {
+                session: builder.start(pool.clone()).unwrap(),
+                handle: PlayerHandle(handle),
+                state: 0,
+                frames: HashMap::new(),
+            }
+        }
+
+        fn confirmed(&self) -> Frame {
+            self.session.confirmed_frame().unwrap_or(-1)
+        }
+
+        fn tick(&mut self) {
+            let commands = self.session.poll();
+            self.run(commands);
+            if !self.session.is_synchronized() {
+                return;
+            }
+            let input = self.handle.0 as u32 * 10 + (self.session.current_frame() % 7) as u32;
+            match self.session.add_local_input(self.handle, input) {
+                Ok(()) => {
+                    let commands = self.session.advance_frame();
+                    self.run(commands);
+                }
+                Err(BackrollError::ReachedPredictionBarrier) => {}
+                Err(err) => panic!("Unexpected error: {:?}", err),
+            }
+        }
+
+        fn run(&mut self, commands: Commands<TestConfig>) {
+            for command in commands {
+                match command {
+                    Command::Save(save) => save.save(self.state),
+                    Command::Load(load) => self.state = load.load(),
+                    Command::AdvanceFrame(inputs) => {
+                        self.state = self
+                            .state
+                            .wrapping_mul(31)
+                            .wrapping_add(inputs.frame as u64);
+                        self.frames.insert(inputs.frame, inputs);
+                    }
+                    Command::Event(_) => {}
+                }
+            }
+        }
+
+        fn first_disconnected_frame(&self, player: PlayerHandle, up_to: Frame) -> Option<Frame> {
+            (0..=up_to).find(|frame| self.frames[frame].is_disconnected(player).unwrap())
+        }
+    }
+
+    #[test]
+    pub fn test_auto_frame_delay_agrees_between_peers() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize| {
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new()
+                    .with_frame_delay(sync::MAX_PREDICTION_FRAMES as Frame)
+                    .auto_frame_delay_after_sync(true),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(vec![Player::Local, Player::Remote(peer_a)], 0);
+        let mut b = start(vec![Player::Remote(peer_b), Player::Local], 1);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 30 || b.confirmed() < 30) && Instant::now() < deadline {
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // The ping over an in-memory transport is well below a single frame.
+        let delay = a.session.frame_delay(PlayerHandle(0)).unwrap();
+        assert!(delay < sync::MAX_PREDICTION_FRAMES as Frame);
+        for player in 0..2 {
+            assert_eq!(
+                a.session.frame_delay(PlayerHandle(player)).unwrap(),
+                b.session.frame_delay(PlayerHandle(player)).unwrap()
+            );
+        }
+        for frame in 0..=30 {
+            assert_eq!(a.frames[&frame], b.frames[&frame]);
+        }
+    }
+
+    /// Forwards every packet waiting on one transport to another.
+    fn relay(from: &TransportPeer, to: &TransportPeer) {
+        while let Ok(packet) = from.try_recv() {
+            let _ = to.try_send(packet);
+        }
+    }
+
+    #[test]
+    pub fn test_replaced_peers_continue_without_resync() {
+        let pool = TaskPool::new();
+        let (peer_a, mut relay_a) = TransportPeer::create_unbounded_pair();
+        let (peer_b, mut relay_b) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize| {
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new(),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(vec![Player::Local, Player::Remote(peer_a.clone())], 0);
+        let mut b = start(vec![Player::Remote(peer_b), Player::Local], 1);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 20 || b.confirmed() < 20) && Instant::now() < deadline {
+            relay(&relay_a, &relay_b);
+            relay(&relay_b, &relay_a);
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(a.confirmed() >= 20 && b.confirmed() >= 20);
+
+        // Move both sides of the connection onto new transports mid-match.
+        let (new_a, new_relay_a) = TransportPeer::create_unbounded_pair();
+        let (new_b, new_relay_b) = TransportPeer::create_unbounded_pair();
+        a.session.replace_peer(PlayerHandle(1), new_a).unwrap();
+        b.session.replace_peer(PlayerHandle(0), new_b).unwrap();
+        relay_a = new_relay_a;
+        relay_b = new_relay_b;
+        assert!(!peer_a.is_connected());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 60 || b.confirmed() < 60) && Instant::now() < deadline {
+            relay(&relay_a, &relay_b);
+            relay(&relay_b, &relay_a);
+            a.tick();
+            b.tick();
+            assert!(a.session.is_synchronized() && b.session.is_synchronized());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(a.confirmed() >= 60 && b.confirmed() >= 60);
+        for frame in 0..=60 {
+            assert_eq!(a.frames[&frame], b.frames[&frame]);
+            assert!(!a.frames[&frame].is_disconnected(PlayerHandle(1)).unwrap());
+        }
+    }
+
+    #[test]
+    pub fn test_input_baseline_never_leaves_gaps_in_remote_inputs() {
+        let pool = TaskPool::new();
+        let (peer_a, relay_a) = TransportPeer::create_unbounded_pair();
+        let (peer_b, relay_b) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize| {
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new(),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(vec![Player::Local, Player::Remote(peer_a)], 0);
+        let mut b = start(vec![Player::Remote(peer_b), Player::Local], 1);
+        // The last of the first player's inputs that the second player received.
+        let received = |client: &MeshClient| {
+            client.session.0.read().local_connect_status[0]
+                .read()
+                .last_frame
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 20 || b.confirmed() < 20) && Instant::now() < deadline {
+            relay(&relay_a, &relay_b);
+            relay(&relay_b, &relay_a);
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(a.confirmed() >= 20 && b.confirmed() >= 20);
+
+        // A baseline at the last input the remote player received leaves no gap.
+        a.session
+            .set_input_baseline(PlayerHandle(1), received(&b))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 40 || b.confirmed() < 40) && Instant::now() < deadline {
+            relay(&relay_a, &relay_b);
+            relay(&relay_b, &relay_a);
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(a.confirmed() >= 40 && b.confirmed() >= 40);
+        for frame in 0..=40 {
+            assert_eq!(a.frames[&frame], b.frames[&frame]);
+        }
+
+        // Lose every packet to the second player, then skip past inputs it never got.
+        for _ in 0..20 {
+            while relay_a.try_recv().is_ok() {}
+            relay(&relay_b, &relay_a);
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let before = received(&b);
+        let sent = received(&a);
+        assert!(sent > before + 1);
+        a.session.set_input_baseline(PlayerHandle(1), sent).unwrap();
+        for _ in 0..20 {
+            relay(&relay_a, &relay_b);
+            relay(&relay_b, &relay_a);
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // The inputs after the baseline are dropped instead of leaving a gap.
+        assert_eq!(received(&b), before);
+    }
+
+    #[test]
+    pub fn test_network_stats_history_keeps_latest_snapshots() {
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_network_stats_history(4);
+        builder.add_player(Player::Local);
+        builder.add_player(Player::Remote(peer));
+        let session = builder.start(TaskPool::new()).unwrap();
+
+        assert!(session
+            .network_stats_history(PlayerHandle(1))
+            .unwrap()
+            .is_empty());
+        for _ in 0..10 {
+            session.poll();
+        }
+        assert_eq!(
+            session
+                .network_stats_history(PlayerHandle(1))
+                .unwrap()
+                .len(),
+            4
+        );
+        assert!(session
+            .network_stats_history(PlayerHandle(0))
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            session.network_stats_history(PlayerHandle(2)),
+            Err(BackrollError::InvalidPlayer(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_network_stats_history_is_only_sampled_by_poll() {
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new()
+            .with_frame_delay(0)
+            .with_network_stats_history(8);
+        builder.add_player(Player::Local);
+        builder.add_player(Player::Remote(peer));
+        let session = builder.start(TaskPool::new()).unwrap();
+        session.0.write().synchronizing = false;
+
+        session.poll();
+        session.add_local_input(PlayerHandle(0), 0).unwrap();
+        run_commands(session.advance_frame());
+        let (_, _, commands) = session.advance_frames(3, |_, _| 0);
+        run_commands(commands);
+        assert_eq!(
+            session
+                .network_stats_history(PlayerHandle(1))
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    pub fn test_connect_status_matrix_tracks_remote_reports() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize| {
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new(),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(vec![Player::Local, Player::Remote(peer_a)], 0);
+        let mut b = start(vec![Player::Remote(peer_b), Player::Local], 1);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while a.confirmed() < 20 && Instant::now() < deadline {
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(a.confirmed() >= 20);
+
+        let matrix = a.session.connect_status_matrix();
+        assert_eq!(matrix.len(), 2);
+        // The remote player reports having received the local player's inputs, and
+        // its own inputs as far as it has added them.
+        for status in matrix[1].iter() {
+            assert!(!status.disconnected);
+            assert!(status.last_frame >= 10);
+        }
+        let local = &matrix[0];
+        assert_eq!(
+            local[1].last_frame,
+            a.session.0.read().local_connect_status[1].read().last_frame
+        );
+    }
+
+    #[test]
+    pub fn test_replace_peer_rejects_local_players() {
+        let mut builder = P2PSessionBuilder::<TestConfig>::new();
+        builder.add_player(Player::Local);
+        let session = builder.start(TaskPool::new()).unwrap();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        assert!(matches!(
+            session.replace_peer(PlayerHandle(0), peer),
+            Err(BackrollError::InvalidPlayer(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_tied_queues_disconnect_identically_on_every_peer() {
+        let pool = TaskPool::new();
+        let (ab, ba) = TransportPeer::create_unbounded_pair();
+        let (ac, ca) = TransportPeer::create_unbounded_pair();
+        let (bc, cb) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize| {
+            // Without frame delay, the queues and connect statuses track the same frames.
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new()
+                    .with_frame_delay(0)
+                    .with_disconnect_timeout(Duration::from_millis(1000)),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(
+            vec![
+                Player::Local,
+                Player::Remote(ab),
+                Player::Remote(ac.clone()),
+            ],
+            0,
+        );
+        let mut b = start(
+            vec![
+                Player::Remote(ba),
+                Player::Local,
+                Player::Remote(bc.clone()),
+            ],
+            1,
+        );
+        let mut c = start(
+            vec![Player::Remote(ca), Player::Remote(cb), Player::Local],
+            2,
+        );
+
+        // Run all three peers in lockstep so that every queue reports the same frame.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while c.session.current_frame() < 20 && Instant::now() < deadline {
+            a.tick();
+            b.tick();
+            c.tick();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(c.session.current_frame() >= 20);
+
+        // Stop the third player and let its last inputs arrive before cutting it off.
+        for _ in 0..10 {
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        ac.set_paused(true);
+        bc.set_paused(true);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 60 || b.confirmed() < 60) && Instant::now() < deadline {
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let up_to = std::cmp::min(a.confirmed(), b.confirmed());
+        assert!(up_to >= 60);
+        let disconnected = a.first_disconnected_frame(PlayerHandle(2), up_to);
+        assert!(disconnected.is_some());
+        assert_eq!(
+            disconnected,
+            b.first_disconnected_frame(PlayerHandle(2), up_to)
+        );
+        for frame in 0..=up_to {
+            assert_eq!(
+                a.frames[&frame], b.frames[&frame],
+                "Diverged at frame {}.",
+                frame
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_disconnect_reported_by_one_peer_spreads_to_others() {
+        let pool = TaskPool::new();
+        let (ab, ba) = TransportPeer::create_unbounded_pair();
+        let (ac, ca) = TransportPeer::create_unbounded_pair();
+        let (bc, cb) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize, timeout: Duration| {
+            // Without frame delay, the queues and connect statuses track the same frames.
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new()
+                    .with_frame_delay(0)
+                    .with_disconnect_timeout(timeout),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(
+            vec![
+                Player::Local,
+                Player::Remote(ab),
+                Player::Remote(ac.clone()),
+            ],
+            0,
+            Duration::from_millis(1000),
+        );
+        let mut b = start(
+            vec![Player::Remote(ba), Player::Local, Player::Remote(bc)],
+            1,
+            Duration::from_millis(1000),
+        );
+        // The third player never times out, so only the first player detects the
+        // silent link and the second player does not receive conflicting reports.
+        let mut c = start(
+            vec![Player::Remote(ca), Player::Remote(cb), Player::Local],
+            2,
+            Duration::from_secs(60),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while [&a, &b, &c].iter().any(|client| client.confirmed() < 20) && Instant::now() < deadline
+        {
+            a.tick();
+            b.tick();
+            c.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!([&a, &b, &c].iter().all(|client| client.confirmed() >= 20));
+
+        // Only the link between the first and third player goes silent. The second
+        // player still hears from the third, and only learns of the disconnect through
+        // the first player's reports.
+        ac.set_paused(true);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (a.confirmed() < 80 || b.confirmed() < 80) && Instant::now() < deadline {
+            a.tick();
+            b.tick();
+            c.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let up_to = std::cmp::min(a.confirmed(), b.confirmed());
+        assert!(up_to >= 80);
+        let disconnected = a.first_disconnected_frame(PlayerHandle(2), up_to);
+        assert!(disconnected.is_some());
+        assert_eq!(
+            disconnected,
+            b.first_disconnected_frame(PlayerHandle(2), up_to)
+        );
+        assert!(b.session.connect_status_matrix()[0][2].disconnected);
+        for frame in 0..=up_to {
+            assert_eq!(a.frames[&frame], b.frames[&frame]);
+        }
+    }
+
+    #[test]
+    pub fn test_poll_summary_reports_confirmed_frame_and_disconnects() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        let start = |players: Vec<Player>, handle: usize| {
+            MeshClient::start(
+                P2PSessionBuilder::<TestConfig>::new(),
+                players,
+                handle,
+                &pool,
+            )
+        };
+        let mut a = start(vec![Player::Local, Player::Remote(peer_a)], 0);
+        let mut b = start(vec![Player::Remote(peer_b), Player::Local], 1);
+        let (_, summary) = a.session.poll_with_summary();
+        assert_eq!(summary.confirmed_frame, None);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while a.confirmed() < 10 && Instant::now() < deadline {
+            a.tick();
+            b.tick();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let (commands, summary) = a.session.poll_with_summary();
+        a.run(commands);
+        assert_eq!(summary.confirmed_frame, a.session.confirmed_frame());
+        assert!(summary.confirmed_frame.unwrap() >= 10);
+        assert!(summary.disconnected.is_empty());
+
+        b.run(b.session.disconnect_player(PlayerHandle(1)).unwrap());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let disconnected = loop {
+            let (commands, summary) = a.session.poll_with_summary();
+            a.run(commands);
+            if !summary.disconnected.is_empty() || Instant::now() >= deadline {
+                break summary.disconnected;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert!(matches!(disconnected.as_slice(), [PlayerHandle(1)]));
+        // Disconnects are only reported by the poll they happened in.
+        assert!(a.session.poll_with_summary().1.disconnected.is_empty());
+    }
+
+    #[test]
+    pub fn test_is_frame_confirmed_up_to_confirmed_frame() {
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        let session = builder.start(TaskPool::new()).unwrap();
+        assert!(!session.is_frame_confirmed(0));
+
+        for _ in 0..5 {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            run_commands(session.advance_frame());
+        }
+        session.poll();
+        let confirmed = session.confirmed_frame().unwrap();
+        assert!(session.is_frame_confirmed(0));
+        assert!(session.is_frame_confirmed(confirmed));
+        assert!(!session.is_frame_confirmed(confirmed + 1));
+        assert!(!session.is_frame_confirmed(crate::NULL_FRAME));
+    }
+
+    #[test]
+    pub fn test_max_frames_per_poll_resets_on_poll() {
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        let session = builder.start(TaskPool::new()).unwrap();
+        session.set_max_frames_per_poll(Some(2));
+        session.poll();
+        for _ in 0..2 {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            run_commands(session.advance_frame());
+        }
+        assert!(matches!(
+            session.add_local_input(PlayerHandle(0), 0),
+            Err(BackrollError::ReachedFrameLimit(2))
+        ));
+
+        session.poll();
+        session.add_local_input(PlayerHandle(0), 0).unwrap();
+        run_commands(session.advance_frame());
+
+        session.set_max_frames_per_poll(None);
+        for _ in 0..5 {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            run_commands(session.advance_frame());
+        }
+        assert_eq!(session.current_frame(), 8);
+    }
+
+    #[test]
+    pub fn test_diagnostic_snapshot_reports_queue_state() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        builder.add_player(Player::Remote(peer));
+        let session = builder.start(pool).unwrap();
+        assert!(session.diagnostic_snapshot().synchronizing);
+
+        session.0.write().synchronizing = false;
+        for _ in 0..3 {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            run_commands(session.advance_frame());
+        }
+        let snapshot = session.diagnostic_snapshot();
+        assert_eq!(snapshot.frame_count, 3);
+        assert_eq!(snapshot.confirmed_frame, None);
+        assert!(!snapshot.in_rollback && !snapshot.synchronizing);
+        assert_eq!(snapshot.players.len(), 2);
+
+        let local = &snapshot.players[0];
+        assert!(local.is_local && !local.disconnected);
+        assert_eq!(local.last_frame, Some(2));
+        assert_eq!(local.last_added_frame, Some(2));
+        assert_eq!(local.queue_length, 3);
+        assert_eq!(local.last_acked_frame, None);
+        assert_eq!(local.pending_inputs, 0);
+
+        // The remote peer never synchronized, so nothing was sent to or received from it.
+        let remote = &snapshot.players[1];
+        assert!(!remote.is_local && !remote.disconnected);
+        assert_eq!(remote.last_frame, None);
+        assert_eq!(remote.last_added_frame, None);
+        assert_eq!(remote.pending_inputs, 0);
+        assert_eq!(remote.last_encoded_frame, None);
+        assert_eq!(remote.last_decoded_frame, None);
+        assert_eq!(remote.decode_high_water_mark, 0);
+
+        // A header line, a column line, and one row per player.
+        assert_eq!(snapshot.to_string().lines().count(), 4);
+    }
+
+    #[test]
+    pub fn test_last_recv_time_tracks_remote_packets() {
+        let pool = TaskPool::new();
+        let (peer_a, peer_b) = TransportPeer::create_unbounded_pair();
+        let session_a =
+            start_session::<TestConfig>(vec![Player::Local, Player::Remote(peer_a)], &pool);
+        assert_eq!(session_a.last_recv_time(PlayerHandle(0)).unwrap(), None);
+        assert_eq!(session_a.last_recv_time(PlayerHandle(1)).unwrap(), None);
+        assert!(matches!(
+            session_a.last_recv_time(PlayerHandle(2)),
+            Err(BackrollError::InvalidPlayer(_))
+        ));
+
+        // The second session starts sending sync requests as soon as it starts.
+        let before = Instant::now();
+        let _session_b =
+            start_session::<TestConfig>(vec![Player::Remote(peer_b), Player::Local], &pool);
+        let deadline = before + Duration::from_secs(10);
+        let received = loop {
+            let received = session_a.last_recv_time(PlayerHandle(1)).unwrap();
+            if received.is_some() || Instant::now() >= deadline {
+                break received;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        let received = received.unwrap();
+        assert!(received >= before && received <= Instant::now());
+        assert_eq!(session_a.last_recv_time(PlayerHandle(0)).unwrap(), None);
+    }
+
+    #[test]
+    pub fn test_prediction_depth_counts_unconfirmed_frames() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        builder.add_player(Player::Remote(peer));
+        let session = builder.start(pool).unwrap();
+        session.0.write().synchronizing = false;
+        assert_eq!(session.prediction_depth(), 0);
+
+        // The remote player never sends any inputs, so every frame is predicted.
+        for frame in 1..4 {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            run_commands(session.advance_frame());
+            assert_eq!(session.prediction_depth(), frame);
+        }
+
+        // Once the remote inputs arrive, only frames past the confirmed frame count.
+        let mut commands = Commands::default();
+        session.0.write().handle_event(
+            &mut commands,
+            1,
+            ProtocolEvent::Inputs((0..2).map(|frame| FrameInput { frame, input: 0 }).collect()),
+        );
+        run_commands(commands);
+        session.poll();
+        assert_eq!(session.confirmed_frame(), Some(1));
+        assert_eq!(session.prediction_depth(), 1);
+    }
+
+    #[test]
+    pub fn test_encode_pending_for_encodes_unacknowledged_inputs() {
+        let pool = TaskPool::new();
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let mut builder = P2PSessionBuilder::<TestConfig>::new().with_frame_delay(0);
+        builder.add_player(Player::Local);
+        builder.add_player(Player::Remote(peer));
+        let session = builder.start(pool).unwrap();
+        session.0.write().synchronizing = false;
+        session.0.read().players[1].peer().unwrap().set_running();
+        assert_eq!(session.encode_pending_for(PlayerHandle(1)).unwrap(), None);
+
+        for frame in 0..3 {
+            session.add_local_input(PlayerHandle(0), frame).unwrap();
+            run_commands(session.advance_frame());
+        }
+        let (start_frame, bits) = session
+            .encode_pending_for(PlayerHandle(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(start_frame, 0);
+        assert!(!bits.is_empty());
+        // Encoding does not acknowledge the inputs.
+        assert_eq!(
+            session.encode_pending_for(PlayerHandle(1)).unwrap(),
+            Some((start_frame, bits))
+        );
+
+        session.set_input_baseline(PlayerHandle(1), 2).unwrap();
+        assert_eq!(session.encode_pending_for(PlayerHandle(1)).unwrap(), None);
+        assert_eq!(session.encode_pending_for(PlayerHandle(0)).unwrap(), None);
+        assert!(matches!(
+            session.encode_pending_for(PlayerHandle(2)),
+            Err(BackrollError::InvalidPlayer(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_saved_state_returns_recent_frames() {
+        let pool = TaskPool::new();
+        let session = start_session::<TestConfig>(vec![Player::Local], &pool);
+        assert_eq!(session.saved_state(0), None);
+
+        let frames = sync::MAX_PREDICTION_FRAMES as Frame * 2;
+        for _ in 0..frames {
+            session.add_local_input(PlayerHandle(0), 0).unwrap();
+            for command in session.advance_frame() {
+                if let Command::Save(save) = command {
+                    let frame = save.frame as u64;
+                    save.save(frame);
+                }
+            }
+        }
+        assert_eq!(session.saved_state(frames), Some(frames as u64));
+        assert_eq!(session.saved_state(frames - 1), Some(frames as u64 - 1));
+        // Older states have been overwritten by newer frames.
+        assert_eq!(session.saved_state(0), None);
+    }
 }
@@ -52,20 +52,25 @@ where
 {
     pub(crate) cell: SavedCell<T>,
     pub(crate) frame: Frame,
+    /// If true, the state is not saved but its checksum is compared against the
+    /// one already in the cell. See [Config::ASSERT_DETERMINISM].
+    ///
+    /// [Config::ASSERT_DETERMINISM]: crate::Config::ASSERT_DETERMINISM
+    pub(crate) verify: bool,
 }
 
 impl<T: Config> SaveState<T> {
     /// Saves a single frame's state to the session's state buffer and uses
     /// the hash of the state as the checksum. This uses the
-    /// [DefaultHasher] implementation.
+    /// [DefaultHasher] implementation, which is not guaranteed to be identical
+    /// across platforms. See [crate::hashing] for alternatives.
     ///
     /// This consumes the SaveState, saving multiple times is not allowed.
     ///
     /// [DefaultHasher]: std::collections::hash_map::DefaultHasher
     pub fn save(self, state: T::State) {
-        let mut hasher = DefaultHasher::new();
-        state.hash(&mut hasher);
-        self.save_with_hash(state, hasher.finish());
+        let checksum = default_hash(&state);
+        self.save_with_hash(state, checksum);
     }
 
     /// Saves a single frame's state to the session's state buffer without
@@ -77,7 +82,9 @@ impl<T: Config> SaveState<T> {
     }
 
     /// Saves a single frame's state to the session's state buffer with a
-    /// provided checksum.
+    /// provided checksum. Any hash function can be used, i.e. one already computed
+    /// by the game, but it must produce identical values on every platform for
+    /// checksums to be comparable between peers.
     ///
     /// This consumes the SaveState, saving multiple times is not allowed.
     pub fn save_with_hash(self, state: T::State, checksum: u64) {
@@ -85,20 +92,51 @@ impl<T: Config> SaveState<T> {
     }
 
     fn save_state(self, state: T::State, checksum: Option<u64>) {
+        if self.verify {
+            if let (Some(expected), Some(checksum)) = (self.cell.checksum(), checksum) {
+                assert_eq!(
+                    expected, checksum,
+                    "Non-deterministic simulation: advancing to frame {} twice from the same \
+                     state with the same inputs produced different checksums.",
+                    self.frame
+                );
+            }
+            return;
+        }
         debug!(
             "=== Saved frame state {} (checksum: {:08x}).",
             self.frame,
             checksum.unwrap_or(0)
         );
+        let saved_hash = if T::ASSERT_SAVE_LOAD {
+            Some(default_hash(&state))
+        } else {
+            None
+        };
         self.cell.save(SavedFrame::<T> {
             frame: self.frame,
             data: Some(Box::new(state)),
             checksum,
         });
         assert!(self.cell.is_valid());
+        if let Some(saved_hash) = saved_hash {
+            assert_eq!(
+                saved_hash,
+                default_hash(&self.cell.load()),
+                "Saving then loading the state for frame {} changed its hash. The Clone and \
+                 Hash implementations of Config::State do not agree.",
+                self.frame
+            );
+        }
     }
 }
 
+fn default_hash(state: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl<T: Config> Drop for SaveState<T> {
     fn drop(&mut self) {
         if !self.cell.is_valid() {
@@ -162,3 +200,52 @@ impl<T: Config> IntoIterator for Commands<T> {
         self.commands.into_iter()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A state whose clone loses one of its fields.
+    #[derive(Hash)]
+    struct LossyState {
+        frame: u64,
+        cache: u64,
+    }
+
+    impl Clone for LossyState {
+        fn clone(&self) -> Self {
+            Self {
+                frame: self.frame,
+                cache: 0,
+            }
+        }
+    }
+
+    struct SaveLoadConfig;
+
+    impl Config for SaveLoadConfig {
+        type Input = u32;
+        type State = LossyState;
+        const ASSERT_SAVE_LOAD: bool = true;
+    }
+
+    fn save_lossy_state(cache: u64) {
+        let save = SaveState::<SaveLoadConfig> {
+            cell: SavedCell::new(0),
+            frame: 0,
+            verify: false,
+        };
+        save.save(LossyState { frame: 0, cache });
+    }
+
+    #[test]
+    pub fn test_save_load_round_trip_accepts_symmetric_states() {
+        save_lossy_state(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Saving then loading the state for frame 0 changed its hash")]
+    pub fn test_save_load_round_trip_catches_lossy_clone() {
+        save_lossy_state(42);
+    }
+}
@@ -0,0 +1,187 @@
+//! Deterministic hash functions for computing state checksums.
+//!
+//! Checksums are compared between peers, so the hash must produce the same value
+//! for the same state on every platform the game runs on. [DefaultHasher] makes no
+//! such guarantee across Rust versions, and the [Hash] implementations of integer
+//! types write them in native endianness, with `usize` lengths whose width depends
+//! on the target. For checksums that are meaningful across platforms, hash a
+//! canonical byte representation of the state with [fnv1a] or [xxh64], and save it
+//! via [SaveState::save_with_hash].
+//!
+//! [DefaultHasher]: std::collections::hash_map::DefaultHasher
+//! [Hash]: std::hash::Hash
+//! [SaveState::save_with_hash]: crate::command::SaveState::save_with_hash
+
+use std::hash::Hasher;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// Computes the 64-bit FNV-1a hash of a byte slice.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hasher = Fnv1aHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// A [Hasher] implementing 64-bit FNV-1a. Fast for small states, but mixes poorly
+/// for large ones.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Computes the 64-bit xxHash (XXH64) of a byte slice with the provided seed.
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let len = data.len() as u64;
+    let mut remaining = data;
+    let mut hash = if data.len() >= 32 {
+        let mut acc = [
+            seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+            seed.wrapping_add(PRIME64_2),
+            seed,
+            seed.wrapping_sub(PRIME64_1),
+        ];
+        while remaining.len() >= 32 {
+            for (lane, acc) in acc.iter_mut().enumerate() {
+                *acc = xxh64_round(*acc, read_u64(&remaining[lane * 8..]));
+            }
+            remaining = &remaining[32..];
+        }
+        let mut hash = acc[0]
+            .rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18));
+        for acc in acc.iter() {
+            hash = xxh64_merge_round(hash, *acc);
+        }
+        hash
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+
+    hash = hash.wrapping_add(len);
+    while remaining.len() >= 8 {
+        hash ^= xxh64_round(0, read_u64(remaining));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        remaining = &remaining[8..];
+    }
+    if remaining.len() >= 4 {
+        let value = u32::from_le_bytes([remaining[0], remaining[1], remaining[2], remaining[3]]);
+        hash ^= (value as u64).wrapping_mul(PRIME64_1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        remaining = &remaining[4..];
+    }
+    for byte in remaining {
+        hash ^= (*byte as u64).wrapping_mul(PRIME64_5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME64_3);
+    hash ^= hash >> 32;
+    hash
+}
+
+/// A [Hasher] implementing XXH64 with a seed of zero. All written bytes are buffered
+/// and hashed at once by [Hasher::finish].
+#[derive(Clone, Debug, Default)]
+pub struct XxHash64 {
+    buffer: Vec<u8>,
+}
+
+impl Hasher for XxHash64 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        xxh64(&self.buffer, 0)
+    }
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge_round(hash: u64, acc: u64) -> u64 {
+    (hash ^ xxh64_round(0, acc))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_fnv1a_matches_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    pub fn test_xxh64_matches_reference_values() {
+        assert_eq!(xxh64(b"", 0), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"a", 0), 0xD24E_C4F1_A98C_6E5B);
+        assert_eq!(xxh64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+        assert_eq!(
+            xxh64(b"Nobody inspects the spammish repetition", 0),
+            0xFBCE_A83C_8A37_8BF1
+        );
+    }
+
+    #[test]
+    pub fn test_hashers_match_byte_functions() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut xx = XxHash64::default();
+        xx.write(&data[..40]);
+        xx.write(&data[40..]);
+        assert_eq!(xx.finish(), xxh64(&data, 0));
+        let mut fnv = Fnv1aHasher::default();
+        fnv.write(&data[..40]);
+        fnv.write(&data[40..]);
+        assert_eq!(fnv.finish(), fnv1a(&data));
+    }
+}
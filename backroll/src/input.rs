@@ -87,10 +87,6 @@ impl<T> FetchedInput<T> {
             Self::Prediction(input) => input,
         }
     }
-
-    pub fn is_prediction(&self) -> bool {
-        matches!(self, Self::Prediction(_))
-    }
 }
 
 pub struct InputQueue<T>
@@ -114,25 +110,11 @@ where
 }
 
 impl<T: Config> InputQueue<T> {
-    #[allow(clippy::uninit_assumed_init)]
     pub fn new(frame_delay: Frame) -> Self {
         // This is necessary as Default is not defined on arrays of more
         // than 32 without a Copy trait bound.
-        //
-        // SAFE: The entire buffer is initialized by the end of the for-loop.
-        // Assuming Zeroable is implemented correctly, this should also never
-        // panic, so a buffer will always correctly be allocated as a large
-        // zeroed buffer.
-        let inputs: [FrameInput<T::Input>; MAX_ROLLBACK_FRAMES] = {
-            let mut inputs: [FrameInput<T::Input>; MAX_ROLLBACK_FRAMES] =
-                unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-
-            for input in inputs.iter_mut() {
-                *input = Default::default();
-            }
-
-            inputs
-        };
+        let inputs: [FrameInput<T::Input>; MAX_ROLLBACK_FRAMES] =
+            std::array::from_fn(|_| Default::default());
 
         Self {
             head: 0,
@@ -150,14 +132,42 @@ impl<T: Config> InputQueue<T> {
     }
 
     pub fn last_confirmed_frame(&self) -> Frame {
-        debug!("returning last confirmed frame {}.", self.last_added_frame);
         self.last_added_frame
     }
 
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    pub fn tail(&self) -> usize {
+        self.tail
+    }
+
+    /// Gets the received input for a given frame, if it is still in the queue.
+    /// Unlike [get_input], this never predicts and does not affect prediction state.
+    ///
+    /// [get_input]: Self::get_input
+    pub fn confirmed_input(&self, frame: Frame) -> Option<&FrameInput<T::Input>> {
+        if self.length == 0 {
+            return None;
+        }
+        let offset = usize::try_from(frame - self.inputs[self.tail].frame).ok()?;
+        if offset >= self.length {
+            return None;
+        }
+        let input = &self.inputs[(self.tail + offset) % MAX_ROLLBACK_FRAMES];
+        debug_assert!(input.frame == frame);
+        Some(input)
+    }
+
     pub fn first_incorrect_frame(&self) -> Frame {
         self.first_incorrect_frame
     }
 
+    pub fn frame_delay(&self) -> Frame {
+        self.frame_delay
+    }
+
     pub fn set_frame_delay(&mut self, frame_delay: Frame) {
         debug_assert!(!super::is_null(frame_delay));
         self.frame_delay = frame_delay;
@@ -165,7 +175,8 @@ impl<T: Config> InputQueue<T> {
 
     pub fn discard_confirmed_frames(&mut self, mut frame: Frame) {
         debug_assert!(!super::is_null(frame));
-        if super::is_null(self.last_frame_requested) {
+        // Never discard frames that have not been requested yet.
+        if !super::is_null(self.last_frame_requested) {
             frame = std::cmp::min(frame, self.last_frame_requested)
         }
 
@@ -201,14 +212,6 @@ impl<T: Config> InputQueue<T> {
         self.last_frame_requested = super::NULL_FRAME;
     }
 
-    pub fn get_confirmed_input(&self, frame: Frame) -> Option<&FrameInput<T::Input>> {
-        debug_assert!(
-            super::is_null(self.first_incorrect_frame) || frame < self.first_incorrect_frame
-        );
-        let offset = usize::try_from(frame).unwrap() % MAX_ROLLBACK_FRAMES;
-        self.inputs.get(offset)
-    }
-
     pub fn get_input(&mut self, frame: Frame) -> FetchedInput<T::Input> {
         debug!("requesting input frame {:?}.", frame);
 
@@ -266,6 +269,26 @@ impl<T: Config> InputQueue<T> {
         FetchedInput::Prediction(prediction)
     }
 
+    /// Gets the input for a given frame the same way as [get_input], predicting it if
+    /// it has not been received yet, but without affecting prediction state.
+    ///
+    /// [get_input]: Self::get_input
+    pub fn peek_input(&self, frame: Frame) -> FetchedInput<T::Input> {
+        let mut prediction = if !super::is_null(self.prediction.frame) {
+            self.prediction.clone()
+        } else if let Some(input) = self.confirmed_input(frame) {
+            return FetchedInput::Normal(input.clone());
+        } else if frame == 0 || super::is_null(self.last_added_frame) {
+            let mut prediction = self.prediction.clone();
+            prediction.clear();
+            prediction
+        } else {
+            self.inputs[previous_frame(self.head)].clone()
+        };
+        prediction.frame = frame;
+        FetchedInput::Prediction(prediction)
+    }
+
     pub fn add_input(&mut self, input: FrameInput<T::Input>) -> Frame {
         // These next two lines simply verify that inputs are passed in
         // sequentially by the user, regardless of frame delay.
@@ -372,3 +395,51 @@ impl<T: Config> InputQueue<T> {
         self.length
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type Input = u32;
+        type State = u64;
+    }
+
+    fn queue_with_inputs(count: Frame) -> InputQueue<TestConfig> {
+        let mut queue = InputQueue::<TestConfig>::new(0);
+        for frame in 0..count {
+            queue.add_input(FrameInput {
+                frame,
+                input: frame as u32,
+            });
+        }
+        queue
+    }
+
+    #[test]
+    pub fn test_discard_keeps_frames_after_last_requested() {
+        let mut queue = queue_with_inputs(5);
+        queue.get_input(2);
+        queue.discard_confirmed_frames(4);
+        assert_eq!(queue.len(), 2);
+        assert!(queue.confirmed_input(2).is_none());
+        assert_eq!(queue.confirmed_input(3).unwrap().input, 3);
+    }
+
+    #[test]
+    pub fn test_discard_without_requested_frames_discards_up_to_frame() {
+        let mut queue = queue_with_inputs(5);
+        queue.discard_confirmed_frames(2);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.confirmed_input(3).unwrap().input, 3);
+
+        // A rollback clears the last requested frame, i.e. for a queue that is never
+        // requested again after disconnecting.
+        queue.get_input(3);
+        queue.reset_prediction(3);
+        queue.discard_confirmed_frames(4);
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -1,8 +1,10 @@
-use std::{hash::Hash, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, hash::Hash, time::Duration};
 use thiserror::Error;
 
 mod backend;
 pub mod command;
+pub mod hashing;
 mod input;
 mod protocol;
 mod sync;
@@ -11,6 +13,7 @@ mod time_sync;
 pub use backend::*;
 pub use backroll_transport as transport;
 pub use input::GameInput;
+pub use protocol::{ConnectionStatus, DecodeError};
 
 // TODO(james7132): Generalize the executor for these.
 pub(crate) use bevy_tasks::TaskPool;
@@ -27,14 +30,34 @@ fn is_null(frame: Frame) -> bool {
     frame < 0
 }
 
+/// Adds a number of frames to a frame, returning `None` if the result does not fit
+/// in a [Frame]. Use this for frames received from remote peers, which may be
+/// arbitrary.
+fn checked_frame_add(frame: Frame, frames: usize) -> Option<Frame> {
+    Frame::try_from(frames)
+        .ok()
+        .and_then(|frames| frame.checked_add(frames))
+}
+
+/// Converts a possibly null frame into an `Option`, so that NULL_FRAME
+/// is never exposed through the public API.
+fn non_null(frame: Frame) -> Option<Frame> {
+    if is_null(frame) {
+        None
+    } else {
+        Some(frame)
+    }
+}
+
 /// A handle for a player in a Backroll session.
 #[derive(Copy, Clone, Debug)]
 pub struct PlayerHandle(pub usize);
 
 /// Players within a Backroll session.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum Player {
     /// The local player. Backroll currently only supports one local player per machine.
+    #[default]
     Local,
     /// A remote player that is not on the local session.
     Remote(transport::Peer),
@@ -46,21 +69,18 @@ impl Player {
     }
 }
 
-impl Default for Player {
-    fn default() -> Self {
-        Self::Local
-    }
-}
-
 /// Compile time parameterization for Backroll sessions.
 pub trait Config: 'static {
     /// The input type for a Backroll session. This is the only game-related data
     /// transmitted over the network.
     ///
     /// Reminder: Types implementing [Pod] may not have the same byte representation
-    /// on platforms with different endianness. Backroll assumes that all players are
-    /// running with the same endianness when encoding and decoding inputs. It may be
-    /// worthwhile to ensure that all players are running with the same endianess.
+    /// on platforms with different endianness. Backroll sends inputs as their raw
+    /// in-memory bytes and does not reorder them, so a multi-byte field written on a
+    /// little-endian peer is read back byte-swapped on a big-endian one. If players may
+    /// run with different endianness, store multi-byte fields as byte arrays in a fixed
+    /// order (i.e. with [i32::to_le_bytes] and [i32::from_le_bytes]) instead of as native
+    /// integers.
     ///
     /// [Pod]: bytemuck::Pod
     type Input: PartialEq + bytemuck::Pod + bytemuck::Zeroable + Send + Sync;
@@ -71,6 +91,112 @@ pub trait Config: 'static {
     ///
     /// [Drop]: std::ops::Drop
     type State: Clone + Hash + Send + Sync + 'static;
+
+    /// If true, every frame re-simulated during a rollback will be advanced a second
+    /// time from the same starting state, and the checksums of the two resulting states
+    /// will be asserted to be equal. This catches non-deterministic simulations at the
+    /// exact frame they diverge, at the cost of doubling the work done during rollbacks.
+    ///
+    /// The check is only performed if the client saves states with a checksum. This is
+    /// intended for development builds only, i.e. `cfg!(debug_assertions)`. Defaults to
+    /// false.
+    const ASSERT_DETERMINISM: bool = false;
+
+    /// If true, every saved state is immediately loaded back the same way a rollback
+    /// would load it, and the [Hash] of the loaded copy is asserted to be equal to the
+    /// [Hash] of the state that was saved. This catches [Clone] or [Hash] implementations
+    /// of [Config::State] that do not round trip at the exact frame they are saved,
+    /// instead of as a desync after a later rollback.
+    ///
+    /// This clones and hashes every saved state a second time. This is intended for
+    /// development builds only, i.e. `cfg!(debug_assertions)`. Defaults to false.
+    const ASSERT_SAVE_LOAD: bool = false;
+
+    /// The number of frames of frame advantage samples that are averaged when
+    /// recommending how many frames to wait to stay in sync with remote players.
+    /// Longer windows produce smoother [Event::TimeSync] recommendations on spiky
+    /// connections, but react more slowly to real changes. Must not be zero.
+    /// Defaults to 40 frames.
+    ///
+    /// [Event::TimeSync]: crate::Event::TimeSync
+    const FRAME_ADVANTAGE_WINDOW: usize = 40;
+
+    /// The maximum size of a single input packet in bytes. Batches of inputs that would
+    /// not fit are split across multiple packets, which avoids IP fragmentation on paths
+    /// with a small MTU, such as VPNs. Values above 1450 bytes have no effect. A single
+    /// input that does not fit on its own is still sent in one packet. Defaults to 1200
+    /// bytes.
+    const MAX_PACKET_BYTES: usize = 1200;
+
+    /// How long to wait for a reply before resending a synchronization packet to a
+    /// remote player during the initial handshake. The very first resend is capped
+    /// at 500ms. Defaults to 2000ms.
+    const SYNC_RETRY_INTERVAL: Duration = Duration::from_millis(2000);
+
+    /// The maximum number of synchronization packets that may be resent in a row
+    /// without the handshake making progress. Once exceeded, the handshake is
+    /// abandoned and [Event::SynchronizationFailed] is fired. If `None`, packets are
+    /// resent until the remote player is disconnected by the disconnect timeout.
+    /// Defaults to `None`.
+    ///
+    /// [Event::SynchronizationFailed]: crate::Event::SynchronizationFailed
+    const SYNC_MAX_RETRIES: Option<u32> = None;
+
+    /// What a session does when a remote player disconnects mid-match. Defaults to
+    /// [DisconnectPolicy::Continue].
+    const ON_DISCONNECT: DisconnectPolicy = DisconnectPolicy::Continue;
+
+    /// What [P2PSession::add_local_input] does when the simulation has reached the
+    /// prediction barrier. Defaults to [PredictionBarrierPolicy::Error].
+    ///
+    /// [P2PSession::add_local_input]: crate::P2PSession::add_local_input
+    const ON_PREDICTION_BARRIER: PredictionBarrierPolicy = PredictionBarrierPolicy::Error;
+
+    /// How inputs are compressed before being sent to remote players. Remote players
+    /// decode inputs correctly regardless of the mode they use themselves. Defaults to
+    /// [Compression::Delta].
+    const COMPRESSION: Compression = Compression::Delta;
+
+    /// The number of frames the simulation is advanced per second. Only used to
+    /// convert between durations and frames. Must not be zero. Defaults to 60.
+    const FPS: u32 = 60;
+
+    /// Called exactly once for every frame once the inputs for it are confirmed
+    /// for all players, in increasing frame order. Confirmed inputs will never be
+    /// rolled back, which makes this suitable for streaming inputs to a replay
+    /// file. Called from within [P2PSession::poll]. Does nothing by default.
+    ///
+    /// [P2PSession::poll]: crate::P2PSession::poll
+    fn on_frame_confirmed(_frame: Frame, _inputs: &GameInput<Self::Input>) {}
+
+    /// Called for every frame that is simulated again during a rollback, with the
+    /// inputs it is about to be re-simulated with. This is a diagnostic hook, i.e. for
+    /// logging mispredicted inputs, and cannot change the inputs. Does nothing by
+    /// default.
+    fn on_resimulate(_frame: Frame, _inputs: &GameInput<Self::Input>) {}
+
+    /// The input used for a player on every frame after they disconnected. Every peer
+    /// substitutes the same value from the same frame onwards, so this must not depend
+    /// on any local state. Defaults to the all-zero value of [Config::Input].
+    fn disconnected_input() -> Self::Input {
+        bytemuck::Zeroable::zeroed()
+    }
+}
+
+/// An optional extension to [Config] for smoothing visuals when rendering at a
+/// higher rate than the simulation runs at.
+///
+/// Interpolation is strictly render-only: it is never invoked while advancing or
+/// rolling back the simulation, and does not need to be deterministic.
+pub trait Interpolate: Config {
+    /// The state used for rendering. This must never be fed back into the
+    /// simulation, as it is derived from predicted states that may be rolled back
+    /// and is not guaranteed to be the same across players.
+    type RenderState;
+
+    /// Blends between two consecutive simulated states. `alpha` is in the range
+    /// `[0.0, 1.0]`, where 0.0 is `from` and 1.0 is `to`.
+    fn interpolate(from: &Self::State, to: &Self::State, alpha: f32) -> Self::RenderState;
 }
 
 #[derive(Clone, Debug, Error)]
@@ -83,14 +209,140 @@ pub enum BackrollError {
     NotSynchronized,
     #[error("The simulation has reached the prediction barrier.")]
     ReachedPredictionBarrier,
+    #[error("The session has already advanced {} frames since the last poll.", .0)]
+    ReachedFrameLimit(usize),
     #[error("Invalid player handle: {:?}", .0)]
     InvalidPlayer(PlayerHandle),
     #[error("Player already disconnected: {:?}", .0)]
     PlayerDisconnected(PlayerHandle),
+    #[error("Input baseline is ahead of all sent inputs: {}", .0)]
+    InvalidBaseline(Frame),
+    #[error("The session is paused.")]
+    Paused,
+    #[error("Invalid session configuration: {}", .0)]
+    InvalidConfig(&'static str),
 }
 
 pub type BackrollResult<T> = Result<T, BackrollError>;
 
+/// The reason the synchronization handshake with a remote player failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SynchronizationFailure {
+    /// The remote player is running an incompatible version of the wire protocol.
+    ProtocolVersion { local: u8, remote: u8 },
+    /// The remote player's session was started with a different number of players.
+    PlayerCount { local: u8, remote: u8 },
+    /// The remote player did not respond after [Config::SYNC_MAX_RETRIES] resent
+    /// synchronization packets.
+    RetriesExhausted { retries: u32 },
+}
+
+/// How inputs are compressed before being sent to remote players. See
+/// [Config::COMPRESSION].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Inputs are delta encoded against each other, then run-length encoded. If the
+    /// `deflate` feature is enabled, the result is also compressed with DEFLATE when
+    /// that makes it smaller.
+    Delta,
+    /// Inputs are sent as their raw bytes without any compression. This is much larger
+    /// on the wire and is only intended for ruling out compression bugs when debugging.
+    None,
+}
+
+/// What a session does when a remote player disconnects mid-match. See
+/// [Config::ON_DISCONNECT].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    /// Keep running with the remaining players. From the frame the player was
+    /// disconnected at onwards, their input is [Config::disconnected_input], and
+    /// [GameInput::is_disconnected] returns true for them.
+    ///
+    /// [Pod] types are not required to implement [Default], so the zeroed value is
+    /// used as the neutral input by default. For inputs made of integers, floats and
+    /// bools, this is identical to what `#[derive(Default)]` would produce. Inputs
+    /// whose derived [Default] is not all zeroes should override
+    /// [Config::disconnected_input].
+    ///
+    /// [Pod]: bytemuck::Pod
+    Continue,
+    /// Pause the session for everyone. Disconnected players are handled the same
+    /// way as with [DisconnectPolicy::Continue], but once a remote player
+    /// disconnects, [P2PSession::add_local_input] returns [BackrollError::Paused]
+    /// until [P2PSession::resume] is called. This gives the game a chance to let the
+    /// remaining players vote on whether to continue.
+    ///
+    /// [P2PSession::add_local_input]: crate::P2PSession::add_local_input
+    /// [P2PSession::resume]: crate::P2PSession::resume
+    PauseAndVote,
+}
+
+/// What a session does when local input is added while the simulation is as far ahead
+/// of the last confirmed frame as it is allowed to predict. See
+/// [Config::ON_PREDICTION_BARRIER].
+///
+/// Both policies are deterministic: the frame is not simulated until the remote inputs
+/// needed to stay within the barrier have been received, so every peer simulates the
+/// same inputs for it. They only differ in how the stalled frame is surfaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredictionBarrierPolicy {
+    /// [P2PSession::add_local_input] returns [BackrollError::ReachedPredictionBarrier],
+    /// and [P2PSession::advance_frame] should not be called for the frame. The game
+    /// retries the frame once more remote inputs have been received.
+    ///
+    /// [P2PSession::add_local_input]: crate::P2PSession::add_local_input
+    /// [P2PSession::advance_frame]: crate::P2PSession::advance_frame
+    Error,
+    /// [P2PSession::add_local_input] discards the input and returns `Ok`, and the
+    /// following call to [P2PSession::advance_frame] only polls the network without
+    /// advancing the simulation. The game keeps calling both as usual, and
+    /// [P2PSession::current_frame] stays the same until the frame can be simulated.
+    ///
+    /// Since the discarded input is never sent, the game should produce the input for
+    /// the frame again on the next attempt rather than assume it was applied.
+    ///
+    /// [P2PSession::add_local_input]: crate::P2PSession::add_local_input
+    /// [P2PSession::advance_frame]: crate::P2PSession::advance_frame
+    /// [P2PSession::current_frame]: crate::P2PSession::current_frame
+    Stall,
+}
+
+/// The progress of the synchronization handshake with a remote player that has
+/// not yet synchronized. See [P2PSession::sync_blockers].
+///
+/// [P2PSession::sync_blockers]: crate::P2PSession::sync_blockers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncState {
+    /// No reply has been received from the remote player yet.
+    AwaitingReply,
+    /// The remote player has replied, and the handshake is partially complete.
+    Synchronizing { count: u8, total: u8 },
+    /// The connection was closed before the handshake completed, i.e. because
+    /// the handshake failed.
+    Failed,
+}
+
+/// The reason a player was disconnected from a session.
+///
+/// When a player is disconnected by request, the reason is sent to the
+/// remote peer and surfaced there via [Event::Disconnected].
+///
+/// [Event::Disconnected]: crate::Event::Disconnected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DisconnectReason {
+    /// No reason was provided.
+    #[default]
+    Unspecified,
+    /// The player was removed from the session by another player.
+    Kicked,
+    /// The player left the session of their own accord.
+    Quit,
+    /// No packets were received from the player within the disconnect timeout.
+    Timeout,
+    /// An application defined reason.
+    Custom(u8),
+}
+
 #[derive(Clone, Debug, Default)]
 /// Event that occurs during the course of a session.
 pub struct NetworkStats {
@@ -103,6 +355,8 @@ pub struct NetworkStats {
     pub recv_queue_len: usize,
     /// The number of kilobytes sent per second, a rolling average.
     pub kbps_sent: u32,
+    /// The total number of received input packets that could not be decoded.
+    pub decode_errors: usize,
 
     /// The local frame advantage relative to the associated peer.
     pub local_frames_behind: Frame,
@@ -125,11 +379,20 @@ pub enum Event {
     /// The initial synchronization handshake has been completed. The connection
     /// is considered live now.
     Synchronized(PlayerHandle),
+    /// The initial synchronization handshake with a remote player failed because
+    /// its session is incompatible with the local one. The player will be disconnected.
+    SynchronizationFailed {
+        player: PlayerHandle,
+        reason: SynchronizationFailure,
+    },
     /// All remote peers are now synchronized, the session is can now start
     /// running.
     Running,
     /// The connection with a remote player has been disconnected.
-    Disconnected(PlayerHandle),
+    Disconnected {
+        player: PlayerHandle,
+        reason: DisconnectReason,
+    },
     /// The local client is several frames ahead of all other peers. Might need
     /// to stall a few frames to allow others to catch up.
     TimeSync { frames_ahead: u8 },
@@ -140,4 +403,12 @@ pub enum Event {
     },
     /// The connection with a remote player has been resumed after being interrupted.
     ConnectionResumed(PlayerHandle),
+    /// The last connected remote player has been disconnected. Only local players
+    /// remain in the session. Emitted once, after the [Event::Disconnected] for that
+    /// player.
+    SessionOrphaned,
+    /// The most recent frame that inputs from all players have been received for has
+    /// advanced. Frames up to and including this one will never be rolled back. Emitted
+    /// at most once per poll.
+    ConfirmedFrameAdvanced(Frame),
 }
@@ -14,7 +14,7 @@ pub fn encode_with_offset(buf: impl AsRef<[u8]>, offset: usize) -> (Vec<u8>, usi
     let mut contiguous = false;
     let mut prev_bits = 0;
     let mut noncontiguous_bits = Vec::new();
-    let mut enc = Vec::with_capacity(encode_len_with_offset(&buf, offset));
+    let mut enc = Vec::with_capacity(encode_len_with_offset(buf, offset));
 
     for (i, byte) in buf[offset..].iter().enumerate() {
         if contiguous && *byte == prev_bits {
@@ -113,18 +113,28 @@ pub fn encode_len_with_offset(buf: impl AsRef<[u8]>, offset: usize) -> usize {
 }
 
 /// Decode an encoded bitfield.
+#[cfg(test)]
 pub fn decode(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodeError> {
     let (bitfield, _) = decode_with_offset(&buf, 0)?;
     Ok(bitfield)
 }
 
 /// Decode an encoded bitfield, starting at a specific offset.
+#[cfg(test)]
 pub fn decode_with_offset(
     buf: impl AsRef<[u8]>,
-    mut offset: usize,
+    offset: usize,
 ) -> Result<(Vec<u8>, usize), DecodeError> {
     let buf = buf.as_ref();
-    let mut bitfield = vec![0; decode_len_with_offset(&buf, offset)?];
+    let mut bitfield = vec![0; decode_len_with_offset(buf, offset)?];
+    let remaining = decode_into(buf, offset, &mut bitfield);
+    Ok((bitfield, remaining))
+}
+
+/// Decode an encoded bitfield into a preallocated buffer, starting at a specific
+/// offset. The buffer must be exactly `[decode_len_with_offset]` bytes long.
+pub fn decode_into(buf: impl AsRef<[u8]>, mut offset: usize, bitfield: &mut [u8]) -> usize {
+    let buf = buf.as_ref();
     let mut next = 0u64;
     let mut ptr = 0;
 
@@ -151,7 +161,7 @@ pub fn decode_with_offset(
         ptr += len;
     }
 
-    Ok((bitfield, buf.len() - offset))
+    buf.len() - offset
 }
 
 /// Returns how many bytes a decoded bitfield will use.
@@ -1,12 +1,37 @@
 use super::bitfield;
+use crate::Frame;
 use bytemuck::Pod;
+use smallvec::{smallvec, SmallVec};
+use std::borrow::Cow;
 use thiserror::Error;
+#[cfg(feature = "deflate")]
+use tracing::debug;
 
 /// The maximum supported size of the raw buffer.
 const MAX_BUFFER_SIZE: usize = u16::MAX as usize;
 
+/// The size of the intermediate delta buffer that is kept on the stack. Batches of
+/// inputs larger than this fall back to a heap allocation.
+const INLINE_BUFFER_SIZE: usize = 64;
+
+type DeltaBuffer = SmallVec<[u8; INLINE_BUFFER_SIZE]>;
+
+/// Header flag set when the packed buffer has been compressed a second time with DEFLATE.
+const FLAG_DEFLATED: u8 = 1 << 0;
+/// Header flag set when the buffer holds the raw bytes of the inputs. Never combined
+/// with any other flag.
+const FLAG_RAW: u8 = 1 << 1;
+/// Header flag set when the sender moved the baseline of its encoder past inputs the
+/// receiver may not have decoded. The receiver discards its own baseline before
+/// decoding. May be combined with any other flag.
+const FLAG_BASELINE_RESET: u8 = 1 << 2;
+const KNOWN_FLAGS: u8 = FLAG_DEFLATED;
+
 /// Encodes a set of `[Pod]` values into a byte buffer relative to a reference snapshot.
 ///
+/// The values are encoded from their native byte representation. Types with multi-byte
+/// fields only decode to the same values on peers with the same endianness.
+///
 /// # Security
 /// This function fails if the delta encoded output is bigger than `[MAX_BUFFER_SIZE]` to prevent
 /// memory exhaustion.
@@ -21,15 +46,190 @@ pub fn encode<'a, T: Pod>(
     Ok(bitfield::encode(bytes))
 }
 
+/// Prepends a header byte to a buffer produced by `[encode]`, describing how it is
+/// stored on the wire.
+///
+/// If the `deflate` feature is enabled, the buffer is compressed a second time, and
+/// the compressed form is used only if it is smaller.
+pub fn pack(bits: Vec<u8>) -> Vec<u8> {
+    #[cfg(feature = "deflate")]
+    {
+        let deflated = miniz_oxide::deflate::compress_to_vec(&bits, DEFLATE_LEVEL);
+        debug!(
+            "Deflated input buffer: {} -> {} bytes (ratio: {:.2}).",
+            bits.len(),
+            deflated.len(),
+            deflated.len() as f32 / bits.len().max(1) as f32
+        );
+        if deflated.len() < bits.len() {
+            return with_header(FLAG_DEFLATED, deflated);
+        }
+    }
+    with_header(0, bits)
+}
+
+/// Encodes a set of `[Pod]` values as their raw bytes, without any compression, and
+/// adds a header marking it as such. The result is already packed, and must not be
+/// passed to `[pack]`.
+///
+/// # Security
+/// This function fails if the output is bigger than `[MAX_BUFFER_SIZE]`, to match
+/// `[encode]`.
+///
+/// [Pod](bytemuck::Pod)
+pub fn encode_raw<'a, T: Pod>(data: impl Iterator<Item = &'a T>) -> Result<Vec<u8>, EncodeError> {
+    let mut bytes = vec![FLAG_RAW];
+    for datum in data {
+        bytes.extend_from_slice(bytemuck::bytes_of(datum));
+        if bytes.len() > MAX_BUFFER_SIZE {
+            return Err(EncodeError::TooBig { len: bytes.len() });
+        }
+    }
+    Ok(bytes)
+}
+
+/// Sets the baseline reset flag in the header of a buffer produced by either `[pack]`
+/// or `[encode_raw]`. Empty buffers carry no header, and are left as is.
+pub fn mark_baseline_reset(data: &mut [u8]) {
+    if let Some(flags) = data.first_mut() {
+        *flags |= FLAG_BASELINE_RESET;
+    }
+}
+
+/// Checks if a buffer was marked via `[mark_baseline_reset]`.
+pub fn is_baseline_reset(data: &[u8]) -> bool {
+    data.first()
+        .map(|flags| flags & FLAG_BASELINE_RESET != 0)
+        .unwrap_or(false)
+}
+
+/// Decodes a buffer produced by either `[pack]` or `[encode_raw]`.
+#[cfg(test)]
+pub fn decode_packed<T: Pod>(base: &T, data: &[u8]) -> Result<Vec<T>, DecodeError> {
+    let mut output = Vec::new();
+    decode_packed_into(base, data, &mut output)?;
+    Ok(output)
+}
+
+/// Decodes a buffer produced by either `[pack]` or `[encode_raw]` into the provided
+/// buffer, replacing its contents. This allows reusing the same allocation across calls.
+pub fn decode_packed_into<T: Pod>(
+    base: &T,
+    data: &[u8],
+    output: &mut Vec<T>,
+) -> Result<(), DecodeError> {
+    output.clear();
+    match data.split_first() {
+        Some((flags, bytes)) if flags & !FLAG_BASELINE_RESET == FLAG_RAW => {
+            decode_raw(base, bytes, output)
+        }
+        _ => decode_into(base, unpack(data)?, output),
+    }
+}
+
+fn decode_raw<T: Pod>(base: &T, data: &[u8], output: &mut Vec<T>) -> Result<(), DecodeError> {
+    let mut base = *base;
+    let bits = bytemuck::bytes_of_mut(&mut base);
+    let stride = bits.len();
+    debug_assert!(stride > 0);
+    if data.len() > MAX_BUFFER_SIZE {
+        return Err(DecodeError::TooBig { len: data.len() });
+    }
+    if !data.len().is_multiple_of(stride) {
+        return Err(DecodeError::InvalidLength {
+            len: data.len(),
+            stride,
+        });
+    }
+
+    output.reserve(data.len() / stride);
+    for chunk in data.chunks_exact(stride) {
+        bits.copy_from_slice(chunk);
+        output.push(*bytemuck::try_from_bytes::<T>(bits)?);
+    }
+    Ok(())
+}
+
+fn with_header(flags: u8, bits: Vec<u8>) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(bits.len() + 1);
+    packed.push(flags);
+    packed.extend(bits);
+    packed
+}
+
+/// Strips the header byte added by `[pack]`, inflating the buffer if needed. An empty
+/// buffer carries no header, and is returned as is.
+///
+/// # Security
+/// This function fails if the inflated output is bigger than `[MAX_BUFFER_SIZE]` to prevent
+/// memory exhaustion. Also fails if the header contains unknown flags.
+pub fn unpack(data: &[u8]) -> Result<Cow<'_, [u8]>, DecodeError> {
+    let (flags, bits) = match data.split_first() {
+        Some((flags, bits)) => (*flags & !FLAG_BASELINE_RESET, bits),
+        None => return Ok(Cow::Borrowed(data)),
+    };
+
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(DecodeError::UnsupportedHeader { flags });
+    }
+
+    if flags & FLAG_DEFLATED != 0 {
+        return inflate(bits).map(Cow::Owned);
+    }
+
+    Ok(Cow::Borrowed(bits))
+}
+
+#[cfg(feature = "deflate")]
+const DEFLATE_LEVEL: u8 = 6;
+
+fn inflate(bits: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    use miniz_oxide::inflate::{
+        core::{decompress, inflate_flags, DecompressorOxide},
+        TINFLStatus,
+    };
+    use std::io::Cursor;
+
+    let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+    let mut output = vec![0; std::cmp::min(MAX_BUFFER_SIZE, bits.len() * 4)];
+    let mut decompressor = DecompressorOxide::default();
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+    loop {
+        let (status, in_consumed, out_consumed) = {
+            let mut cursor = Cursor::new(output.as_mut_slice());
+            cursor.set_position(out_pos as u64);
+            decompress(&mut decompressor, &bits[in_pos..], &mut cursor, flags)
+        };
+        in_pos += in_consumed;
+        out_pos += out_consumed;
+
+        match status {
+            TINFLStatus::Done => {
+                output.truncate(out_pos);
+                return Ok(output);
+            }
+            TINFLStatus::HasMoreOutput if output.len() < MAX_BUFFER_SIZE => {
+                let len = std::cmp::min(MAX_BUFFER_SIZE, output.len() * 2);
+                output.resize(len, 0);
+            }
+            TINFLStatus::HasMoreOutput => {
+                return Err(DecodeError::TooBig { len: output.len() });
+            }
+            _ => return Err(DecodeError::Inflate),
+        }
+    }
+}
+
 fn delta_encode<'a, T: bytemuck::Pod>(
     base: &'a T,
     data: impl Iterator<Item = &'a T>,
-) -> Result<Vec<u8>, EncodeError> {
+) -> Result<DeltaBuffer, EncodeError> {
     let mut base = *base;
     let bits = bytemuck::bytes_of_mut(&mut base);
     let (lower, upper) = data.size_hint();
     let capacity = std::cmp::min(MAX_BUFFER_SIZE, upper.unwrap_or(lower) * bits.len());
-    let mut bytes = Vec::with_capacity(capacity);
+    let mut bytes = DeltaBuffer::with_capacity(capacity);
 
     // Create buffer of delta encoded bytes via XOR.
     for datum in data {
@@ -57,7 +257,19 @@ fn delta_encode<'a, T: bytemuck::Pod>(
 /// `[bytemuck::bytes_of]`.
 ///
 /// [Pod](bytemuck::Pod)
+#[cfg(test)]
 pub fn decode<T: Pod>(base: &T, data: impl AsRef<[u8]>) -> Result<Vec<T>, DecodeError> {
+    let mut output = Vec::new();
+    decode_into(base, data, &mut output)?;
+    Ok(output)
+}
+
+/// Same as `[decode]`, but appends the decoded values to the provided buffer.
+fn decode_into<T: Pod>(
+    base: &T,
+    data: impl AsRef<[u8]>,
+    output: &mut Vec<T>,
+) -> Result<(), DecodeError> {
     let mut base = *base;
     let bits = bytemuck::bytes_of_mut(&mut base);
     let stride = bits.len();
@@ -70,28 +282,32 @@ pub fn decode<T: Pod>(base: &T, data: impl AsRef<[u8]>) -> Result<Vec<T>, Decode
         return Err(DecodeError::TooBig { len: delta_len });
     }
 
-    let delta = bitfield::decode(data)?;
-    debug_assert!(delta.len() % stride == 0);
+    let mut delta: DeltaBuffer = smallvec![0; delta_len];
+    bitfield::decode_into(data, 0, &mut delta);
+    debug_assert!(delta.len().is_multiple_of(stride));
     let output_size = delta.len() / stride;
-    let mut output = Vec::with_capacity(output_size);
+    output.reserve(output_size);
 
     for idx in 0..output_size {
         for (local_idx, byte) in bits.iter_mut().enumerate() {
             *byte ^= delta[idx * stride + local_idx];
         }
-        output.push(*bytemuck::try_from_bytes::<T>(&bits)?)
+        output.push(*bytemuck::try_from_bytes::<T>(bits)?)
     }
 
-    Ok(output)
+    Ok(())
 }
 
 #[derive(Error, Debug)]
 pub enum EncodeError {
     #[error("Input buffer is too big: {}", .len)]
     TooBig { len: usize },
+    #[error("Baseline frame {} is ahead of the latest input: {}", .frame, .latest)]
+    BaselineAhead { frame: Frame, latest: Frame },
 }
 
-#[derive(Error, Debug)]
+/// An error that occured while decoding inputs received from a remote peer.
+#[derive(Error, Clone, Debug)]
 pub enum DecodeError {
     #[error("Cannot be cast {:?}", .0)]
     Cast(bytemuck::PodCastError),
@@ -99,6 +315,14 @@ pub enum DecodeError {
     InvalidRLEBitfield { offset: usize, len: usize },
     #[error("Output buffer is too big: {}", .len)]
     TooBig { len: usize },
+    #[error("Unsupported packet header flags: {:#04x}", .flags)]
+    UnsupportedHeader { flags: u8 },
+    #[error("Failed to inflate a compressed input buffer")]
+    Inflate,
+    #[error("Buffer length {} is not a multiple of the input size {}", .len, .stride)]
+    InvalidLength { len: usize, stride: usize },
+    #[error("{} inputs starting at frame {} are out of range", .len, .start_frame)]
+    InvalidFrame { start_frame: Frame, len: usize },
 }
 
 impl From<bytemuck::PodCastError> for DecodeError {
@@ -137,6 +361,170 @@ mod test {
         assert_eq!(decoded, buf);
     }
 
+    #[test]
+    pub fn test_small_and_large_batches_round_trip() {
+        let base = Input { x: 0, y: 0 };
+        for count in [1, INLINE_BUFFER_SIZE / 8, INLINE_BUFFER_SIZE / 8 + 1, 100] {
+            let buf: Vec<Input> = (0..count as i32).map(|i| Input { x: i, y: -i }).collect();
+            let encoded = encode(&base, buf.iter()).unwrap();
+            let decoded = decode(&base, encoded.iter()).unwrap();
+            assert_eq!(decoded, buf);
+        }
+    }
+
+    #[test]
+    pub fn test_pack_round_trip() {
+        let base = Input { x: 0, y: 0 };
+        let buf: Vec<Input> = (0..200).map(|i| Input { x: i % 7, y: i }).collect();
+        let encoded = encode(&base, buf.iter()).unwrap();
+        let packed = pack(encoded.clone());
+        assert!(packed.len() <= encoded.len() + 1);
+        assert_eq!(unpack(&packed).unwrap().as_ref(), encoded.as_slice());
+        assert_eq!(decode(&base, unpack(&packed).unwrap()).unwrap(), buf);
+    }
+
+    #[test]
+    pub fn test_raw_round_trip_matches_delta() {
+        let mut rng = rand::thread_rng();
+        let base = Input { x: 0, y: 0 };
+        let buf: Vec<Input> = (0..100)
+            .map(|_| Input {
+                x: rng.next_u32() as i32,
+                y: rng.next_u32() as i32,
+            })
+            .collect();
+        let raw = encode_raw(buf.iter()).unwrap();
+        assert_eq!(raw[0], FLAG_RAW);
+        assert_eq!(raw.len(), 1 + buf.len() * std::mem::size_of::<Input>());
+        let delta = pack(encode(&base, buf.iter()).unwrap());
+        assert_eq!(decode_packed(&base, &raw).unwrap(), buf);
+        assert_eq!(decode_packed(&base, &delta).unwrap(), buf);
+    }
+
+    #[test]
+    pub fn test_raw_rejects_partial_inputs() {
+        let base = Input { x: 0, y: 0 };
+        assert!(matches!(
+            decode_packed(&base, &[FLAG_RAW, 1, 2, 3]),
+            Err(DecodeError::InvalidLength { len: 3, stride: 8 })
+        ));
+        assert!(matches!(
+            decode_packed(&base, &[FLAG_RAW | FLAG_DEFLATED, 1, 2, 3]),
+            Err(DecodeError::UnsupportedHeader { .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_baseline_reset_flag_does_not_change_contents() {
+        let base = Input { x: 0, y: 0 };
+        let buf = vec![Input { x: 1, y: 2 }, Input { x: 3, y: 4 }];
+        for mut packed in [
+            pack(encode(&base, buf.iter()).unwrap()),
+            encode_raw(buf.iter()).unwrap(),
+        ] {
+            assert!(!is_baseline_reset(&packed));
+            mark_baseline_reset(&mut packed);
+            assert!(is_baseline_reset(&packed));
+            assert_eq!(decode_packed(&base, &packed).unwrap(), buf);
+        }
+        let mut empty = Vec::new();
+        mark_baseline_reset(&mut empty);
+        assert!(!is_baseline_reset(&empty));
+    }
+
+    #[test]
+    pub fn test_unpack_rejects_unknown_flags() {
+        assert!(matches!(
+            unpack(&[0x80, 1, 2, 3]),
+            Err(DecodeError::UnsupportedHeader { flags: 0x80 })
+        ));
+        assert!(unpack(&[]).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    pub fn test_pack_deflates_repetitive_buffers() {
+        let bits: Vec<u8> = (0..1000).map(|i| (i % 3) as u8 + 1).collect();
+        let packed = pack(bits.clone());
+        assert_eq!(packed[0], FLAG_DEFLATED);
+        assert!(packed.len() < bits.len());
+        assert_eq!(unpack(&packed).unwrap().as_ref(), bits.as_slice());
+    }
+
+    #[test]
+    pub fn test_unpack_inflates_regardless_of_feature() {
+        let base = Input { x: 0, y: 0 };
+        let buf: Vec<Input> = (0..200).map(|i| Input { x: i % 7, y: i }).collect();
+        let encoded = encode(&base, buf.iter()).unwrap();
+        // Built by hand, as `pack` only deflates with the `deflate` feature enabled.
+        let deflated = with_header(
+            FLAG_DEFLATED,
+            miniz_oxide::deflate::compress_to_vec(&encoded, 6),
+        );
+        assert_eq!(unpack(&deflated).unwrap().as_ref(), encoded.as_slice());
+        assert_eq!(decode_packed(&base, &deflated).unwrap(), buf);
+    }
+
+    #[test]
+    pub fn test_unpack_rejects_corrupt_deflate_stream() {
+        assert!(unpack(&[FLAG_DEFLATED, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    /// An input that stores its field in little-endian order regardless of the platform.
+    #[repr(C)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    struct LittleEndianInput([u8; 4]);
+
+    unsafe impl Pod for LittleEndianInput {}
+    unsafe impl Zeroable for LittleEndianInput {}
+
+    impl LittleEndianInput {
+        fn new(value: i32) -> Self {
+            Self(value.to_le_bytes())
+        }
+
+        fn value(&self) -> i32 {
+            i32::from_le_bytes(self.0)
+        }
+    }
+
+    /// Four little-endian `i32`s, 1, 256, -2 and 0x12345678, as sent by any peer
+    /// in a raw buffer.
+    const LITTLE_ENDIAN_FIXTURE: [u8; 17] = [
+        FLAG_RAW, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff, 0x78,
+        0x56, 0x34, 0x12,
+    ];
+    const FIXTURE_VALUES: [i32; 4] = [1, 256, -2, 0x1234_5678];
+
+    #[test]
+    pub fn test_little_endian_inputs_match_fixture_on_any_platform() {
+        let base = LittleEndianInput::new(0);
+        let inputs: Vec<LittleEndianInput> = FIXTURE_VALUES
+            .iter()
+            .map(|value| LittleEndianInput::new(*value))
+            .collect();
+        assert_eq!(encode_raw(inputs.iter()).unwrap(), LITTLE_ENDIAN_FIXTURE);
+
+        let decoded = decode_packed(&base, &LITTLE_ENDIAN_FIXTURE).unwrap();
+        assert_eq!(
+            decoded
+                .iter()
+                .map(|input| input.value())
+                .collect::<Vec<_>>(),
+            FIXTURE_VALUES
+        );
+        let encoded = encode(&base, inputs.iter()).unwrap();
+        assert_eq!(decode(&base, encoded.iter()).unwrap(), decoded);
+    }
+
+    #[test]
+    pub fn test_native_integers_decode_fixture_in_platform_order() {
+        let decoded: Vec<i32> = decode_packed(&0, &LITTLE_ENDIAN_FIXTURE).unwrap();
+        // Only matches the sent values on little-endian platforms.
+        let expected: Vec<i32> = FIXTURE_VALUES.iter().map(|value| value.to_le()).collect();
+        assert_eq!(decoded, expected);
+    }
+
     #[test]
     pub fn test_empty_buffer() {
         let buf: Vec<Input> = Vec::new();
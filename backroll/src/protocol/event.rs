@@ -1,11 +1,13 @@
-use crate::input::FrameInput;
+use crate::{input::FrameInput, DisconnectReason, SynchronizationFailure};
 use std::time::Duration;
 
 pub(crate) enum Event<T> {
     Connected,
     Synchronizing { total: u8, count: u8 },
     Synchronized,
+    SynchronizationFailed(SynchronizationFailure),
     Inputs(Vec<FrameInput<T>>),
     NetworkInterrupted { disconnect_timeout: Duration },
     NetworkResumed,
+    Disconnected { reason: DisconnectReason },
 }
@@ -1,8 +1,10 @@
 use super::compression;
-use crate::{input::FrameInput, Frame};
+use crate::{input::FrameInput, Compression, Frame};
 use parking_lot::RwLock;
 use std::collections::VecDeque;
+use std::ops::Range;
 use std::sync::Arc;
+use tracing::debug;
 
 struct InputEncoderRef<T>
 where
@@ -12,6 +14,10 @@ where
 
     last_acked: Frame,
     last_encoded: Frame,
+    compression: Compression,
+    /// Set when [InputEncoder::set_baseline] skipped inputs, until the remote peer
+    /// acknowledges an input after the new baseline.
+    baseline_reset: bool,
 }
 
 /// A buffer of all inputs that have not been yet acknowledged by a connected remote peer.
@@ -24,16 +30,22 @@ where
 
 impl<T: bytemuck::Zeroable + bytemuck::Pod> Default for InputEncoder<T> {
     fn default() -> Self {
+        Self::new(Compression::Delta)
+    }
+}
+
+impl<T: bytemuck::Zeroable + bytemuck::Pod> InputEncoder<T> {
+    pub fn new(compression: Compression) -> Self {
         Self(Arc::new(RwLock::new(InputEncoderRef::<T> {
             pending: VecDeque::new(),
 
             last_acked: crate::NULL_FRAME,
             last_encoded: crate::NULL_FRAME,
+            compression,
+            baseline_reset: false,
         })))
     }
-}
 
-impl<T: bytemuck::Zeroable + bytemuck::Pod> InputEncoder<T> {
     /// Adds an input to as the latest element in the queue.
     pub fn push(&self, input: FrameInput<T>) {
         self.0.write().pending.push_back(input);
@@ -43,53 +55,187 @@ impl<T: bytemuck::Zeroable + bytemuck::Pod> InputEncoder<T> {
     pub fn last_encoded_frame(&self) -> Frame {
         self.0.read().last_encoded
     }
+
+    /// Gets the frame of the last input acknowledged by the remote peer.
+    pub fn last_acked_frame(&self) -> Frame {
+        self.0.read().last_acked
+    }
+
+    /// Gets the number of inputs that have not yet been acknowledged.
+    pub fn pending_len(&self) -> usize {
+        self.0.read().pending.len()
+    }
+
+    /// Gets how many frames the newest pending input is ahead of the last encoded
+    /// input, i.e. how many inputs have been added but never sent. Unlike
+    /// [pending_len], this does not depend on acknowledgements from the remote peer.
+    ///
+    /// [pending_len]: Self::pending_len
+    pub fn send_lag(&self) -> Frame {
+        let queue = self.0.read();
+        queue
+            .pending
+            .back()
+            .map(|input| std::cmp::max(0, input.frame - queue.last_encoded))
+            .unwrap_or(0)
+    }
 }
 
 impl<T: bytemuck::Zeroable + bytemuck::Pod + Clone> InputEncoder<T> {
     /// Acknowledges a given frame. All inputs with of a prior frame will be dropped.
     ///
     /// This will update the reference input that is used to delta-encode.
+    ///
+    /// Acknowledgements may arrive out of order. Any acknowledgement that is not newer
+    /// than the last acknowledged frame is ignored.
     pub fn acknowledge_frame(&self, ack_frame: Frame) {
         let mut queue = self.0.write();
+        if ack_frame <= queue.last_acked + 1 {
+            return;
+        }
         // Get rid of our buffered input
         let last = queue.pending.iter().filter(|i| i.frame < ack_frame).last();
         if let Some(last) = last {
             queue.last_acked = last.frame;
             queue.pending.retain(|i| i.frame >= ack_frame);
+            queue.baseline_reset = false;
         }
     }
 
-    /// Encodes all pending output as a byte buffer.
+    /// Sets the baseline of the encoder to a frame the remote peer is already known
+    /// to have received, as if it had been acknowledged. All inputs up to and including
+    /// the frame will be dropped, so the next encoded buffer will start immediately
+    /// after it.
+    ///
+    /// Baselines that are older than the last acknowledged frame are ignored.
+    ///
+    /// Encoded buffers are marked with a baseline reset until an input after the
+    /// baseline is acknowledged. The remote peer's decoder only honors the reset if it
+    /// already decoded every input up to the baseline, as received inputs must stay
+    /// contiguous. Otherwise the buffers are dropped, as the skipped inputs will never
+    /// be sent again.
+    ///
+    /// # Errors
+    /// Returns [EncodeError::BaselineAhead] if the frame is ahead of the latest input
+    /// provided to the encoder, as doing so would skip inputs that have never been sent.
+    ///
+    /// [EncodeError::BaselineAhead]: super::compression::EncodeError::BaselineAhead
+    pub fn set_baseline(&self, frame: Frame) -> Result<(), compression::EncodeError> {
+        let mut queue = self.0.write();
+        let latest = queue
+            .pending
+            .back()
+            .map(|input| input.frame)
+            .unwrap_or(queue.last_acked);
+        if frame > latest {
+            return Err(compression::EncodeError::BaselineAhead { frame, latest });
+        }
+        if frame > queue.last_acked {
+            queue.last_acked = frame;
+            queue.pending.retain(|i| i.frame > frame);
+            queue.baseline_reset = true;
+        }
+        Ok(())
+    }
+
+    /// Encodes all pending output as a single byte buffer.
     ///
     /// To minimize the size of the produced buffer, the sequence of is delta
     /// encoded by `[compression::encode]` relative to the last acknowledged
-    /// input, which is updated via `[acknowledge_frame]`.
+    /// input, which is updated via `[acknowledge_frame]`, then given a header
+    /// by `[compression::pack]`.
     ///
     /// This will not remove any of the inputs in the queue, but will update
     /// the value returned by `[last_encoded_frame]` to reflect the highest
     /// frame that has been encoded.
     pub fn encode(&self) -> Result<(Frame, Vec<u8>), compression::EncodeError> {
-        let zeroed = T::zeroed();
         let mut queue = self.0.write();
-        let pending = &queue.pending;
-        if !pending.is_empty() {
-            let start_frame = pending.front().unwrap().frame;
-            let inputs = pending.iter().map(|f| &f.input);
-            let bits = compression::encode(&zeroed, inputs)?;
+        if !queue.pending.is_empty() {
+            let start_frame = queue.pending.front().unwrap().frame;
+            let bits = Self::encode_range(&queue, 0..queue.pending.len(), queue.baseline_reset)?;
             queue.last_encoded = queue.pending.back().unwrap().frame;
             Ok((start_frame, bits))
         } else {
             Ok((queue.last_acked, Vec::new()))
         }
     }
+
+    /// Encodes all pending output as a sequence of byte buffers, each paired with
+    /// the frame of its first input. Inputs are split across buffers so that no
+    /// buffer is bigger than `max_bytes`, unless a single input does not fit on its
+    /// own. Each buffer is encoded the same way as [encode], and can be decoded
+    /// as soon as all of the buffers before it have been.
+    ///
+    /// If there are no pending inputs, a single empty buffer is returned.
+    ///
+    /// [encode]: Self::encode
+    pub fn encode_chunks(
+        &self,
+        max_bytes: usize,
+    ) -> Result<Vec<(Frame, Vec<u8>)>, compression::EncodeError> {
+        let mut queue = self.0.write();
+        if queue.pending.is_empty() {
+            return Ok(vec![(queue.last_acked, Vec::new())]);
+        }
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < queue.pending.len() {
+            let mut end = queue.pending.len();
+            loop {
+                // Later chunks continue from the first, so only it needs the flag.
+                let baseline_reset = start == 0 && queue.baseline_reset;
+                match Self::encode_range(&queue, start..end, baseline_reset) {
+                    Ok(bits) if bits.len() <= max_bytes || end - start == 1 => {
+                        chunks.push((queue.pending[start].frame, bits));
+                        break;
+                    }
+                    Err(err) if end - start == 1 => return Err(err),
+                    _ => end = start + (end - start) / 2,
+                }
+            }
+            start = end;
+        }
+
+        queue.last_encoded = queue.pending.back().unwrap().frame;
+        Ok(chunks)
+    }
+
+    fn encode_range(
+        queue: &InputEncoderRef<T>,
+        range: Range<usize>,
+        baseline_reset: bool,
+    ) -> Result<Vec<u8>, compression::EncodeError> {
+        let inputs = queue.pending.range(range).map(|f| &f.input);
+        let mut bits = match queue.compression {
+            Compression::Delta => {
+                let zeroed = T::zeroed();
+                compression::pack(compression::encode(&zeroed, inputs)?)
+            }
+            Compression::None => compression::encode_raw(inputs)?,
+        };
+        if baseline_reset {
+            compression::mark_baseline_reset(&mut bits);
+        }
+        Ok(bits)
+    }
 }
 
+/// The most inputs [InputDecoder] keeps allocated for reuse between calls to
+/// [InputDecoder::decode]. Larger buffers are still decoded, but their memory is
+/// released afterwards.
+const MAX_POOLED_INPUTS: usize = crate::MAX_ROLLBACK_FRAMES;
+
 struct InputDecoderRef<T>
 where
     T: bytemuck::Zeroable,
 {
     last_decoded: Frame,
-    phantom: std::marker::PhantomData<T>,
+    /// The latest frame accepted from the remote peer. See `[InputDecoder::set_max_frame]`.
+    max_frame: Frame,
+    /// Reused across calls to `[InputDecoder::decode]` to avoid reallocating.
+    buffer: Vec<T>,
+    high_water_mark: usize,
 }
 
 /// A stateful decoder that decodes delta patches created by `[InputEncoder]`.
@@ -104,7 +250,9 @@ impl<T: bytemuck::Zeroable + bytemuck::Pod> Default for InputDecoder<T> {
     fn default() -> Self {
         Self(Arc::new(RwLock::new(InputDecoderRef::<T> {
             last_decoded: crate::NULL_FRAME,
-            phantom: Default::default(),
+            max_frame: crate::MAX_ROLLBACK_FRAMES as Frame,
+            buffer: Vec::new(),
+            high_water_mark: 0,
         })))
     }
 }
@@ -116,39 +264,94 @@ impl<T: bytemuck::Zeroable + bytemuck::Pod> InputDecoder<T> {
     pub fn last_decoded_frame(&self) -> Frame {
         self.0.read().last_decoded
     }
+
+    /// Sets the latest frame accepted from the remote peer. Buffers with inputs past it
+    /// are rejected. Defaults to `[MAX_ROLLBACK_FRAMES]` frames after frame 0.
+    pub fn set_max_frame(&self, frame: Frame) {
+        self.0.write().max_frame = frame;
+    }
+
+    /// Gets the largest number of inputs decoded from a single buffer so far. The
+    /// decoder keeps a buffer of this size allocated for reuse, up to
+    /// `[MAX_POOLED_INPUTS]`.
+    pub fn high_water_mark(&self) -> usize {
+        self.0.read().high_water_mark
+    }
 }
 
 impl<T: bytemuck::Zeroable + bytemuck::Pod + Clone> InputDecoder<T> {
+    /// Decodes a buffer created by `[InputEncoder]`, appending the inputs that were
+    /// not decoded before to `output`. Returns how many inputs were appended.
     pub fn decode(
         &self,
         start_frame: Frame,
         bits: impl AsRef<[u8]>,
-    ) -> Result<Vec<FrameInput<T>>, compression::DecodeError> {
-        let mut decoder = self.0.write();
+        output: &mut Vec<FrameInput<T>>,
+    ) -> Result<usize, compression::DecodeError> {
+        let mut guard = self.0.write();
+        let decoder = &mut *guard;
+        let bits = bits.as_ref();
         let last_decoded_frame = decoder.last_decoded;
+        if !crate::is_null(last_decoded_frame) && start_frame.saturating_sub(1) > last_decoded_frame
+        {
+            // Either an earlier buffer was lost and its inputs will be resent, or the
+            // sender reset its baseline past inputs that were never decoded here. The
+            // received inputs must stay contiguous, so a baseline reset is only honored
+            // if it leaves no gap. Drop this buffer in both cases.
+            debug!(
+                "Dropping inputs starting at frame {} (last decoded: {}, baseline reset: {}).",
+                start_frame,
+                last_decoded_frame,
+                compression::is_baseline_reset(bits)
+            );
+            return Ok(0);
+        }
         let current_frame = if crate::is_null(decoder.last_decoded) {
-            start_frame - 1
+            start_frame.saturating_sub(1)
         } else {
             decoder.last_decoded
         };
         let zeroed = T::zeroed();
-        let frame_inputs = compression::decode(&zeroed, bits)?
-            .into_iter()
-            .enumerate()
-            .map(|(i, input)| FrameInput::<T> {
-                frame: start_frame + i as Frame,
-                input,
-            })
-            .skip_while(|input| input.frame <= current_frame)
-            .collect::<Vec<_>>();
+        compression::decode_packed_into(&zeroed, bits, &mut decoder.buffer)?;
+        let len = decoder.buffer.len();
+        // Frames received from the remote peer may be arbitrary. Reject any buffer whose
+        // frames are not all valid, before computing them.
+        if len > 0
+            && (crate::is_null(start_frame)
+                || crate::checked_frame_add(start_frame, len - 1)
+                    .map(|frame| frame > decoder.max_frame)
+                    .unwrap_or(true))
+        {
+            return Err(compression::DecodeError::InvalidFrame { start_frame, len });
+        }
+        decoder.high_water_mark = std::cmp::max(decoder.high_water_mark, len);
+        let skip = std::cmp::min(
+            current_frame
+                .saturating_add(1)
+                .saturating_sub(start_frame)
+                .max(0) as usize,
+            len,
+        );
+        output.reserve(len - skip);
+        output.extend(decoder.buffer[skip..].iter().enumerate().map(
+            |(i, input)| FrameInput::<T> {
+                frame: start_frame + (skip + i) as Frame,
+                input: *input,
+            },
+        ));
+        if skip < len {
+            decoder.last_decoded = start_frame + (len - 1) as Frame;
+        }
 
-        if let Some(latest) = frame_inputs.last() {
-            decoder.last_decoded = latest.clone().frame;
+        // Don't hold on to the memory of an unusually large buffer indefinitely.
+        if decoder.buffer.capacity() > MAX_POOLED_INPUTS {
+            decoder.buffer.clear();
+            decoder.buffer.shrink_to(MAX_POOLED_INPUTS);
         }
 
         debug_assert!(decoder.last_decoded >= last_decoded_frame);
 
-        Ok(frame_inputs)
+        Ok(len - skip)
     }
 }
 
@@ -168,6 +371,16 @@ mod test {
     unsafe impl Pod for Input {}
     unsafe impl Zeroable for Input {}
 
+    fn decode(
+        decoder: &InputDecoder<Input>,
+        start_frame: Frame,
+        bits: impl AsRef<[u8]>,
+    ) -> Result<Vec<FrameInput<Input>>, compression::DecodeError> {
+        let mut inputs = Vec::new();
+        decoder.decode(start_frame, bits, &mut inputs)?;
+        Ok(inputs)
+    }
+
     #[test]
     pub fn test_same_input_compresses_down() {
         let encoder = InputEncoder::<Input>::default();
@@ -180,9 +393,9 @@ mod test {
         }
 
         let (start, encoded) = encoder.encode().unwrap();
-        let decoded = decoder.decode(start, &encoded).unwrap();
+        let decoded = decode(&decoder, start, &encoded).unwrap();
         assert_eq!(start, 0);
-        assert_eq!(encoded, vec![4, 164, 1, 9, 4, 57, 5, 233, 24]);
+        assert_eq!(encoded, vec![0, 4, 164, 1, 9, 4, 57, 5, 233, 24]);
         assert_eq!(
             decoded.into_iter().map(|f| f.input).collect::<Vec<Input>>(),
             buf
@@ -197,7 +410,7 @@ mod test {
         let buf: Vec<Input> = Vec::new();
 
         let (start, encoded) = encoder.encode().unwrap();
-        let decoded = decoder.decode(start, encoded.clone()).unwrap();
+        let decoded = decode(&decoder, start, encoded.clone()).unwrap();
         assert_eq!(start, -1);
         assert_eq!(
             decoded.into_iter().map(|f| f.input).collect::<Vec<Input>>(),
@@ -240,10 +453,363 @@ mod test {
         assert!(encoded_3 != encoded_2);
     }
 
+    #[test]
+    pub fn test_send_lag_tracks_unencoded_inputs() {
+        let encoder = InputEncoder::<Input>::default();
+        assert_eq!(encoder.send_lag(), 0);
+        for frame in 0..5 {
+            encoder.push(FrameInput::<Input> {
+                frame,
+                input: Input { x: frame, y: 0 },
+            });
+        }
+        assert_eq!(encoder.send_lag(), 5);
+        encoder.encode().unwrap();
+        assert_eq!(encoder.send_lag(), 0);
+        encoder.push(FrameInput::<Input> {
+            frame: 5,
+            input: Input { x: 5, y: 0 },
+        });
+        assert_eq!(encoder.send_lag(), 1);
+        // Acknowledgements do not affect the lag.
+        encoder.acknowledge_frame(5);
+        assert_eq!(encoder.send_lag(), 1);
+    }
+
+    #[test]
+    pub fn test_out_of_order_acks_are_ignored() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        for frame in 0..20 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+
+        encoder.acknowledge_frame(12);
+        assert_eq!(encoder.last_acked_frame(), 11);
+        // Delayed acks for older frames must not regress the baseline.
+        encoder.acknowledge_frame(5);
+        encoder.acknowledge_frame(12);
+        encoder.acknowledge_frame(0);
+        assert_eq!(encoder.last_acked_frame(), 11);
+        assert_eq!(encoder.pending_len(), 8);
+
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(start, 12);
+        let decoded = decode(&decoder, start, &encoded).unwrap();
+        assert_eq!(
+            decoded.into_iter().map(|f| f.input.x).collect::<Vec<_>>(),
+            (12..20).collect::<Vec<_>>()
+        );
+
+        encoder.acknowledge_frame(15);
+        assert_eq!(encoder.last_acked_frame(), 14);
+    }
+
+    #[test]
+    pub fn test_chunks_fit_and_decode_in_order() {
+        let mut rng = rand::thread_rng();
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        let mut buf: Vec<Input> = Vec::new();
+        for frame in 0..100 {
+            let input = Input {
+                x: rng.next_u32() as i32,
+                y: rng.next_u32() as i32,
+            };
+            buf.push(input);
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+
+        let chunks = encoder.encode_chunks(64).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(encoder.last_encoded_frame(), 99);
+        let mut decoded = Vec::new();
+        for (start, bits) in chunks {
+            assert!(bits.len() <= 64);
+            decoded.extend(decode(&decoder, start, &bits).unwrap());
+        }
+        assert_eq!(
+            decoded.into_iter().map(|f| f.input).collect::<Vec<Input>>(),
+            buf
+        );
+    }
+
+    #[test]
+    pub fn test_chunks_after_a_gap_are_dropped() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        for frame in 0..60 {
+            let input = Input {
+                x: frame * 1_000_003,
+                y: frame * 7_919,
+            };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+
+        let chunks = encoder.encode_chunks(64).unwrap();
+        assert!(chunks.len() > 2);
+        let (start, bits) = &chunks[0];
+        assert!(!decode(&decoder, *start, bits).unwrap().is_empty());
+        let last_decoded = decoder.last_decoded_frame();
+        // Skip the second chunk, as if it were lost.
+        let (start, bits) = &chunks[2];
+        assert!(decode(&decoder, *start, bits).unwrap().is_empty());
+        assert_eq!(decoder.last_decoded_frame(), last_decoded);
+        let (start, bits) = &chunks[1];
+        assert_eq!(
+            decode(&decoder, *start, bits)
+                .unwrap()
+                .first()
+                .unwrap()
+                .frame,
+            last_decoded + 1
+        );
+    }
+
+    #[test]
+    pub fn test_high_water_mark_tracks_largest_decode() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        assert_eq!(decoder.high_water_mark(), 0);
+        for frame in 0..10 {
+            encoder.push(FrameInput::<Input> {
+                frame,
+                input: Input { x: frame, y: 0 },
+            });
+        }
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(decode(&decoder, start, &encoded).unwrap().len(), 10);
+        assert_eq!(decoder.high_water_mark(), 10);
+
+        encoder.acknowledge_frame(9);
+        for frame in 10..12 {
+            encoder.push(FrameInput::<Input> {
+                frame,
+                input: Input { x: frame, y: 0 },
+            });
+        }
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(decode(&decoder, start, &encoded).unwrap().len(), 2);
+        // Smaller packets reuse the existing buffer.
+        assert_eq!(decoder.high_water_mark(), 10);
+    }
+
+    #[test]
+    pub fn test_large_buffers_are_not_pooled() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        let count = MAX_POOLED_INPUTS as Frame + 10;
+        decoder.set_max_frame(count);
+        for frame in 0..count {
+            encoder.push(FrameInput::<Input> {
+                frame,
+                input: Input { x: frame, y: 0 },
+            });
+        }
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(
+            decode(&decoder, start, &encoded).unwrap().len(),
+            count as usize
+        );
+        assert_eq!(decoder.high_water_mark(), count as usize);
+        assert!(decoder.0.read().buffer.capacity() <= MAX_POOLED_INPUTS);
+    }
+
+    #[test]
+    pub fn test_decode_appends_new_inputs_to_output() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        for frame in 0..3 {
+            encoder.push(FrameInput::<Input> {
+                frame,
+                input: Input { x: frame, y: 0 },
+            });
+        }
+        let (start, encoded) = encoder.encode().unwrap();
+        let mut output = decode(&decoder, start, &encoded).unwrap();
+        // Inputs that were already decoded are not appended again.
+        encoder.push(FrameInput::<Input> {
+            frame: 3,
+            input: Input { x: 3, y: 0 },
+        });
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(decoder.decode(start, &encoded, &mut output).unwrap(), 1);
+        let frames: Vec<Frame> = output.iter().map(|input| input.frame).collect();
+        assert_eq!(frames, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    pub fn test_decode_rejects_out_of_range_frames() {
+        let encoder = InputEncoder::<Input>::default();
+        for frame in 0..3 {
+            encoder.push(FrameInput::<Input> {
+                frame,
+                input: Input { x: frame, y: 0 },
+            });
+        }
+        let (_, encoded) = encoder.encode().unwrap();
+
+        let decoder = InputDecoder::<Input>::default();
+        decoder.set_max_frame(Frame::MAX);
+        let decoded = decode(&decoder, Frame::MAX - 2, &encoded).unwrap();
+        assert_eq!(
+            decoded.iter().map(|f| f.frame).collect::<Vec<_>>(),
+            vec![Frame::MAX - 2, Frame::MAX - 1, Frame::MAX]
+        );
+        assert_eq!(decoder.last_decoded_frame(), Frame::MAX);
+
+        let decoder = InputDecoder::<Input>::default();
+        decoder.set_max_frame(Frame::MAX);
+        assert!(matches!(
+            decode(&decoder, Frame::MAX - 1, &encoded),
+            Err(compression::DecodeError::InvalidFrame { len: 3, .. })
+        ));
+        assert!(matches!(
+            decode(&decoder, Frame::MIN, &encoded),
+            Err(compression::DecodeError::InvalidFrame { len: 3, .. })
+        ));
+        assert_eq!(decoder.last_decoded_frame(), crate::NULL_FRAME);
+        assert!(decode(&decoder, Frame::MIN, Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_inputs_past_max_frame_are_rejected() {
+        let encoder = InputEncoder::<Input>::default();
+        for frame in 0..3 {
+            let input = Input { x: frame, y: 0 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+        let (_, encoded) = encoder.encode().unwrap();
+
+        // The first buffer from a peer could otherwise start at any frame.
+        let decoder = InputDecoder::<Input>::default();
+        let max_frame = crate::MAX_ROLLBACK_FRAMES as Frame;
+        assert!(matches!(
+            decode(&decoder, Frame::MAX / 2, &encoded),
+            Err(compression::DecodeError::InvalidFrame { len: 3, .. })
+        ));
+        assert!(matches!(
+            decode(&decoder, max_frame - 1, &encoded),
+            Err(compression::DecodeError::InvalidFrame { len: 3, .. })
+        ));
+        assert_eq!(decoder.last_decoded_frame(), crate::NULL_FRAME);
+        assert_eq!(decode(&decoder, max_frame - 2, &encoded).unwrap().len(), 3);
+
+        // The limit moves forward with the local frame.
+        decoder.set_max_frame(max_frame + 3);
+        assert_eq!(decode(&decoder, max_frame + 1, &encoded).unwrap().len(), 3);
+        assert_eq!(decoder.last_decoded_frame(), max_frame + 3);
+    }
+
+    #[test]
+    pub fn test_baseline_drops_inputs_up_to_frame() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        for frame in 0..100 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+
+        let (_, full) = encoder.encode().unwrap();
+        encoder.set_baseline(89).unwrap();
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(start, 90);
+        assert!(encoded.len() < full.len());
+
+        let decoded = decode(&decoder, start, &encoded).unwrap();
+        assert_eq!(
+            decoded.into_iter().map(|f| f.input.x).collect::<Vec<_>>(),
+            (90..100).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    pub fn test_baseline_reset_mid_stream_decodes_after_reconnect() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        let xs = |decoded: Vec<FrameInput<Input>>| {
+            decoded.into_iter().map(|f| f.input.x).collect::<Vec<_>>()
+        };
+        for frame in 0..15 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(
+            xs(decode(&decoder, start, &encoded).unwrap()),
+            (0..15).collect::<Vec<_>>()
+        );
+
+        // The connection drops before the inputs are acknowledged. Once it resumes, the
+        // sender already knows that the decoder has every input up to frame 14.
+        for frame in 15..20 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+        encoder.set_baseline(14).unwrap();
+        let (start, encoded) = encoder.encode().unwrap();
+        assert_eq!(start, 15);
+        assert!(compression::is_baseline_reset(&encoded));
+        assert_eq!(
+            xs(decode(&decoder, start, &encoded).unwrap()),
+            (15..20).collect::<Vec<_>>()
+        );
+        assert_eq!(decoder.last_decoded_frame(), 19);
+        assert!(decode(&decoder, start, &encoded).unwrap().is_empty());
+
+        encoder.acknowledge_frame(decoder.last_decoded_frame() + 1);
+        let input = Input { x: 20, y: 1337 };
+        encoder.push(FrameInput::<Input> { frame: 20, input });
+        let (start, encoded) = encoder.encode().unwrap();
+        assert!(!compression::is_baseline_reset(&encoded));
+        assert_eq!(xs(decode(&decoder, start, &encoded).unwrap()), vec![20]);
+    }
+
+    #[test]
+    pub fn test_baseline_reset_past_last_decoded_frame_is_ignored() {
+        let encoder = InputEncoder::<Input>::default();
+        let decoder = InputDecoder::<Input>::default();
+        for frame in 0..20 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+        let (start, encoded) = encoder.encode().unwrap();
+        decode(&decoder, start, &encoded).unwrap();
+        assert_eq!(decoder.last_decoded_frame(), 19);
+
+        // Frames 20 to 24 were never sent, so a reset to frame 25 would leave a gap.
+        for frame in 20..30 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+        encoder.set_baseline(24).unwrap();
+        let (start, encoded) = encoder.encode().unwrap();
+        assert!(compression::is_baseline_reset(&encoded));
+        assert!(decode(&decoder, start, &encoded).unwrap().is_empty());
+        assert_eq!(decoder.last_decoded_frame(), 19);
+    }
+
+    #[test]
+    pub fn test_baseline_ahead_of_pending_inputs_is_rejected() {
+        let encoder = InputEncoder::<Input>::default();
+        for frame in 0..10 {
+            let input = Input { x: frame, y: 1337 };
+            encoder.push(FrameInput::<Input> { frame, input });
+        }
+
+        assert!(encoder.set_baseline(10).is_err());
+        encoder.set_baseline(4).unwrap();
+        // Older baselines are ignored.
+        encoder.set_baseline(2).unwrap();
+        let (start, _) = encoder.encode().unwrap();
+        assert_eq!(start, 5);
+    }
+
     #[test]
     pub fn test_random_data() {
         let mut rng = rand::thread_rng();
-        for i in 0..100 {
+        for _ in 0..100 {
             let encoder = InputEncoder::<Input>::default();
             let decoder = InputDecoder::<Input>::default();
             let mut buf: Vec<Input> = Vec::new();
@@ -265,7 +831,7 @@ mod test {
             }
 
             let (start, encoded) = encoder.encode().unwrap();
-            let decoded = decoder.decode(start, &encoded).unwrap();
+            let decoded = decode(&decoder, start, &encoded).unwrap();
             assert_eq!(start, 0);
             assert!(encoded.len() <= std::mem::size_of::<Input>() * buf.len());
             assert_eq!(decoded.len(), buf.len());
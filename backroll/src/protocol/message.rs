@@ -1,8 +1,13 @@
 use super::ConnectionStatus;
-use crate::{time_sync::UnixMillis, Frame};
+use crate::{time_sync::UnixMillis, DisconnectReason, Frame};
 use serde::{Deserialize, Serialize};
 use std::num::Wrapping;
 
+/// The version of the wire format. This is exchanged during the initial handshake,
+/// and peers with a different version are rejected. This must be bumped whenever
+/// any change is made to the serialized messages or how their contents are encoded.
+pub(super) const PROTOCOL_VERSION: u8 = 7;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(super) struct Message {
     pub magic: u16,
@@ -19,6 +24,7 @@ pub(super) enum MessageData {
     InputAck(InputAck),
     QualityReport(QualityReport),
     QualityReply(QualityReply),
+    Disconnect(Disconnect),
 }
 
 impl MessageData {
@@ -63,11 +69,19 @@ impl From<QualityReply> for MessageData {
     }
 }
 
+impl From<Disconnect> for MessageData {
+    fn from(value: Disconnect) -> Self {
+        Self::Disconnect(value)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(super) struct Input {
     pub peer_connect_status: Vec<ConnectionStatus>,
     pub start_frame: Frame,
     pub ack_frame: Frame,
+    /// The frame delay the sender applies to its own inputs.
+    pub frame_delay: Frame,
     pub bits: Vec<u8>,
 }
 
@@ -79,11 +93,15 @@ pub(super) struct InputAck {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(super) struct SyncRequest {
     pub random: u32,
+    pub protocol_version: u8,
+    pub player_count: u8,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(super) struct SyncReply {
     pub random: u32,
+    pub protocol_version: u8,
+    pub player_count: u8,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -96,3 +114,8 @@ pub(super) struct QualityReport {
 pub(super) struct QualityReply {
     pub pong: UnixMillis,
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct Disconnect {
+    pub reason: DisconnectReason,
+}
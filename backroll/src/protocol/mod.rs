@@ -2,22 +2,24 @@ use self::input_buffer::*;
 use self::message::*;
 use crate::{
     input::FrameInput,
-    time_sync::{TimeSync, UnixMillis},
-    Config, Frame, NetworkStats, TaskPool,
+    time_sync::{ClockDrift, PacketJitter, TimeSync, UnixMillis},
+    Config, DisconnectReason, Frame, NetworkStats, SyncState, SynchronizationFailure, TaskPool,
 };
-use async_channel::TrySendError;
+use async_channel::{SendError, TrySendError};
 use backroll_transport::Peer as TransportPeer;
 use bincode::config::Options;
 use futures::FutureExt;
 use futures_timer::Delay;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::num::Wrapping;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, error};
 
+pub use compression::DecodeError;
 pub(crate) use event::Event;
 
 mod bitfield;
@@ -30,14 +32,21 @@ pub enum PeerError {
     LocalDisconnected,
     RemoteDisconnected,
     InvalidMessage,
+    HandshakeFailed,
 }
 
 const UDP_HEADER_SIZE: usize = 28; // Size of IP + UDP headers
-const MAX_TRANSMISSION_UNIT: u64 = 1450; // A sane common packet size.
+/// A sane common packet size.
+const MAX_TRANSMISSION_UNIT: u64 = 1450;
+/// An upper bound on the serialized size of everything in an input packet other
+/// than the encoded inputs themselves.
+const INPUT_PACKET_OVERHEAD: usize = 80;
+const OVERSIZED_INPUT: &str = "The Backroll client has somehow sent created an input \
+                               of 65,535 bytes or more. This is ill advised. \
+                               Consider further compressing your inputs.";
 const NUM_SYNC_PACKETS: u8 = 5;
 const TARGET_TPS: u64 = 60;
 const POLL_INTERVAL: Duration = Duration::from_millis(1000 / TARGET_TPS);
-const SYNC_RETRY_INTERVAL: Duration = Duration::from_millis(2000);
 const SYNC_FIRST_RETRY_INTERVAL: Duration = Duration::from_millis(500);
 const RUNNING_RETRY_INTERVAL: Duration = Duration::from_millis(200);
 const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(200);
@@ -86,22 +95,26 @@ impl PeerState {
         matches!(self, Self::Running { .. } | Self::Interrupted { .. })
     }
 
-    fn create_sync_request(&self) -> SyncRequest {
+    fn create_sync_request(&self, player_count: u8) -> SyncRequest {
         if let PeerState::Connecting { random, .. } | PeerState::Syncing { random, .. } = self {
-            SyncRequest { random: *random }
+            SyncRequest {
+                random: *random,
+                protocol_version: PROTOCOL_VERSION,
+                player_count,
+            }
         } else {
             panic!("Sending sync request while not syncing.")
         }
     }
 
-    pub fn is_disconnected(&self) -> bool {
-        matches!(self, Self::Disconnected)
-    }
-
     pub fn is_interrupted(&self) -> bool {
         matches!(self, Self::Interrupted { .. })
     }
 
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected)
+    }
+
     pub fn start_syncing(&mut self, round_trips: u8) {
         if let Self::Connecting { random } = *self {
             *self = Self::Syncing {
@@ -140,13 +153,32 @@ impl Default for PeerState {
 struct PeerStats {
     pub packets_sent: usize,
     pub bytes_sent: usize,
+    /// When `packets_sent` and `bytes_sent` started counting, for computing `kbps_sent`.
+    pub counting_since: Option<UnixMillis>,
     pub last_send_time: Option<UnixMillis>,
+    pub last_recv_time: Option<Instant>,
+    pub sync_retries: u32,
     pub last_input_packet_recv_time: UnixMillis,
     pub round_trip_time: Duration,
     pub kbps_sent: u32,
 
+    pub decode_errors: usize,
+    pub last_decode_error: Option<DecodeError>,
+
     pub local_frame_advantage: Frame,
     pub remote_frame_advantage: Frame,
+    /// The round trip time used to estimate `local_frame_advantage`.
+    pub frame_advantage_rtt: Duration,
+    pub clock_drift: ClockDrift,
+    pub recv_jitter: PacketJitter,
+    /// When the last sync request was sent, for measuring the round trip time during
+    /// the handshake.
+    pub sync_request_time: Option<Instant>,
+
+    /// The frame delay of the local player, sent alongside every input packet.
+    pub local_frame_delay: Frame,
+    /// The frame delay the remote peer reported for its own inputs.
+    pub remote_frame_delay: Option<Frame>,
 }
 
 #[derive(Clone)]
@@ -154,6 +186,10 @@ pub(crate) struct PeerConfig {
     pub peer: TransportPeer,
     pub disconnect_timeout: Duration,
     pub disconnect_notify_start: Duration,
+    /// If set, `disconnect_notify_start` is raised to the mean packet interval plus
+    /// this many standard deviations.
+    pub adaptive_notify_multiplier: Option<f32>,
+    pub frame_delay: Frame,
     pub task_pool: TaskPool,
 }
 
@@ -168,12 +204,22 @@ where
 
     stats: Arc<RwLock<PeerStats>>,
     local_connect_status: Arc<[RwLock<ConnectionStatus>]>,
-    peer_connect_status: Vec<ConnectionStatus>,
+    peer_connect_status: Arc<RwLock<Vec<ConnectionStatus>>>,
 
     input_encoder: InputEncoder<T::Input>,
     input_decoder: InputDecoder<T::Input>,
 
+    /// The transport currently used to reach the remote peer. Starts as
+    /// [PeerConfig::peer], but may be swapped via [replace_transport].
+    ///
+    /// [replace_transport]: Self::replace_transport
+    transport: Arc<RwLock<TransportPeer>>,
     message_in: async_channel::Receiver<Message>,
+    /// Feeds [message_in]. Used for packets received outside of the transport.
+    ///
+    /// [message_in]: Self::message_in
+    message_in_tx: async_channel::Sender<Message>,
+    next_recv_seq: Arc<Mutex<Wrapping<u16>>>,
     message_out: async_channel::Sender<MessageData>,
     events: async_channel::Sender<Event<T::Input>>,
 }
@@ -193,7 +239,10 @@ impl<T: Config> Clone for Peer<T> {
             input_encoder: self.input_encoder.clone(),
             input_decoder: self.input_decoder.clone(),
 
+            transport: self.transport.clone(),
             message_in: self.message_in.clone(),
+            message_in_tx: self.message_in_tx.clone(),
+            next_recv_seq: self.next_recv_seq.clone(),
             message_out: self.message_out.clone(),
             events: self.events.clone(),
         }
@@ -214,23 +263,30 @@ impl<T: Config> Peer<T> {
             .map(|status| status.read().clone())
             .collect();
         let task_pool = config.task_pool.clone();
+        let stats = PeerStats {
+            local_frame_delay: config.frame_delay,
+            ..Default::default()
+        };
 
         let peer = Self {
             queue,
-            config,
-            timesync: Default::default(),
+            timesync: TimeSync::new(T::FRAME_ADVANTAGE_WINDOW),
             state: Default::default(),
 
-            stats: Default::default(),
+            stats: Arc::new(RwLock::new(stats)),
             local_connect_status,
-            peer_connect_status,
+            peer_connect_status: Arc::new(RwLock::new(peer_connect_status)),
 
-            input_encoder: Default::default(),
+            input_encoder: InputEncoder::new(T::COMPRESSION),
             input_decoder: Default::default(),
 
+            transport: Arc::new(RwLock::new(config.peer.clone())),
             message_in,
+            message_in_tx: deserialize_send.clone(),
+            next_recv_seq: Arc::new(Mutex::new(Wrapping(0))),
             message_out,
             events,
+            config,
         };
 
         // Start the base subtasks on the provided executor
@@ -249,6 +305,38 @@ impl<T: Config> Peer<T> {
         self.state.read().is_running()
     }
 
+    #[cfg(test)]
+    pub(crate) fn set_running(&self) {
+        *self.state.write() = PeerState::Running { remote_magic: 0 };
+    }
+
+    /// Gets the progress of the synchronization handshake. Returns `None` once
+    /// the handshake has completed.
+    pub fn sync_state(&self) -> Option<SyncState> {
+        match *self.state.read() {
+            PeerState::Connecting { .. } => Some(SyncState::AwaitingReply),
+            PeerState::Syncing {
+                roundtrips_remaining,
+                ..
+            } => Some(SyncState::Synchronizing {
+                count: NUM_SYNC_PACKETS - roundtrips_remaining,
+                total: NUM_SYNC_PACKETS,
+            }),
+            PeerState::Running { .. } | PeerState::Interrupted { .. } => None,
+            PeerState::Disconnected => Some(SyncState::Failed),
+        }
+    }
+
+    /// Disconnects from the remote peer, notifying it of why the connection
+    /// is being closed.
+    pub fn disconnect_with_reason(&self, reason: DisconnectReason) {
+        if !self.state.read().is_disconnected() {
+            // Failure to send just means the remote peer is already gone.
+            let _ = self.send(Disconnect { reason });
+        }
+        self.disconnect();
+    }
+
     pub fn disconnect(&self) {
         *self.state.write() = PeerState::Disconnected;
         self.message_in.close();
@@ -277,6 +365,18 @@ impl<T: Config> Peer<T> {
         }
     }
 
+    /// Zeroes the cumulative packet, byte and decode error counters. Live values,
+    /// such as the round trip time and frame advantages, are kept.
+    pub fn reset_network_stats(&self) {
+        let mut stats = self.stats.write();
+        stats.packets_sent = 0;
+        stats.bytes_sent = 0;
+        stats.counting_since = None;
+        stats.kbps_sent = 0;
+        stats.decode_errors = 0;
+        stats.last_decode_error = None;
+    }
+
     pub fn get_network_stats(&self) -> NetworkStats {
         let stats = self.stats.read();
         NetworkStats {
@@ -284,12 +384,17 @@ impl<T: Config> Peer<T> {
             send_queue_len: self.message_out.len(),
             recv_queue_len: self.message_in.len(),
             kbps_sent: stats.kbps_sent,
+            decode_errors: stats.decode_errors,
 
             local_frames_behind: stats.local_frame_advantage,
             remote_frames_behind: stats.remote_frame_advantage,
         }
     }
 
+    pub fn last_recv_time(&self) -> Option<Instant> {
+        self.stats.read().last_recv_time
+    }
+
     pub fn send_input(&self, input: FrameInput<T::Input>) -> Result<(), PeerError> {
         if self.state.read().is_running() {
             let stats = self.stats.read();
@@ -311,22 +416,67 @@ impl<T: Config> Peer<T> {
         self.send_pending_output()
     }
 
+    pub fn set_input_baseline(&self, frame: Frame) -> Result<(), compression::EncodeError> {
+        self.input_encoder.set_baseline(frame)
+    }
+
+    pub fn last_acked_frame(&self) -> Frame {
+        self.input_encoder.last_acked_frame()
+    }
+
+    pub fn last_encoded_frame(&self) -> Frame {
+        self.input_encoder.last_encoded_frame()
+    }
+
+    pub fn send_lag(&self) -> Frame {
+        self.input_encoder.send_lag()
+    }
+
+    pub fn pending_input_count(&self) -> usize {
+        self.input_encoder.pending_len()
+    }
+
+    pub fn last_decoded_frame(&self) -> Frame {
+        self.input_decoder.last_decoded_frame()
+    }
+
+    pub fn decode_high_water_mark(&self) -> usize {
+        self.input_decoder.high_water_mark()
+    }
+
+    /// Encodes all unacknowledged inputs as a single buffer without sending it.
+    /// Returns `None` if there are no pending inputs.
+    pub fn encode_pending(&self) -> Option<(Frame, Vec<u8>)> {
+        if self.input_encoder.pending_len() == 0 {
+            return None;
+        }
+        Some(self.input_encoder.encode().expect(OVERSIZED_INPUT))
+    }
+
     fn send_pending_output(&self) -> Result<(), PeerError> {
-        let (start_frame, bits) = self.input_encoder.encode().expect(
-            "The Backroll client has somehow sent created an input \
-             queue of 65,535 bytes or more. This is ill advised. \
-             Consider further compressing your inputs.",
-        );
-        self.send(Input {
-            peer_connect_status: self
-                .local_connect_status
-                .iter()
-                .map(|status| status.read().clone())
-                .collect(),
-            start_frame,
-            ack_frame: self.input_decoder.last_decoded_frame(),
-            bits,
-        })
+        let max_bytes = std::cmp::min(T::MAX_PACKET_BYTES, MAX_TRANSMISSION_UNIT as usize)
+            .saturating_sub(INPUT_PACKET_OVERHEAD);
+        let chunks = self
+            .input_encoder
+            .encode_chunks(max_bytes)
+            .expect(OVERSIZED_INPUT);
+        let peer_connect_status: Vec<ConnectionStatus> = self
+            .local_connect_status
+            .iter()
+            .map(|status| status.read().clone())
+            .collect();
+        let ack_frame = self.input_decoder.last_decoded_frame();
+        let frame_delay = self.stats.read().local_frame_delay;
+        for (start_frame, bits) in chunks {
+            self.send(Input {
+                peer_connect_status: peer_connect_status.clone(),
+                start_frame,
+                ack_frame,
+                frame_delay,
+                bits,
+            })?;
+        }
+        Ok(())
     }
 
     async fn heartbeat(self, interval: Duration) {
@@ -367,6 +517,7 @@ impl<T: Config> Peer<T> {
                         self.input_decoder.last_decoded_frame(),
                         self.input_encoder.last_encoded_frame());
                     stats.last_input_packet_recv_time = now;
+                    drop(stats);
                     self.send_pending_output()?;
                 }
             }
@@ -399,7 +550,7 @@ impl<T: Config> Peer<T> {
                 },
                 _ = Delay::new(POLL_INTERVAL).fuse() => {
                     let timeout = self.config.disconnect_timeout;
-                    let notify_start = self.config.disconnect_notify_start;
+                    let notify_start = self.disconnect_notify_threshold();
                     let now = UnixMillis::now();
 
                     {
@@ -409,7 +560,7 @@ impl<T: Config> Peer<T> {
                             debug!("Endpoint has stopped receiving packets for {} ms.  Sending notification.",
                                   notify_start.as_millis());
                             self.push_event(Event::<T::Input>::NetworkInterrupted {
-                                disconnect_timeout: timeout - notify_start
+                                disconnect_timeout: timeout.saturating_sub(notify_start)
                             })?;
                         }
                     }
@@ -419,6 +570,9 @@ impl<T: Config> Peer<T> {
                             "Endpoint has stopped receiving packets for {} ms. Disconnecting.",
                             timeout.as_millis()
                         );
+                        let _ = self.push_event(Event::<T::Input>::Disconnected {
+                            reason: DisconnectReason::Timeout,
+                        });
                         self.disconnect();
                         return Err(PeerError::RemoteDisconnected);
                     }
@@ -431,23 +585,43 @@ impl<T: Config> Peer<T> {
     fn poll(&mut self) -> Result<(), PeerError> {
         let state = self.state.read();
         let next_interval = match *state {
-            PeerState::Connecting { .. } => SYNC_FIRST_RETRY_INTERVAL,
-            PeerState::Syncing { .. } => SYNC_RETRY_INTERVAL,
+            PeerState::Connecting { .. } => {
+                std::cmp::min(SYNC_FIRST_RETRY_INTERVAL, T::SYNC_RETRY_INTERVAL)
+            }
+            PeerState::Syncing { .. } => T::SYNC_RETRY_INTERVAL,
             _ => return Ok(()),
         };
         let now = UnixMillis::now();
-        if let Some(last_send_time) = self.stats.read().last_send_time {
+        let last_send_time = self.stats.read().last_send_time;
+        if let Some(last_send_time) = last_send_time {
             if last_send_time + next_interval < now {
+                let retries = {
+                    let mut stats = self.stats.write();
+                    stats.sync_retries += 1;
+                    stats.sync_retries
+                };
+                if let Some(max_retries) = T::SYNC_MAX_RETRIES {
+                    if retries > max_retries {
+                        drop(state);
+                        let failure = SynchronizationFailure::RetriesExhausted {
+                            retries: max_retries,
+                        };
+                        error!("Giving up on syncing queue {}: {:?}.", self.queue, failure);
+                        self.push_event(Event::<T::Input>::SynchronizationFailed(failure))?;
+                        self.disconnect();
+                        return Err(PeerError::HandshakeFailed);
+                    }
+                }
                 debug!(
                     "No luck syncing after {:?} ms... Re-queueing sync packet.",
                     next_interval
                 );
-                self.send(state.create_sync_request())?;
+                self.send_sync_request(&state)?;
             }
         } else {
             // If we have not sent anything yet, kick off the connection with a
             // sync request.
-            self.send(state.create_sync_request())?;
+            self.send_sync_request(&state)?;
         }
 
         Ok(())
@@ -480,7 +654,7 @@ impl<T: Config> Peer<T> {
             }
 
             let msg_size = bytes.len();
-            if let Ok(()) = self.config.peer.send(bytes.into()).await {
+            if self.send_to_transport(bytes.into()).await {
                 let mut stats = self.stats.write();
                 stats.packets_sent += 1;
                 stats.last_send_time = Some(UnixMillis::now());
@@ -496,49 +670,106 @@ impl<T: Config> Peer<T> {
         self,
         messages: async_channel::Sender<Message>,
     ) -> Result<(), PeerError> {
-        let mut next_recv_seq = Wrapping(0);
-
-        while let Ok(bytes) = self.config.peer.recv().await {
-            let mut bincode = bincode::de::Deserializer::with_reader(
-                &*bytes,
-                bincode::options().with_limit(MAX_TRANSMISSION_UNIT),
-            );
-            let message = match Message::deserialize(&mut bincode) {
-                Ok(message) => message,
-                Err(err) => {
-                    error!("Dropping incoming message. Error while deserialilzing incoming message: {:?}", err);
-                    continue;
+        loop {
+            let transport = self.transport.read().clone();
+            while let Ok(bytes) = transport.recv().await {
+                if let Some(message) = self.accept_packet(&bytes) {
+                    messages
+                        .send(message)
+                        .await
+                        .map_err(|_| PeerError::LocalDisconnected)?;
                 }
-            };
+            }
+            // The transport may have been closed because it was replaced. Keep
+            // receiving from the replacement if so.
+            if !self.transport.read().is_connected() {
+                break;
+            }
+        }
 
-            let seq = message.sequence_number;
-            if message.data.is_sync_message() {
-                if let PeerState::Running { remote_magic } = *self.state.read() {
-                    if message.magic != remote_magic {
-                        continue;
+        debug!("Stopped receiving messages for queue: {}", self.queue);
+        Ok(())
+    }
+
+    /// Sends a packet over the current transport, retrying on the replacement if the
+    /// transport was replaced while sending. Returns false if the transport has been
+    /// disconnected.
+    async fn send_to_transport(&self, mut bytes: Box<[u8]>) -> bool {
+        loop {
+            let transport = self.transport.read().clone();
+            match transport.send(bytes).await {
+                Ok(()) => return true,
+                Err(SendError(unsent)) => {
+                    if !self.transport.read().is_connected() {
+                        return false;
                     }
+                    bytes = unsent;
                 }
+            }
+        }
+    }
 
-                // filter out out-of-order packets
-                let skipped = seq - next_recv_seq;
-                if skipped > MAX_SEQ_DISTANCE {
-                    debug!(
-                        "dropping out of order packet (seq: {}, last seq: {})",
-                        seq, next_recv_seq
-                    );
-                    continue;
+    /// Replaces the transport used to reach the remote peer, i.e. after the remote
+    /// peer's address changed. All protocol state is kept, so the connection
+    /// continues without resynchronizing. The old transport is disconnected.
+    pub fn replace_transport(&self, transport: TransportPeer) {
+        let old = std::mem::replace(&mut *self.transport.write(), transport);
+        old.disconnect();
+    }
+
+    /// Handles a packet received from the remote peer outside of the transport, the
+    /// same way as packets received from the transport.
+    pub fn receive_packet(&self, bytes: &[u8]) {
+        if let Some(message) = self.accept_packet(bytes) {
+            // Failure to send just means the peer has already been shut down.
+            let _ = self.message_in_tx.try_send(message);
+        }
+    }
+
+    /// Deserializes a received packet. Returns `None` if the packet is malformed or
+    /// should otherwise be dropped.
+    fn accept_packet(&self, bytes: &[u8]) -> Option<Message> {
+        let mut bincode = bincode::de::Deserializer::with_reader(
+            bytes,
+            bincode::options().with_limit(MAX_TRANSMISSION_UNIT),
+        );
+        let message = match Message::deserialize(&mut bincode) {
+            Ok(message) => message,
+            Err(err) => {
+                error!(
+                    "Dropping incoming message. Error while deserialilzing incoming message: {:?}",
+                    err
+                );
+                return None;
+            }
+        };
+
+        let mut next_recv_seq = self.next_recv_seq.lock();
+        let seq = message.sequence_number;
+        if message.data.is_sync_message() {
+            if let PeerState::Running { remote_magic } = *self.state.read() {
+                if message.magic != remote_magic {
+                    return None;
                 }
             }
 
-            next_recv_seq = message.sequence_number;
-            messages
-                .send(message)
-                .await
-                .map_err(|_| PeerError::LocalDisconnected)?;
+            // filter out out-of-order packets
+            let skipped = seq - *next_recv_seq;
+            if skipped > MAX_SEQ_DISTANCE {
+                debug!(
+                    "dropping out of order packet (seq: {}, last seq: {})",
+                    seq, *next_recv_seq
+                );
+                return None;
+            }
         }
 
-        debug!("Stopped receiving messages for queue: {}", self.queue);
-        Ok(())
+        *next_recv_seq = message.sequence_number;
+        let now = Instant::now();
+        let mut stats = self.stats.write();
+        stats.last_recv_time = Some(now);
+        stats.recv_jitter.sample(now);
+        Some(message)
     }
 
     async fn handle_message(&mut self, message: Message) -> Result<(), PeerError> {
@@ -556,29 +787,34 @@ impl<T: Config> Peer<T> {
                 self.stats.write().round_trip_time = UnixMillis::now() - data.pong;
                 Ok(())
             }
+            MessageData::Disconnect(data) => {
+                debug!(
+                    "Remote peer disconnected from queue {}: {:?}",
+                    self.queue, data.reason
+                );
+                self.push_event(Event::<T::Input>::Disconnected {
+                    reason: data.reason,
+                })?;
+                Err(PeerError::RemoteDisconnected)
+            }
         }
     }
 
     async fn update_network_stats(self, interval: Duration) {
-        let mut start_time: Option<UnixMillis> = None;
-
         loop {
             Delay::new(interval).await;
 
             if !self.is_running() {
-                start_time = None;
+                self.stats.write().counting_since = None;
                 continue;
             }
 
             let now = UnixMillis::now();
-            if start_time.is_none() {
-                start_time = Some(now);
-            }
-
             let mut stats = self.stats.write();
+            let start_time = *stats.counting_since.get_or_insert(now);
             let total_bytes_sent =
                 (stats.bytes_sent + (UDP_HEADER_SIZE * stats.packets_sent)) as f32;
-            let seconds = (now - start_time.unwrap()).as_millis() as f32 / 1000.0;
+            let seconds = (now - start_time).as_millis() as f32 / 1000.0;
             let bps = total_bytes_sent / seconds;
             let udp_overhead =
                 100.0 * (UDP_HEADER_SIZE * stats.packets_sent) as f32 / stats.bytes_sent as f32;
@@ -589,19 +825,29 @@ impl<T: Config> Peer<T> {
                 KB Sent: {} UDP Overhead: {:.2}.",
                 stats.kbps_sent,
                 stats.packets_sent,
-                stats.packets_sent as f32 * 1000.0 / (now - start_time.unwrap()).as_millis() as f32,
+                stats.packets_sent as f32 * 1000.0 / (now - start_time).as_millis() as f32,
                 total_bytes_sent / 1024.0,
                 udp_overhead
             );
         }
     }
 
-    pub fn get_peer_connect_status(&self, id: usize) -> &ConnectionStatus {
-        &self.peer_connect_status[id]
+    pub fn get_peer_connect_status(&self, id: usize) -> ConnectionStatus {
+        self.peer_connect_status.read()[id].clone()
+    }
+
+    /// Gets the remote peer's view of the connection status of every queue, as last
+    /// reported in its input packets.
+    pub fn peer_connect_statuses(&self) -> Vec<ConnectionStatus> {
+        self.peer_connect_status.read().clone()
     }
 
     fn on_sync_request(&mut self, magic: u16, data: SyncRequest) -> Result<(), PeerError> {
-        let SyncRequest { random } = data;
+        let SyncRequest {
+            random,
+            protocol_version,
+            player_count,
+        } = data;
         if let PeerState::Running { remote_magic } = *self.state.read() {
             if magic != remote_magic {
                 debug!(
@@ -611,24 +857,65 @@ impl<T: Config> Peer<T> {
                 return Err(PeerError::InvalidMessage);
             }
         }
-        self.send(SyncReply { random })?;
-        Ok(())
+        // Always reply, even to incompatible peers, so that the remote side also
+        // learns of the mismatch.
+        self.send(SyncReply {
+            random,
+            protocol_version: PROTOCOL_VERSION,
+            player_count: self.player_count(),
+        })?;
+        self.check_handshake(protocol_version, player_count)
+    }
+
+    fn send_sync_request(&self, state: &PeerState) -> Result<(), PeerError> {
+        self.stats.write().sync_request_time = Some(Instant::now());
+        self.send(state.create_sync_request(self.player_count()))
+    }
+
+    fn player_count(&self) -> u8 {
+        self.local_connect_status.len() as u8
+    }
+
+    /// Checks that the remote peer is compatible with the local session.
+    fn check_handshake(&self, protocol_version: u8, player_count: u8) -> Result<(), PeerError> {
+        let failure = if protocol_version != PROTOCOL_VERSION {
+            SynchronizationFailure::ProtocolVersion {
+                local: PROTOCOL_VERSION,
+                remote: protocol_version,
+            }
+        } else if player_count != self.player_count() {
+            SynchronizationFailure::PlayerCount {
+                local: self.player_count(),
+                remote: player_count,
+            }
+        } else {
+            return Ok(());
+        };
+        error!("Rejecting peer for queue {}: {:?}.", self.queue, failure);
+        self.push_event(Event::<T::Input>::SynchronizationFailed(failure))?;
+        Err(PeerError::HandshakeFailed)
     }
 
     fn on_sync_reply(&self, magic: u16, data: SyncReply) -> Result<(), PeerError> {
+        self.check_handshake(data.protocol_version, data.player_count)?;
         let mut state = self.state.write();
         if let Some(random) = state.random() {
             if data.random != random {
                 debug!("sync reply {} != {}.  Keep looking...", data.random, random);
                 return Err(PeerError::InvalidMessage);
             }
+            let mut stats = self.stats.write();
+            if let Some(sent) = stats.sync_request_time {
+                stats.round_trip_time = sent.elapsed();
+            }
         }
 
         match *state {
             PeerState::Connecting { .. } => {
+                self.stats.write().sync_retries = 0;
                 self.push_event(Event::<T::Input>::Connected)?;
                 state.start_syncing(NUM_SYNC_PACKETS);
-                self.send(state.create_sync_request())?;
+                self.send_sync_request(&state)?;
                 Ok(())
             }
             PeerState::Syncing {
@@ -641,6 +928,7 @@ impl<T: Config> Peer<T> {
                 );
                 debug_assert!(*roundtrips_remaining > 0);
                 *roundtrips_remaining -= 1;
+                self.stats.write().sync_retries = 0;
                 if *roundtrips_remaining == 0 {
                     debug!("Synchronized queue {}!", self.queue);
                     self.push_event(Event::<T::Input>::Synchronized)?;
@@ -669,9 +957,9 @@ impl<T: Config> Peer<T> {
                 } else {
                     self.push_event(Event::<T::Input>::Synchronizing {
                         total: NUM_SYNC_PACKETS,
-                        count: NUM_SYNC_PACKETS - *roundtrips_remaining as u8,
+                        count: NUM_SYNC_PACKETS - *roundtrips_remaining,
                     })?;
-                    self.send(state.create_sync_request())?;
+                    self.send_sync_request(&state)?;
                 }
                 Ok(())
             }
@@ -688,30 +976,46 @@ impl<T: Config> Peer<T> {
             peer_connect_status,
             start_frame,
             ack_frame,
+            frame_delay,
             bits,
         } = msg;
+        self.stats.write().remote_frame_delay = Some(frame_delay);
 
         // Update the peer connection status if this peer is still considered to be part
         // of the network.
-        for (i, remote_status) in peer_connect_status.iter().enumerate() {
-            if i < self.peer_connect_status.len() {
-                debug_assert!(remote_status.last_frame >= self.peer_connect_status[i].last_frame);
-                self.peer_connect_status[i].disconnected |= remote_status.disconnected;
-                self.peer_connect_status[i].last_frame = std::cmp::max(
-                    self.peer_connect_status[i].last_frame,
-                    remote_status.last_frame,
-                );
-            } else {
-                self.peer_connect_status.push(remote_status.clone());
+        {
+            let mut statuses = self.peer_connect_status.write();
+            for (i, remote_status) in peer_connect_status.iter().enumerate() {
+                if i < statuses.len() {
+                    // A peer may roll its last frame for a player back when it disconnects
+                    // them, so it only ever moves forward while they are connected.
+                    debug_assert!(
+                        remote_status.disconnected
+                            || remote_status.last_frame >= statuses[i].last_frame
+                    );
+                    statuses[i].disconnected |= remote_status.disconnected;
+                    statuses[i].last_frame =
+                        std::cmp::max(statuses[i].last_frame, remote_status.last_frame);
+                } else {
+                    statuses.push(remote_status.clone());
+                }
             }
         }
 
         // Decompress the input.
-        match self.input_decoder.decode(start_frame, bits) {
-            Ok(inputs) => {
-                if !inputs.is_empty() {
+        // The event takes ownership of the inputs, so they are decoded straight into
+        // its Vec, which is only allocated if there are new inputs.
+        let mut inputs = Vec::new();
+        match self.input_decoder.decode(start_frame, bits, &mut inputs) {
+            Ok(count) => {
+                if count > 0 {
                     self.push_event(Event::<T::Input>::Inputs(inputs))?;
-                    self.stats.write().last_input_packet_recv_time = UnixMillis::now();
+                    let mut stats = self.stats.write();
+                    stats.last_input_packet_recv_time = UnixMillis::now();
+                    stats
+                        .clock_drift
+                        .sample(Instant::now(), self.input_decoder.last_decoded_frame());
+                    drop(stats);
                     self.send(InputAck {
                         ack_frame: self.input_decoder.last_decoded_frame(),
                     })?;
@@ -722,6 +1026,9 @@ impl<T: Config> Peer<T> {
                     "Error while decoding recieved inputs. discarding: {:?}",
                     err
                 );
+                let mut stats = self.stats.write();
+                stats.decode_errors += 1;
+                stats.last_decode_error = Some(err);
                 return Err(PeerError::InvalidMessage);
             }
         }
@@ -742,25 +1049,85 @@ impl<T: Config> Peer<T> {
         // Estimate which frame the other guy is one by looking at the
         // last frame they gave us plus some delta for the one-way packet
         // trip time.
-        let remote_frame = self.input_decoder.last_decoded_frame()
-            + ((stats.round_trip_time.as_secs() / 2) * TARGET_TPS) as i32;
+        let one_way_frames = stats.round_trip_time.as_millis() as u64 / 2 * T::FPS as u64 / 1000;
+        let one_way_frames = Frame::try_from(one_way_frames).unwrap_or(Frame::MAX);
+        let remote_frame = self
+            .input_decoder
+            .last_decoded_frame()
+            .saturating_add(one_way_frames);
+        stats.frame_advantage_rtt = stats.round_trip_time;
 
         // Our frame advantage is how many frames *behind* the other guy
         // we are.  Counter-intuative, I know.  It's an advantage because
         // it means they'll have to predict more often and our moves will
         // pop more frequently.
         stats.local_frame_advantage = remote_frame - local_frame;
+        drop(stats);
+
+        // The remote player can never be further ahead than the input queues can hold.
+        // Rejecting anything past that keeps arbitrary frames from a misbehaving peer
+        // out of the queues.
+        self.input_decoder
+            .set_max_frame(local_frame.saturating_add(crate::MAX_ROLLBACK_FRAMES as Frame));
+    }
+
+    pub fn last_decode_error(&self) -> Option<DecodeError> {
+        self.stats.read().last_decode_error.clone()
     }
 
     pub fn recommend_frame_delay(&self) -> Frame {
         // XXX: require idle input should be a configuration parameter
         self.timesync.recommend_frame_wait_duration(false)
     }
+
+    pub fn round_trip_time(&self) -> Duration {
+        self.stats.read().round_trip_time
+    }
+
+    /// Sets the frame delay of the local player reported to the remote peer.
+    pub fn set_local_frame_delay(&self, delay: Frame) {
+        self.stats.write().local_frame_delay = delay;
+    }
+
+    /// Gets the frame delay the remote peer applies to its own inputs. Returns `None`
+    /// until inputs have been received from the peer.
+    pub fn remote_frame_delay(&self) -> Option<Frame> {
+        self.stats.read().remote_frame_delay
+    }
+
+    /// Gets how long the peer waits without receiving a packet before reporting the
+    /// connection as interrupted. Never exceeds the disconnect timeout.
+    pub fn disconnect_notify_threshold(&self) -> Duration {
+        let fixed = self.config.disconnect_notify_start;
+        let threshold = self
+            .config
+            .adaptive_notify_multiplier
+            .and_then(|multiplier| self.stats.read().recv_jitter.threshold(multiplier))
+            .map_or(fixed, |adaptive| std::cmp::max(fixed, adaptive));
+        std::cmp::min(threshold, self.config.disconnect_timeout)
+    }
+
+    pub fn frame_advantage_history(&self) -> Vec<(Frame, Frame)> {
+        self.timesync.history()
+    }
+
+    pub fn clock_drift(&self) -> f32 {
+        self.stats.read().clock_drift.frames_per_thousand(T::FPS)
+    }
+
+    pub fn frame_advantage_rtt(&self) -> Duration {
+        self.stats.read().frame_advantage_rtt
+    }
 }
 
+/// A player's view of whether a queue is still connected, and the latest frame it
+/// has received inputs for. See [P2PSession::connect_status_matrix].
+///
+/// [P2PSession::connect_status_matrix]: crate::P2PSession::connect_status_matrix
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ConnectionStatus {
     pub disconnected: bool,
+    /// The latest frame inputs were received for, or -1 if none have been received.
     pub last_frame: Frame,
 }
 
@@ -772,3 +1139,135 @@ impl Default for ConnectionStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct SlowConfig;
+
+    impl Config for SlowConfig {
+        type Input = u32;
+        type State = u64;
+        const FPS: u32 = 30;
+    }
+
+    fn create_peer<T: Config>() -> (Peer<T>, async_channel::Receiver<Event<T::Input>>) {
+        let (peer, _remote) = TransportPeer::create_unbounded_pair();
+        let connect_status: Vec<RwLock<ConnectionStatus>> =
+            (0..2).map(|_| Default::default()).collect();
+        let config = PeerConfig {
+            peer,
+            disconnect_timeout: Duration::from_secs(5),
+            disconnect_notify_start: Duration::from_millis(750),
+            adaptive_notify_multiplier: None,
+            frame_delay: 0,
+            task_pool: TaskPool::new(),
+        };
+        Peer::new(1, config, connect_status.into())
+    }
+
+    #[test]
+    pub fn test_input_packet_overhead_covers_worst_case_input() {
+        let bits = vec![u8::MAX; MAX_TRANSMISSION_UNIT as usize - INPUT_PACKET_OVERHEAD];
+        let message = Message {
+            magic: u16::MAX,
+            sequence_number: Wrapping(u16::MAX),
+            data: MessageData::Input(Input {
+                peer_connect_status: vec![
+                    ConnectionStatus {
+                        disconnected: true,
+                        last_frame: Frame::MIN,
+                    };
+                    crate::MAX_PLAYERS
+                ],
+                start_frame: Frame::MIN,
+                ack_frame: Frame::MIN,
+                frame_delay: Frame::MIN,
+                bits: bits.clone(),
+            }),
+        };
+        let bytes = bincode::options()
+            .with_limit(MAX_TRANSMISSION_UNIT)
+            .serialize(&message)
+            .unwrap();
+        assert!(bytes.len() - bits.len() <= INPUT_PACKET_OVERHEAD);
+    }
+
+    #[test]
+    pub fn test_frame_advantage_uses_config_frame_rate() {
+        let (peer, _events) = create_peer::<SlowConfig>();
+        peer.stats.write().round_trip_time = Duration::from_millis(200);
+        peer.set_local_frame_number(10);
+        // A 100ms one way trip is 3 frames at 30 FPS. No inputs have been decoded yet.
+        assert_eq!(peer.stats.read().local_frame_advantage, -1 + 3 - 10);
+        assert_eq!(peer.frame_advantage_rtt(), Duration::from_millis(200));
+    }
+
+    #[test]
+    pub fn test_disconnect_reason_round_trips_over_the_wire() {
+        let reasons = [
+            DisconnectReason::Unspecified,
+            DisconnectReason::Kicked,
+            DisconnectReason::Quit,
+            DisconnectReason::Timeout,
+            DisconnectReason::Custom(0),
+            DisconnectReason::Custom(u8::MAX),
+        ];
+        let options = bincode::options().with_limit(MAX_TRANSMISSION_UNIT);
+        for reason in reasons {
+            let message = Message {
+                magic: 1,
+                sequence_number: Wrapping(2),
+                data: MessageData::Disconnect(Disconnect { reason }),
+            };
+            let bytes = options.serialize(&message).unwrap();
+            let decoded: Message = options.deserialize(&bytes).unwrap();
+            match decoded.data {
+                MessageData::Disconnect(disconnect) => assert_eq!(disconnect.reason, reason),
+                data => panic!("Unexpected message: {:?}", data),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_handshake_rejects_incompatible_peers() {
+        let cases = [
+            (
+                PROTOCOL_VERSION.wrapping_add(1),
+                2,
+                SynchronizationFailure::ProtocolVersion {
+                    local: PROTOCOL_VERSION,
+                    remote: PROTOCOL_VERSION.wrapping_add(1),
+                },
+            ),
+            (
+                PROTOCOL_VERSION,
+                3,
+                SynchronizationFailure::PlayerCount {
+                    local: 2,
+                    remote: 3,
+                },
+            ),
+        ];
+        for (protocol_version, player_count, expected) in cases {
+            let (peer, events) = create_peer::<SlowConfig>();
+            let reply = SyncReply {
+                random: 0,
+                protocol_version,
+                player_count,
+            };
+            assert!(matches!(
+                peer.on_sync_reply(0, reply),
+                Err(PeerError::HandshakeFailed)
+            ));
+            let failure =
+                std::iter::from_fn(|| events.try_recv().ok()).find_map(|event| match event {
+                    Event::SynchronizationFailed(failure) => Some(failure),
+                    _ => None,
+                });
+            assert_eq!(failure, Some(expected));
+            assert!(!peer.is_running());
+        }
+    }
+}
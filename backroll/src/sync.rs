@@ -6,12 +6,11 @@ use crate::{
     BackrollError, BackrollResult, Config, Frame, NULL_FRAME,
 };
 use parking_lot::{Mutex, RwLock};
-use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::Arc;
 use tracing::{debug, warn};
 
-const MAX_PREDICTION_FRAMES: usize = 8;
+pub(crate) const MAX_PREDICTION_FRAMES: usize = 8;
 
 pub struct PlayerConfig {
     pub player_count: usize,
@@ -43,11 +42,15 @@ where
     T: Config;
 
 impl<T: Config> SavedCell<T> {
-    pub fn reset(&self, frame: Frame) {
-        *self.0.lock() = SavedFrame::<T> {
+    pub fn new(frame: Frame) -> Self {
+        Self(Arc::new(Mutex::new(SavedFrame::<T> {
             frame,
             ..Default::default()
-        };
+        })))
+    }
+
+    pub fn checksum(&self) -> Option<u64> {
+        self.0.lock().checksum
     }
 
     pub fn save(&self, new_frame: SavedFrame<T>) {
@@ -70,6 +73,11 @@ impl<T: Config> SavedCell<T> {
         }
     }
 
+    /// Gets a copy of the saved state, if one has been saved.
+    pub fn state(&self) -> Option<T::State> {
+        self.0.lock().data.as_deref().cloned()
+    }
+
     pub fn is_valid(&self) -> bool {
         let frame = self.0.lock();
         frame.data.is_some() && !crate::is_null(frame.frame)
@@ -97,9 +105,12 @@ where
 }
 
 impl<T: Config> SavedState<T> {
+    /// Replaces the oldest cell with a new one for a frame. Commands that were already
+    /// issued keep the cell they were created with, so a pending load is not cleared
+    /// by a later rollback within the same batch of commands.
     pub fn push(&mut self, frame: Frame) -> SavedCell<T> {
-        let saved_frame = self.frames[self.head].clone();
-        saved_frame.reset(frame);
+        let saved_frame = SavedCell::new(frame);
+        self.frames[self.head] = saved_frame.clone();
         self.head = (self.head + 1) % self.frames.len();
         debug_assert!(self.head < self.frames.len());
         saved_frame
@@ -109,9 +120,12 @@ impl<T: Config> SavedState<T> {
     fn find_index(&self, frame: Frame) -> Option<usize> {
         self.frames
             .iter()
-            .enumerate()
-            .find(|(i, saved)| saved.0.lock().frame == frame)
-            .map(|(i, _)| i)
+            .position(|saved| saved.0.lock().frame == frame)
+    }
+
+    /// Gets the saved state for a frame without moving the head of the queue.
+    pub fn get(&self, frame: Frame) -> Option<SavedCell<T>> {
+        self.find_index(frame).map(|idx| self.frames[idx].clone())
     }
 
     pub fn reset_to(&mut self, frame: Frame) -> SavedCell<T> {
@@ -120,14 +134,6 @@ impl<T: Config> SavedState<T> {
             .unwrap_or_else(|| panic!("Could not find saved frame index for frame: {}", frame));
         self.frames[self.head].clone()
     }
-
-    /// Peeks at the latest saved frame in the queue.
-    pub fn latest(&self) -> Option<SavedCell<T>> {
-        self.frames
-            .iter()
-            .max_by_key(|saved| saved.0.lock().frame)
-            .cloned()
-    }
 }
 
 impl<T: Config> Default for SavedState<T> {
@@ -149,8 +155,11 @@ where
     rolling_back: bool,
 
     last_confirmed_frame: Frame,
+    last_reported_frame: Frame,
     frame_count: Frame,
+    last_rollback_cause: Vec<usize>,
     local_connect_status: Arc<[RwLock<ConnectionStatus>]>,
+    confirmed_listeners: Vec<async_channel::Sender<(Frame, GameInput<T::Input>)>>,
 }
 
 impl<T: Config> Sync<T> {
@@ -167,7 +176,10 @@ impl<T: Config> Sync<T> {
 
             rolling_back: false,
             last_confirmed_frame: super::NULL_FRAME,
+            last_reported_frame: super::NULL_FRAME,
             frame_count: 0,
+            last_rollback_cause: Vec::new(),
+            confirmed_listeners: Vec::new(),
         }
     }
 
@@ -179,17 +191,97 @@ impl<T: Config> Sync<T> {
         self.frame_count
     }
 
+    #[cfg(test)]
+    pub fn set_frame_count(&mut self, frame: Frame) {
+        self.frame_count = frame;
+    }
+
+    pub fn last_confirmed_frame(&self) -> Frame {
+        self.last_confirmed_frame
+    }
+
+    /// Gets the queues whose inputs differed from their prediction in the most recent
+    /// rollback. Empty if no rollback has happened yet, or if the most recent one was
+    /// not caused by a misprediction, i.e. by a player disconnecting.
+    pub fn last_rollback_cause(&self) -> &[usize] {
+        &self.last_rollback_cause
+    }
+
     pub fn in_rollback(&self) -> bool {
         self.rolling_back
     }
 
+    /// Gets a copy of the saved state for a given frame, if it is still available
+    /// and has been saved by the client.
+    pub fn saved_state(&self, frame: Frame) -> Option<T::State> {
+        self.saved_state.get(frame)?.state()
+    }
+
     pub fn set_last_confirmed_frame(&mut self, frame: Frame) {
         self.last_confirmed_frame = frame;
-        if frame > 0 {
+        self.report_confirmed_frames(frame);
+
+        // Never discard inputs that have not been reported yet.
+        let discard_frame = std::cmp::min(frame, self.last_reported_frame + 1) - 1;
+        if discard_frame >= 0 {
             for queue in self.input_queues.iter_mut() {
-                queue.discard_confirmed_frames(frame - 1);
+                queue.discard_confirmed_frames(discard_frame);
+            }
+        }
+    }
+
+    /// Calls [Config::on_frame_confirmed] for every frame up to and including the
+    /// provided frame that has not been reported yet. Stops early at the first frame
+    /// that is still missing an input for any connected player, which will be
+    /// reported on a later call instead.
+    fn report_confirmed_frames(&mut self, frame: Frame) {
+        while self.last_reported_frame < frame {
+            let next = self.last_reported_frame + 1;
+            match self.confirmed_inputs(next) {
+                Some(inputs) => {
+                    T::on_frame_confirmed(next, &inputs);
+                    self.confirmed_listeners
+                        .retain(|listener| listener.try_send((next, inputs.clone())).is_ok());
+                }
+                None => break,
+            }
+            self.last_reported_frame = next;
+        }
+    }
+
+    /// Creates a channel that receives the inputs for every frame reported after this
+    /// call, alongside [Config::on_frame_confirmed]. Dropping the receiver unregisters it.
+    /// If more than `capacity` frames are left unreceived, the channel is closed instead.
+    pub fn subscribe_confirmed(
+        &mut self,
+        capacity: usize,
+    ) -> async_channel::Receiver<(Frame, GameInput<T::Input>)> {
+        let (tx, rx) = async_channel::bounded(capacity);
+        self.confirmed_listeners.push(tx);
+        rx
+    }
+
+    /// Gets the received inputs for every player for a given frame. Returns `None` if
+    /// any connected player's input for the frame is missing or already discarded.
+    pub fn confirmed_inputs(&self, frame: Frame) -> Option<GameInput<T::Input>> {
+        let mut output = GameInput::<T::Input> {
+            frame,
+            ..Default::default()
+        };
+        for idx in 0..self.config.player_count {
+            let status = self.local_connect_status[idx].read();
+            if status.disconnected && status.last_frame < frame {
+                output.disconnected |= 1 << idx;
+                output.inputs[idx] = T::disconnected_input();
+            } else {
+                output.inputs[idx] = self.input_queues[idx].confirmed_input(frame)?.input;
             }
         }
+        Some(output)
+    }
+
+    pub fn input_queue(&self, queue: usize) -> &InputQueue<T> {
+        &self.input_queues[queue]
     }
 
     pub fn set_frame_delay(&mut self, queue: usize, delay: Frame) {
@@ -201,13 +293,81 @@ impl<T: Config> Sync<T> {
             self.save_current_frame(commands);
         }
         let inputs = self.synchronize_inputs();
-        commands.push(Command::AdvanceFrame(inputs));
+        if self.rolling_back {
+            T::on_resimulate(self.frame_count, &inputs);
+        }
+        if T::ASSERT_DETERMINISM && self.rolling_back {
+            self.advance_and_verify(commands, inputs);
+        } else {
+            commands.push(Command::AdvanceFrame(inputs));
+            self.frame_count += 1;
+            self.save_current_frame(commands);
+        }
+    }
+
+    /// Advances the frame, then loads the starting state and advances with the same
+    /// inputs a second time. The second save verifies that its checksum matches the
+    /// first instead of overwriting it.
+    fn advance_and_verify(&mut self, commands: &mut Commands<T>, inputs: GameInput<T::Input>) {
+        let start = self.saved_state.get(self.frame_count);
+        commands.push(Command::AdvanceFrame(inputs.clone()));
         self.frame_count += 1;
-        self.save_current_frame(commands);
+        let cell = self.save_current_frame(commands);
+
+        if let Some(start) = start {
+            debug!(
+                "Re-running frame {} to verify determinism.",
+                self.frame_count - 1
+            );
+            commands.push(Command::Load(LoadState::<T> { cell: start }));
+            commands.push(Command::AdvanceFrame(inputs));
+            commands.push(Command::Save(SaveState::<T> {
+                cell,
+                frame: self.frame_count,
+                verify: true,
+            }));
+        }
+    }
+
+    /// Gets how many frames the simulation is ahead of the latest input received for
+    /// a given queue.
+    pub fn prediction_distance(&self, queue: usize) -> Frame {
+        self.frame_count - self.input_queues[queue].last_confirmed_frame()
+    }
+
+    /// Gets the prediction distance of the slowest connected queue.
+    ///
+    /// Only the slowest queue determines how far back a rollback may need to go, so
+    /// this alone is used to determine if the prediction barrier has been reached.
+    /// Queues that are well ahead of it do not contribute to stalling.
+    ///
+    /// With more than two players, another peer may report a disconnect at an
+    /// older frame than any input received locally, so the distance from the
+    /// last confirmed frame is also taken into account.
+    fn max_prediction_distance(&self) -> Frame {
+        let distance = (0..self.config.player_count)
+            .filter(|idx| !self.local_connect_status[*idx].read().disconnected)
+            .map(|idx| self.prediction_distance(idx))
+            .max()
+            .unwrap_or(0);
+        if self.config.player_count > 2 && !super::is_null(self.last_confirmed_frame) {
+            distance.max(self.frame_count - self.last_confirmed_frame)
+        } else {
+            distance
+        }
+    }
+
+    /// Gets how many more frames can be advanced before local inputs are rejected
+    /// with [BackrollError::ReachedPredictionBarrier], assuming no new remote inputs
+    /// are received.
+    pub fn frames_until_prediction_barrier(&self) -> Frame {
+        let max = MAX_PREDICTION_FRAMES as Frame;
+        let remaining = std::cmp::max(max - self.frame_count, max - self.max_prediction_distance());
+        std::cmp::max(0, remaining)
     }
 
     pub fn add_local_input(&mut self, queue: usize, input: T::Input) -> BackrollResult<Frame> {
-        let frames_behind = self.frame_count - self.last_confirmed_frame;
+        let frames_behind = self.max_prediction_distance();
         if self.frame_count >= MAX_PREDICTION_FRAMES as i32
             && frames_behind >= MAX_PREDICTION_FRAMES as i32
         {
@@ -232,24 +392,30 @@ impl<T: Config> Sync<T> {
         self.input_queues[queue].add_input(input);
     }
 
-    pub fn get_confirmed_inputs(&mut self, frame: Frame) -> GameInput<T::Input> {
-        let mut output: GameInput<T::Input> = Default::default();
+    pub fn synchronize_inputs(&mut self) -> GameInput<T::Input> {
+        let mut output = GameInput::<T::Input> {
+            frame: self.frame_count,
+            ..Default::default()
+        };
         for idx in 0..self.config.player_count {
-            let input = if self.is_disconnected(idx) {
+            if self.is_disconnected(idx) {
                 output.disconnected |= 1 << idx;
-                Default::default()
+                output.inputs[idx] = T::disconnected_input();
             } else {
-                self.input_queues[idx]
-                    .get_confirmed_input(frame)
+                output.inputs[idx] = self.input_queues[idx]
+                    .get_input(self.frame_count)
                     .unwrap()
-                    .clone()
-            };
-            output.inputs[idx] = input.input;
+                    .input;
+            }
         }
         output
     }
 
-    pub fn synchronize_inputs(&mut self) -> GameInput<T::Input> {
+    /// Gets the inputs [synchronize_inputs] would currently return, without affecting
+    /// the prediction state of any input queue.
+    ///
+    /// [synchronize_inputs]: Self::synchronize_inputs
+    pub fn peek_inputs(&self) -> GameInput<T::Input> {
         let mut output = GameInput::<T::Input> {
             frame: self.frame_count,
             ..Default::default()
@@ -257,9 +423,10 @@ impl<T: Config> Sync<T> {
         for idx in 0..self.config.player_count {
             if self.is_disconnected(idx) {
                 output.disconnected |= 1 << idx;
+                output.inputs[idx] = T::disconnected_input();
             } else {
                 output.inputs[idx] = self.input_queues[idx]
-                    .get_input(self.frame_count)
+                    .peek_input(self.frame_count)
                     .unwrap()
                     .input;
             }
@@ -273,10 +440,6 @@ impl<T: Config> Sync<T> {
         }
     }
 
-    pub fn get_last_saved_frame(&self) -> SavedCell<T> {
-        self.saved_state.latest().unwrap()
-    }
-
     pub fn load_frame(&mut self, commands: &mut Commands<T>, frame: Frame) {
         // find the frame in question
         if frame == self.frame_count {
@@ -292,12 +455,14 @@ impl<T: Config> Sync<T> {
         self.saved_state.head %= self.saved_state.frames.len();
     }
 
-    pub fn save_current_frame(&mut self, commands: &mut Commands<T>) {
+    pub fn save_current_frame(&mut self, commands: &mut Commands<T>) -> SavedCell<T> {
         let cell = self.saved_state.push(self.frame_count);
         commands.push(Command::Save(SaveState::<T> {
-            cell,
+            cell: cell.clone(),
             frame: self.frame_count,
+            verify: false,
         }));
+        cell
     }
 
     pub fn adjust_simulation(&mut self, commands: &mut Commands<T>, seek_to: Frame) {
@@ -307,6 +472,15 @@ impl<T: Config> Sync<T> {
         debug!("Catching up");
         self.rolling_back = true;
 
+        // Record which queues mispredicted before the predictions are reset below.
+        self.last_rollback_cause = self
+            .input_queues
+            .iter()
+            .enumerate()
+            .filter(|(_, queue)| !super::is_null(queue.first_incorrect_frame()))
+            .map(|(i, _)| i)
+            .collect();
+
         //  Flush our input queue and load the last frame.
         self.load_frame(commands, seek_to);
         debug_assert!(self.frame_count == seek_to);
@@ -347,3 +521,521 @@ impl<T: Config> Sync<T> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type Input = u32;
+        type State = u64;
+    }
+
+    fn create_sync(player_count: usize) -> Sync<TestConfig> {
+        let connect_status: Vec<RwLock<ConnectionStatus>> =
+            (0..player_count).map(|_| Default::default()).collect();
+        let config = PlayerConfig {
+            player_count,
+            frame_delay: 0,
+        };
+        Sync::new(config, connect_status.into())
+    }
+
+    /// Executes the commands the same way a client would, using the sum of all
+    /// inputs seen so far as the game state. Returns true if a state was loaded.
+    fn run_commands<T>(state: &mut u64, commands: Commands<T>) -> bool
+    where
+        T: Config<Input = u32, State = u64>,
+    {
+        let mut loaded = false;
+        for command in commands {
+            match command {
+                Command::Save(save) => save.save(*state),
+                Command::Load(load) => {
+                    *state = load.load();
+                    loaded = true;
+                }
+                Command::AdvanceFrame(inputs) => {
+                    *state += inputs.inputs.iter().map(|i| *i as u64).sum::<u64>();
+                }
+                Command::Event(_) => {}
+            }
+        }
+        loaded
+    }
+
+    #[test]
+    pub fn test_prediction_barrier_only_considers_slowest_queue() {
+        let mut sync = create_sync(3);
+        let mut state = 0;
+        // Queue 0 is local, queue 1 is a fast remote peer, and queue 2 is a slow
+        // remote peer that stops sending after frame 2.
+        for frame in 0..(MAX_PREDICTION_FRAMES as Frame + 3) {
+            sync.add_remote_input(1, FrameInput { frame, input: 1 });
+            if frame <= 2 {
+                sync.add_remote_input(2, FrameInput { frame, input: 1 });
+            }
+            assert_eq!(sync.prediction_distance(1), 0);
+            let result = sync.add_local_input(0, 1);
+            if sync.prediction_distance(2) >= MAX_PREDICTION_FRAMES as Frame {
+                assert!(matches!(
+                    result,
+                    Err(BackrollError::ReachedPredictionBarrier)
+                ));
+                return;
+            }
+            assert_eq!(result.unwrap(), frame);
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            run_commands(&mut state, commands);
+        }
+        panic!("The prediction barrier was never reached.");
+    }
+
+    #[test]
+    pub fn test_prediction_barrier_considers_confirmed_frame_with_more_than_two_players() {
+        let mut sync = create_sync(3);
+        let mut state = 0;
+        // Every queue is up to date, but no frame past the first has been confirmed
+        // by all peers.
+        for frame in 0.. {
+            sync.add_remote_input(1, FrameInput { frame, input: 1 });
+            sync.add_remote_input(2, FrameInput { frame, input: 1 });
+            if sync.add_local_input(0, 1).is_err() {
+                break;
+            }
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            run_commands(&mut state, commands);
+            if frame == 0 {
+                sync.set_last_confirmed_frame(0);
+            }
+        }
+        assert_eq!(sync.frame_count(), MAX_PREDICTION_FRAMES as Frame);
+        assert_eq!(sync.frames_until_prediction_barrier(), 0);
+    }
+
+    #[test]
+    pub fn test_multiple_rollbacks_in_one_batch_load_saved_states() {
+        let mut sync = create_sync(2);
+        let mut state = 0;
+        for frame in 0..MAX_PREDICTION_FRAMES as Frame {
+            sync.add_remote_input(1, FrameInput { frame, input: 2 });
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            run_commands(&mut state, commands);
+        }
+        let expected = state;
+
+        // The second rollback re-saves the frame the first one loads before the
+        // client has run any of the commands.
+        let mut commands = Commands::default();
+        sync.adjust_simulation(&mut commands, MAX_PREDICTION_FRAMES as Frame - 1);
+        sync.adjust_simulation(&mut commands, 1);
+        run_commands(&mut state, commands);
+        assert_eq!(state, expected);
+    }
+
+    #[test]
+    pub fn test_peeked_inputs_match_synchronized_inputs() {
+        let mut sync = create_sync(2);
+        // Queue 1 is a remote peer that stops sending after frame 2, so later frames
+        // are predicted.
+        for frame in 0..6 {
+            if frame <= 2 {
+                sync.add_remote_input(
+                    1,
+                    FrameInput {
+                        frame,
+                        input: 5 + frame as u32,
+                    },
+                );
+            }
+            sync.add_local_input(0, 1).unwrap();
+            let peeked = sync.peek_inputs();
+            assert_eq!(sync.peek_inputs(), peeked);
+
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            let mut advanced = Vec::new();
+            for command in commands {
+                match command {
+                    Command::Save(save) => save.save(0),
+                    Command::AdvanceFrame(inputs) => advanced.push(inputs),
+                    _ => {}
+                }
+            }
+            assert_eq!(advanced, vec![peeked]);
+        }
+        // Receiving the predicted input must not trigger a rollback.
+        sync.add_remote_input(1, FrameInput { frame: 3, input: 7 });
+        assert!(sync.check_simulation_consistency().is_none());
+    }
+
+    #[test]
+    pub fn test_frames_until_prediction_barrier_counts_down_to_rejection() {
+        let mut sync = create_sync(2);
+        let mut state = 0;
+        // Queue 1 is a remote peer that never sends any inputs.
+        loop {
+            let remaining = sync.frames_until_prediction_barrier();
+            let result = sync.add_local_input(0, 1);
+            if remaining == 0 {
+                assert!(matches!(
+                    result,
+                    Err(BackrollError::ReachedPredictionBarrier)
+                ));
+                break;
+            }
+            result.unwrap();
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            run_commands(&mut state, commands);
+            assert_eq!(sync.frames_until_prediction_barrier(), remaining - 1);
+        }
+        assert_eq!(sync.frame_count(), MAX_PREDICTION_FRAMES as Frame);
+    }
+
+    #[test]
+    pub fn test_prediction_barrier_not_reached_with_current_remote_inputs() {
+        let mut sync = create_sync(3);
+        let mut state = 0;
+        // The network confirmed frame is never updated, but all remote queues are
+        // current, so there is nothing to roll back to and no reason to stall.
+        for frame in 0..(MAX_PREDICTION_FRAMES as Frame * 4) {
+            sync.add_remote_input(1, FrameInput { frame, input: 1 });
+            sync.add_remote_input(2, FrameInput { frame, input: 2 });
+            assert_eq!(sync.add_local_input(0, 3).unwrap(), frame);
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            run_commands(&mut state, commands);
+        }
+        assert_eq!(state, 6 * MAX_PREDICTION_FRAMES as u64 * 4);
+    }
+
+    #[test]
+    pub fn test_prediction_holds_last_remote_input() {
+        let mut sync = create_sync(2);
+        let mut state = 0;
+        let advance = |sync: &mut Sync<TestConfig>, state: &mut u64| {
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.check_simulation(&mut commands);
+            sync.increment_frame(&mut commands);
+            run_commands(state, commands)
+        };
+
+        sync.add_remote_input(1, FrameInput { frame: 0, input: 5 });
+        assert!(!advance(&mut sync, &mut state));
+        // Frames 1 and 2 are predicted by holding the last received input.
+        assert!(!advance(&mut sync, &mut state));
+        assert!(!advance(&mut sync, &mut state));
+        assert_eq!(state, 18);
+
+        // A late input matching the held prediction does not roll back.
+        sync.add_remote_input(1, FrameInput { frame: 1, input: 5 });
+        assert!(!advance(&mut sync, &mut state));
+        assert_eq!(state, 24);
+
+        // A late input that differs does.
+        sync.add_remote_input(1, FrameInput { frame: 2, input: 7 });
+        assert!(advance(&mut sync, &mut state));
+        assert_eq!(state, 36);
+    }
+
+    #[test]
+    pub fn test_last_rollback_cause_records_mispredicted_queues() {
+        let mut sync = create_sync(3);
+        let mut state = 0;
+        let advance = |sync: &mut Sync<TestConfig>, state: &mut u64| {
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.check_simulation(&mut commands);
+            sync.increment_frame(&mut commands);
+            run_commands(state, commands)
+        };
+
+        sync.add_remote_input(1, FrameInput { frame: 0, input: 5 });
+        sync.add_remote_input(2, FrameInput { frame: 0, input: 5 });
+        assert!(!advance(&mut sync, &mut state));
+        assert!(!advance(&mut sync, &mut state));
+        assert!(sync.last_rollback_cause().is_empty());
+
+        // Only the second remote player's late input differs from the prediction.
+        sync.add_remote_input(1, FrameInput { frame: 1, input: 5 });
+        sync.add_remote_input(2, FrameInput { frame: 1, input: 7 });
+        assert!(advance(&mut sync, &mut state));
+        assert_eq!(sync.last_rollback_cause(), &[2]);
+    }
+
+    struct DeterminismConfig;
+
+    impl Config for DeterminismConfig {
+        type Input = u32;
+        type State = u64;
+        const ASSERT_DETERMINISM: bool = true;
+    }
+
+    /// Advances two frames on a predicted input, then rolls back with a different one.
+    /// The commands are executed by `run`.
+    fn rollback_with_determinism_check(mut run: impl FnMut(Commands<DeterminismConfig>)) {
+        let connect_status: Vec<RwLock<ConnectionStatus>> =
+            (0..2).map(|_| Default::default()).collect();
+        let config = PlayerConfig {
+            player_count: 2,
+            frame_delay: 0,
+        };
+        let mut sync = Sync::<DeterminismConfig>::new(config, connect_status.into());
+        sync.add_remote_input(1, FrameInput { frame: 0, input: 5 });
+        for _ in 0..3 {
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.check_simulation(&mut commands);
+            sync.increment_frame(&mut commands);
+            run(commands);
+        }
+        sync.add_remote_input(1, FrameInput { frame: 1, input: 7 });
+        let mut commands = Commands::default();
+        sync.check_simulation(&mut commands);
+        run(commands);
+    }
+
+    #[test]
+    pub fn test_determinism_check_accepts_deterministic_simulation() {
+        let mut state = 0;
+        let mut advances = 0;
+        rollback_with_determinism_check(|commands| {
+            for command in commands {
+                match command {
+                    Command::Save(save) => save.save(state),
+                    Command::Load(load) => state = load.load(),
+                    Command::AdvanceFrame(inputs) => {
+                        advances += 1;
+                        state += inputs.inputs.iter().map(|i| *i as u64).sum::<u64>();
+                    }
+                    Command::Event(_) => {}
+                }
+            }
+        });
+        // Three frames, then the two re-simulated frames are each advanced twice.
+        assert_eq!(advances, 7);
+        assert_eq!(state, 6 + 8 + 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "Non-deterministic simulation")]
+    pub fn test_determinism_check_catches_diverging_simulation() {
+        let mut state = 0;
+        let mut advances = 0;
+        rollback_with_determinism_check(|commands| {
+            for command in commands {
+                match command {
+                    Command::Save(save) => save.save(state),
+                    Command::Load(load) => state = load.load(),
+                    Command::AdvanceFrame(inputs) => {
+                        // Depends on how many times the frame was advanced, not only
+                        // on the state and the inputs.
+                        advances += 1;
+                        state += advances;
+                        state += inputs.inputs.iter().map(|i| *i as u64).sum::<u64>();
+                    }
+                    Command::Event(_) => {}
+                }
+            }
+        });
+    }
+
+    #[test]
+    pub fn test_disconnected_player_inputs_are_zeroed() {
+        let mut sync = create_sync(2);
+        let mut state = 0;
+        sync.add_remote_input(1, FrameInput { frame: 0, input: 5 });
+        sync.add_remote_input(1, FrameInput { frame: 1, input: 5 });
+        {
+            let mut status = sync.local_connect_status[1].write();
+            status.disconnected = true;
+            status.last_frame = 1;
+        }
+        for _ in 0..4 {
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.check_simulation(&mut commands);
+            sync.increment_frame(&mut commands);
+            assert!(!run_commands(&mut state, commands));
+        }
+        // Frames 0 and 1 use the received inputs, later frames a zeroed input.
+        assert_eq!(state, 14);
+    }
+
+    struct NeutralInputConfig;
+
+    impl Config for NeutralInputConfig {
+        type Input = u32;
+        type State = u64;
+
+        fn disconnected_input() -> u32 {
+            100
+        }
+    }
+
+    #[test]
+    pub fn test_peers_agree_on_neutral_input_after_disconnect() {
+        let create = || {
+            let connect_status: Vec<RwLock<ConnectionStatus>> =
+                (0..2).map(|_| Default::default()).collect();
+            let config = PlayerConfig {
+                player_count: 2,
+                frame_delay: 0,
+            };
+            Sync::<NeutralInputConfig>::new(config, connect_status.into())
+        };
+        // Both peers simulate 6 frames, but the first received two more inputs from
+        // player 1 before it disconnected after frame 1.
+        let mut peers = [(create(), 0), (create(), 0)];
+        for (received, (sync, state)) in [4, 2].iter().zip(peers.iter_mut()) {
+            for frame in 0..*received {
+                sync.add_remote_input(1, FrameInput { frame, input: 5 });
+            }
+            for _ in 0..6 {
+                sync.add_local_input(0, 1).unwrap();
+                let mut commands = Commands::default();
+                sync.check_simulation(&mut commands);
+                sync.increment_frame(&mut commands);
+                run_commands(state, commands);
+            }
+
+            {
+                let mut status = sync.local_connect_status[1].write();
+                status.disconnected = true;
+                status.last_frame = 1;
+            }
+            let mut commands = Commands::default();
+            sync.adjust_simulation(&mut commands, 1);
+            run_commands(state, commands);
+        }
+
+        // Frames 0 and 1 use the received inputs, later frames the neutral input.
+        assert_eq!(peers[0].1, 6 + 2 * 5 + 4 * 100);
+        assert_eq!(peers[0].1, peers[1].1);
+    }
+
+    #[test]
+    pub fn test_saved_state_tracks_latest_frames() {
+        let mut sync = create_sync(1);
+        let mut state = 0;
+        for frame in 0..(MAX_PREDICTION_FRAMES as Frame * 2) {
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.increment_frame(&mut commands);
+            run_commands(&mut state, commands);
+            assert_eq!(sync.saved_state(frame + 1), Some(frame as u64 + 1));
+            assert_eq!(sync.saved_state(frame), Some(frame as u64));
+        }
+        assert_eq!(sync.saved_state(0), None);
+    }
+
+    struct ReplayConfig;
+
+    thread_local! {
+        static CONFIRMED: std::cell::RefCell<Vec<(Frame, u32, u32)>> = Default::default();
+        static RESIMULATED: std::cell::RefCell<Vec<(Frame, u32, u32)>> = Default::default();
+    }
+
+    impl Config for ReplayConfig {
+        type Input = u32;
+        type State = u64;
+
+        fn on_frame_confirmed(frame: Frame, inputs: &GameInput<u32>) {
+            CONFIRMED.with(|confirmed| {
+                confirmed
+                    .borrow_mut()
+                    .push((frame, inputs.inputs[0], inputs.inputs[1]))
+            });
+        }
+
+        fn on_resimulate(frame: Frame, inputs: &GameInput<u32>) {
+            RESIMULATED.with(|resimulated| {
+                resimulated
+                    .borrow_mut()
+                    .push((frame, inputs.inputs[0], inputs.inputs[1]))
+            });
+        }
+    }
+
+    fn take_confirmed() -> Vec<(Frame, u32, u32)> {
+        CONFIRMED.with(|confirmed| confirmed.borrow_mut().drain(..).collect())
+    }
+
+    #[test]
+    pub fn test_confirmed_frames_reported_once_in_order() {
+        let connect_status: Vec<RwLock<ConnectionStatus>> =
+            (0..2).map(|_| Default::default()).collect();
+        let config = PlayerConfig {
+            player_count: 2,
+            frame_delay: 0,
+        };
+        let mut sync = Sync::<ReplayConfig>::new(config, connect_status.into());
+        for frame in 0..4 {
+            sync.add_local_input(0, frame as u32 + 1).unwrap();
+            sync.increment_frame(&mut Commands::default());
+        }
+        for frame in 0..3 {
+            sync.add_remote_input(
+                1,
+                FrameInput {
+                    frame,
+                    input: frame as u32 + 10,
+                },
+            );
+        }
+
+        sync.set_last_confirmed_frame(1);
+        assert_eq!(take_confirmed(), vec![(0, 1, 10), (1, 2, 11)]);
+        sync.set_last_confirmed_frame(1);
+        assert_eq!(take_confirmed(), vec![]);
+
+        // Frame 3 is missing the remote input and must be held back.
+        sync.set_last_confirmed_frame(3);
+        assert_eq!(take_confirmed(), vec![(2, 3, 12)]);
+
+        sync.add_remote_input(
+            1,
+            FrameInput {
+                frame: 3,
+                input: 13,
+            },
+        );
+        sync.set_last_confirmed_frame(3);
+        assert_eq!(take_confirmed(), vec![(3, 4, 13)]);
+    }
+
+    #[test]
+    pub fn test_resimulated_frames_are_reported_with_corrected_inputs() {
+        let connect_status: Vec<RwLock<ConnectionStatus>> =
+            (0..2).map(|_| Default::default()).collect();
+        let config = PlayerConfig {
+            player_count: 2,
+            frame_delay: 0,
+        };
+        let mut sync = Sync::<ReplayConfig>::new(config, connect_status.into());
+        sync.add_remote_input(1, FrameInput { frame: 0, input: 5 });
+        for _ in 0..3 {
+            sync.add_local_input(0, 1).unwrap();
+            let mut commands = Commands::default();
+            sync.check_simulation(&mut commands);
+            sync.increment_frame(&mut commands);
+        }
+        assert!(RESIMULATED.with(|resimulated| resimulated.borrow().is_empty()));
+
+        // Frames 1 and 2 were predicted as 5 and must be re-simulated.
+        sync.add_remote_input(1, FrameInput { frame: 1, input: 7 });
+        sync.add_remote_input(1, FrameInput { frame: 2, input: 7 });
+        sync.check_simulation(&mut Commands::default());
+        let resimulated: Vec<_> =
+            RESIMULATED.with(|resimulated| resimulated.borrow_mut().drain(..).collect());
+        assert_eq!(resimulated, vec![(1, 1, 7), (2, 1, 7)]);
+    }
+}
@@ -4,30 +4,41 @@ use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::ops::{Add, Sub};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::debug;
 
-const FRAME_WINDOW_SIZE: usize = 40;
 const MIN_UNIQUE_FRAMES: usize = 10;
 const MIN_FRAME_ADVANTAGE: super::Frame = 3;
 const MAX_FRAME_ADVANTAGE: super::Frame = 9;
 
 struct TimeSyncRef<T> {
-    local: [Frame; FRAME_WINDOW_SIZE],
-    remote: [Frame; FRAME_WINDOW_SIZE],
+    local: Vec<Frame>,
+    remote: Vec<Frame>,
     last_inputs: [FrameInput<T>; MIN_UNIQUE_FRAMES],
+    /// The index of the most recent sample in the window.
+    newest: usize,
     iteration: u32,
 }
 
 #[derive(Clone)]
 pub struct TimeSync<T>(Arc<Mutex<TimeSyncRef<T>>>);
 
-impl<T: bytemuck::Pod> Default for TimeSync<T> {
-    fn default() -> Self {
+impl<T: bytemuck::Pod> TimeSync<T> {
+    /// Creates a new TimeSync that averages frame advantages over the last
+    /// `window_size` frames.
+    ///
+    /// # Panics
+    /// Panics if `window_size` is zero.
+    pub fn new(window_size: usize) -> Self {
+        assert!(
+            window_size > 0,
+            "The frame advantage window cannot be empty."
+        );
         Self(Arc::new(Mutex::new(TimeSyncRef {
-            local: [0; FRAME_WINDOW_SIZE],
-            remote: [0; FRAME_WINDOW_SIZE],
+            local: vec![0; window_size],
+            remote: vec![0; window_size],
             last_inputs: Default::default(),
+            newest: window_size - 1,
             iteration: 0,
         })))
     }
@@ -37,10 +48,24 @@ impl<T: PartialEq> TimeSync<T> {
     pub fn advance_frame(&self, input: FrameInput<T>, advantage: Frame, radvantage: Frame) {
         let frame = usize::try_from(input.frame).unwrap();
         let mut sync = self.0.lock();
+        let window_size = sync.local.len();
         // Remember the last frame and frame advantage
         sync.last_inputs[frame % MIN_UNIQUE_FRAMES] = input;
-        sync.local[frame % FRAME_WINDOW_SIZE] = advantage;
-        sync.remote[frame % FRAME_WINDOW_SIZE] = radvantage;
+        sync.local[frame % window_size] = advantage;
+        sync.remote[frame % window_size] = radvantage;
+        sync.newest = frame % window_size;
+    }
+
+    /// Gets the local and remote frame advantage samples currently in the window,
+    /// from oldest to newest. Slots that have not been filled yet are zero, and are
+    /// included in the averages all the same.
+    pub fn history(&self) -> Vec<(Frame, Frame)> {
+        let sync = self.0.lock();
+        let window_size = sync.local.len();
+        (1..=window_size)
+            .map(|offset| (sync.newest + offset) % window_size)
+            .map(|idx| (sync.local[idx], sync.remote[idx]))
+            .collect()
     }
 
     pub fn recommend_frame_wait_duration(&self, require_idle_input: bool) -> super::Frame {
@@ -99,6 +124,87 @@ impl<T: PartialEq> TimeSync<T> {
     }
 }
 
+/// The minimum amount of time between the first and last samples before the clock
+/// drift of a peer is estimated.
+const MIN_DRIFT_SAMPLE_TIME: Duration = Duration::from_secs(1);
+
+/// Estimates how fast a peer's frame counter advances compared to the expected frame
+/// rate, from the frames received from it over the whole session.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClockDrift {
+    first: Option<(Instant, Frame)>,
+    last: Option<(Instant, Frame)>,
+}
+
+impl ClockDrift {
+    pub fn sample(&mut self, time: Instant, frame: Frame) {
+        if self.first.is_none() {
+            self.first = Some((time, frame));
+        }
+        self.last = Some((time, frame));
+    }
+
+    /// Gets how many more frames the peer advanced per 1000 frames expected at `fps`.
+    /// Returns zero until enough samples have been taken.
+    pub fn frames_per_thousand(&self, fps: u32) -> f32 {
+        let ((first_time, first_frame), (last_time, last_frame)) = match (self.first, self.last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0.0,
+        };
+        let elapsed = last_time.saturating_duration_since(first_time);
+        if elapsed < MIN_DRIFT_SAMPLE_TIME {
+            return 0.0;
+        }
+        let expected = elapsed.as_secs_f32() * fps as f32;
+        let actual = (last_frame - first_frame) as f32;
+        (actual - expected) / expected * 1000.0
+    }
+}
+
+/// How much each new sample moves the running packet interval statistics.
+const JITTER_SMOOTHING: f32 = 1.0 / 16.0;
+/// The number of packet intervals that must be sampled before they are used.
+const MIN_JITTER_SAMPLES: u32 = 16;
+
+/// Tracks the running mean and variance of the time between packets received from a
+/// peer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketJitter {
+    last: Option<Instant>,
+    mean: f32,
+    variance: f32,
+    samples: u32,
+}
+
+impl PacketJitter {
+    pub fn sample(&mut self, time: Instant) {
+        if let Some(last) = self.last {
+            let interval = time.saturating_duration_since(last).as_secs_f32();
+            if self.samples == 0 {
+                self.mean = interval;
+            } else {
+                let diff = interval - self.mean;
+                self.mean += JITTER_SMOOTHING * diff;
+                self.variance =
+                    (1.0 - JITTER_SMOOTHING) * (self.variance + JITTER_SMOOTHING * diff * diff);
+            }
+            self.samples += 1;
+        }
+        self.last = Some(time);
+    }
+
+    /// Gets the mean packet interval plus `multiplier` standard deviations. Returns
+    /// `None` until enough intervals have been sampled. Negative or NaN thresholds are
+    /// treated as zero, and thresholds too large for a [Duration] saturate.
+    pub fn threshold(&self, multiplier: f32) -> Option<Duration> {
+        if self.samples < MIN_JITTER_SAMPLES {
+            return None;
+        }
+        let threshold = self.mean + multiplier * self.variance.sqrt();
+        Some(Duration::try_from_secs_f32(threshold.max(0.0)).unwrap_or(Duration::MAX))
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct UnixMillis(u64);
 
@@ -133,3 +239,107 @@ impl Sub<UnixMillis> for UnixMillis {
         Duration::from_millis(self.0 - other.0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Simulates a connection where the remote frame advantage is noisy around a
+    /// constant mean and returns the total variation of the recommendations.
+    fn recommendation_variation(window_size: usize) -> Frame {
+        let time_sync = TimeSync::<u32>::new(window_size);
+        let mut seed: u32 = 0x1234_5678;
+        let mut last: Option<Frame> = None;
+        let mut variation = 0;
+        for frame in 0..1000 {
+            // Simple LCG to keep the noise deterministic.
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let radvantage = ((seed >> 16) % 25) as Frame;
+            time_sync.advance_frame(FrameInput { frame, input: 0 }, 0, radvantage);
+            if frame < window_size as Frame {
+                continue;
+            }
+            let recommendation = time_sync.recommend_frame_wait_duration(false);
+            if let Some(last) = last {
+                variation += (recommendation - last).abs();
+            }
+            last = Some(recommendation);
+        }
+        variation
+    }
+
+    #[test]
+    pub fn test_longer_window_smooths_recommendations() {
+        let short = recommendation_variation(10);
+        let long = recommendation_variation(80);
+        assert!(short > 0);
+        assert!(long < short, "{} is not less than {}", long, short);
+    }
+
+    #[test]
+    pub fn test_history_is_ordered_oldest_first() {
+        let time_sync = TimeSync::<u32>::new(3);
+        assert_eq!(time_sync.history(), vec![(0, 0); 3]);
+        for frame in 0..4 {
+            time_sync.advance_frame(FrameInput { frame, input: 0 }, frame, -frame);
+        }
+        assert_eq!(time_sync.history(), vec![(1, -1), (2, -2), (3, -3)]);
+    }
+
+    #[test]
+    pub fn test_jitter_threshold_grows_with_deviation() {
+        let start = Instant::now();
+        let mut steady = PacketJitter::default();
+        let mut jittery = PacketJitter::default();
+        let mut steady_time = start;
+        let mut jittery_time = start;
+        for i in 0..MIN_JITTER_SAMPLES * 8 {
+            assert_eq!(steady.threshold(4.0).is_some(), i > MIN_JITTER_SAMPLES);
+            steady.sample(steady_time);
+            jittery.sample(jittery_time);
+            steady_time += Duration::from_millis(50);
+            jittery_time += Duration::from_millis(if i % 2 == 0 { 10 } else { 90 });
+        }
+        let steady = steady.threshold(4.0).unwrap();
+        let jittery = jittery.threshold(4.0).unwrap();
+        assert!((steady.as_secs_f32() - 0.05).abs() < 0.001, "{:?}", steady);
+        assert!(jittery > Duration::from_millis(150), "{:?}", jittery);
+    }
+
+    #[test]
+    pub fn test_jitter_threshold_handles_extreme_multipliers() {
+        let start = Instant::now();
+        let mut jitter = PacketJitter::default();
+        for i in 0..MIN_JITTER_SAMPLES * 2 {
+            jitter.sample(start + Duration::from_millis(i as u64 * 50 + (i as u64 % 2) * 20));
+        }
+        assert_eq!(jitter.threshold(f32::INFINITY), Some(Duration::MAX));
+        assert_eq!(jitter.threshold(f32::MAX), Some(Duration::MAX));
+        assert_eq!(jitter.threshold(f32::NEG_INFINITY), Some(Duration::ZERO));
+        assert_eq!(jitter.threshold(f32::NAN), Some(Duration::ZERO));
+    }
+
+    #[test]
+    pub fn test_clock_drift_compares_against_frame_rate() {
+        let start = Instant::now();
+        let mut drift = ClockDrift::default();
+        assert_eq!(drift.frames_per_thousand(60), 0.0);
+        drift.sample(start, 100);
+        drift.sample(start + Duration::from_millis(500), 130);
+        // Not enough time has passed yet.
+        assert_eq!(drift.frames_per_thousand(60), 0.0);
+
+        drift.sample(start + Duration::from_secs(10), 700);
+        assert_eq!(drift.frames_per_thousand(60), 0.0);
+        drift.sample(start + Duration::from_secs(10), 706);
+        assert!((drift.frames_per_thousand(60) - 10.0).abs() < 0.01);
+        drift.sample(start + Duration::from_secs(10), 694);
+        assert!((drift.frames_per_thousand(60) + 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn test_empty_window_panics() {
+        TimeSync::<u32>::new(0);
+    }
+}
@@ -0,0 +1,194 @@
+use backroll::{
+    command::Command, transport::Peer, BackrollError, Config, P2PSession, Player, PlayerHandle,
+};
+use bevy_tasks::TaskPool;
+use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+const LATENCY: Duration = Duration::from_millis(50);
+const FRAME_TIME: Duration = Duration::from_millis(16);
+/// The first frame where the inputs of both players differ from what was predicted.
+const DIVERGENT_FRAME: i32 = 30;
+const CONFIRMED_TARGET: i32 = 90;
+
+struct TestConfig;
+
+impl Config for TestConfig {
+    type Input = u32;
+    type State = State;
+}
+
+#[derive(Clone, Debug, Default, Hash, PartialEq)]
+struct State {
+    frame: i32,
+    inputs: u64,
+}
+
+/// The scripted input for each player: constant, then changing once at the
+/// divergent frame so that the remote side mispredicts it.
+fn scripted_input(player: usize, frame: i32) -> u32 {
+    let base = player as u32 * 10 + 1;
+    if frame < DIVERGENT_FRAME {
+        base
+    } else {
+        base + 1
+    }
+}
+
+fn checksum(state: &State) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Forwards packets between two transport endpoints after a fixed delay.
+struct DelayedLink {
+    a: Peer,
+    b: Peer,
+    a_to_b: VecDeque<(Instant, Box<[u8]>)>,
+    b_to_a: VecDeque<(Instant, Box<[u8]>)>,
+}
+
+impl DelayedLink {
+    fn relay(&mut self) {
+        let now = Instant::now();
+        while let Ok(packet) = self.a.try_recv() {
+            self.a_to_b.push_back((now + LATENCY, packet));
+        }
+        while let Ok(packet) = self.b.try_recv() {
+            self.b_to_a.push_back((now + LATENCY, packet));
+        }
+        Self::deliver(&mut self.a_to_b, &self.b, now);
+        Self::deliver(&mut self.b_to_a, &self.a, now);
+    }
+
+    fn deliver(queue: &mut VecDeque<(Instant, Box<[u8]>)>, to: &Peer, now: Instant) {
+        while queue.front().map(|(time, _)| *time <= now).unwrap_or(false) {
+            let (_, packet) = queue.pop_front().unwrap();
+            let _ = to.try_send(packet);
+        }
+    }
+}
+
+struct Client {
+    session: P2PSession<TestConfig>,
+    handle: PlayerHandle,
+    player: usize,
+    state: State,
+    /// The checksum of the most recent state saved for every frame.
+    checksums: HashMap<i32, u64>,
+    rollbacks: usize,
+}
+
+impl Client {
+    fn new(players: Vec<Player>, pool: &TaskPool) -> Self {
+        let mut builder = P2PSession::<TestConfig>::build();
+        let mut handle = None;
+        let mut player = 0;
+        for (idx, config) in players.into_iter().enumerate() {
+            let is_local = matches!(config, Player::Local);
+            let added = builder.add_player(config);
+            if is_local {
+                handle = Some(added);
+                player = idx;
+            }
+        }
+        Self {
+            session: builder.start(pool.clone()).unwrap(),
+            handle: handle.unwrap(),
+            player,
+            state: Default::default(),
+            checksums: HashMap::new(),
+            rollbacks: 0,
+        }
+    }
+
+    fn run(&mut self, commands: backroll::command::Commands<TestConfig>) {
+        for command in commands {
+            match command {
+                Command::Save(save) => {
+                    self.checksums
+                        .insert(self.state.frame, checksum(&self.state));
+                    save.save(self.state.clone());
+                }
+                Command::Load(load) => {
+                    self.state = load.load();
+                    self.rollbacks += 1;
+                }
+                Command::AdvanceFrame(inputs) => {
+                    self.state.frame += 1;
+                    for player in 0..2 {
+                        let input = *inputs.get(PlayerHandle(player)).unwrap();
+                        self.state.inputs = self
+                            .state
+                            .inputs
+                            .wrapping_mul(31)
+                            .wrapping_add(input as u64);
+                    }
+                }
+                Command::Event(_) => {}
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        let commands = self.session.poll();
+        self.run(commands);
+        if !self.session.is_synchronized() {
+            return;
+        }
+        let input = scripted_input(self.player, self.session.current_frame());
+        match self.session.add_local_input(self.handle, input) {
+            Ok(()) => {
+                let commands = self.session.advance_frame();
+                self.run(commands);
+            }
+            Err(BackrollError::ReachedPredictionBarrier) => {}
+            Err(err) => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    fn confirmed_frame(&self) -> i32 {
+        self.session.confirmed_frame().unwrap_or(-1)
+    }
+}
+
+#[test]
+pub fn test_divergent_inputs_converge_after_rollback() {
+    let pool = TaskPool::new();
+    let (peer_a, link_a) = Peer::create_unbounded_pair();
+    let (peer_b, link_b) = Peer::create_unbounded_pair();
+    let mut link = DelayedLink {
+        a: link_a,
+        b: link_b,
+        a_to_b: VecDeque::new(),
+        b_to_a: VecDeque::new(),
+    };
+
+    let mut a = Client::new(vec![Player::Local, Player::Remote(peer_a)], &pool);
+    let mut b = Client::new(vec![Player::Remote(peer_b), Player::Local], &pool);
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while (a.confirmed_frame() < CONFIRMED_TARGET || b.confirmed_frame() < CONFIRMED_TARGET)
+        && Instant::now() < deadline
+    {
+        link.relay();
+        a.tick();
+        b.tick();
+        std::thread::sleep(FRAME_TIME);
+    }
+
+    let confirmed = std::cmp::min(a.confirmed_frame(), b.confirmed_frame());
+    assert!(confirmed >= CONFIRMED_TARGET);
+    assert!(a.rollbacks > 0);
+    assert!(b.rollbacks > 0);
+    for frame in 0..=confirmed {
+        assert_eq!(
+            a.checksums.get(&frame),
+            b.checksums.get(&frame),
+            "Sessions diverged at confirmed frame {}.",
+            frame
+        );
+    }
+}
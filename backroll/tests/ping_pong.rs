@@ -0,0 +1,117 @@
+use backroll::{
+    command::{Command, Commands},
+    transport::Peer,
+    BackrollError, Config, GameInput, P2PSession, Player, PlayerHandle,
+};
+use bevy_tasks::TaskPool;
+use futures::{FutureExt, Stream, StreamExt};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+const CONFIRMED_TARGET: usize = 60;
+
+struct TestConfig;
+
+impl Config for TestConfig {
+    type Input = u32;
+    type State = u64;
+}
+
+type ConfirmedInputs = Pin<Box<dyn Stream<Item = (i32, GameInput<u32>)>>>;
+
+struct Client {
+    session: P2PSession<TestConfig>,
+    handle: PlayerHandle,
+    remote: PlayerHandle,
+    /// The far end of the session's transport. Only used to read outgoing packets.
+    link: Peer,
+    stream: ConfirmedInputs,
+    confirmed: Vec<(i32, GameInput<u32>)>,
+    state: u64,
+}
+
+impl Client {
+    fn new(players: Vec<Player>, link: Peer, pool: &TaskPool) -> Self {
+        let mut builder = P2PSession::<TestConfig>::build();
+        for player in players {
+            builder.add_player(player);
+        }
+        let session = builder.start(pool.clone()).unwrap();
+        let stream = Box::pin(session.input_stream());
+        let handle = session.local_player().unwrap();
+        Self {
+            handle,
+            remote: PlayerHandle(1 - handle.0),
+            session,
+            link,
+            stream,
+            confirmed: Vec::new(),
+            state: 0,
+        }
+    }
+
+    fn run(&mut self, commands: Commands<TestConfig>) {
+        for command in commands {
+            match command {
+                Command::Save(save) => save.save(self.state),
+                Command::Load(load) => self.state = load.load(),
+                Command::AdvanceFrame(inputs) => self.state += inputs.frame as u64,
+                Command::Event(_) => {}
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        let commands = self.session.poll();
+        self.run(commands);
+        if self.session.is_synchronized() {
+            let input = self.handle.0 as u32 * 100 + self.session.current_frame() as u32;
+            match self.session.add_local_input(self.handle, input) {
+                Ok(()) => {
+                    let commands = self.session.advance_frame();
+                    self.run(commands);
+                }
+                Err(BackrollError::ReachedPredictionBarrier) => {}
+                Err(err) => panic!("Unexpected error: {:?}", err),
+            }
+        }
+        while let Some(Some(confirmed)) = self.stream.next().now_or_never() {
+            self.confirmed.push(confirmed);
+        }
+    }
+}
+
+/// Hands every packet sent by one session directly to the other, without any transport
+/// in between.
+fn shuttle(from: &Client, to: &Client) {
+    while let Ok(packet) = from.link.try_recv() {
+        to.session.receive_packet(to.remote, &packet).unwrap();
+    }
+}
+
+#[test]
+pub fn test_hand_shuttled_sessions_confirm_the_same_inputs() {
+    let pool = TaskPool::new();
+    let (peer_a, link_a) = Peer::create_unbounded_pair();
+    let (peer_b, link_b) = Peer::create_unbounded_pair();
+    let mut a = Client::new(vec![Player::Local, Player::Remote(peer_a)], link_a, &pool);
+    let mut b = Client::new(vec![Player::Remote(peer_b), Player::Local], link_b, &pool);
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while (a.confirmed.len() < CONFIRMED_TARGET || b.confirmed.len() < CONFIRMED_TARGET)
+        && Instant::now() < deadline
+    {
+        shuttle(&a, &b);
+        shuttle(&b, &a);
+        a.tick();
+        b.tick();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    assert!(a.confirmed.len() >= CONFIRMED_TARGET);
+    assert!(b.confirmed.len() >= CONFIRMED_TARGET);
+    assert_eq!(
+        a.confirmed[..CONFIRMED_TARGET],
+        b.confirmed[..CONFIRMED_TARGET]
+    );
+}
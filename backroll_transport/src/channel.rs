@@ -1,9 +1,16 @@
 use async_channel::{TryRecvError, TrySendError};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 #[derive(Clone)]
 pub struct BidirectionalAsyncChannel<T> {
     incoming: async_channel::Receiver<T>,
     outgoing: async_channel::Sender<T>,
+    paused: Arc<AtomicBool>,
+    /// Messages sent while paused are sent here instead and dropped on the next send.
+    discarded: (async_channel::Sender<T>, async_channel::Receiver<T>),
 }
 
 impl<T> BidirectionalAsyncChannel<T> {
@@ -27,9 +34,14 @@ impl<T> BidirectionalAsyncChannel<T> {
     /// If the send buffer is full, this method waits until there is
     /// space for a message.
     ///
-    /// If the peer is disconnected, this method returns an error.
+    /// If the peer is disconnected, this method returns an error. If the pair
+    /// is paused, the message is silently dropped.
     #[inline]
     pub fn send(&self, message: T) -> async_channel::Send<'_, T> {
+        if self.is_paused() {
+            self.clear_discarded();
+            return self.discarded.0.send(message);
+        }
         self.outgoing.send(message)
     }
 
@@ -45,9 +57,13 @@ impl<T> BidirectionalAsyncChannel<T> {
         self.incoming.recv()
     }
 
-    /// Attempts to send a message to the connected peer.
+    /// Attempts to send a message to the connected peer. If the pair is
+    /// paused, the message is silently dropped.
     #[inline]
     pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        if self.is_paused() {
+            return Ok(());
+        }
         self.outgoing.try_send(message)
     }
 
@@ -73,7 +89,28 @@ impl<T> BidirectionalAsyncChannel<T> {
         self.incoming.close();
     }
 
+    /// Pauses or resumes delivery of messages in both directions. While paused,
+    /// all messages sent from either end of the pair are dropped, but the pair
+    /// remains connected. Messages that were already sent are still delivered.
+    ///
+    /// This is intended for simulating a connection going silent in tests.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        if !paused {
+            self.clear_discarded();
+        }
+    }
+
+    /// Checks if delivery of messages is currently paused. See [set_paused].
+    ///
+    /// [set_paused]: Self::set_paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
     /// Gets the raw sender for the peer.
+    ///
+    /// Messages sent through it are delivered even while the pair is paused.
     pub fn sender(&self) -> async_channel::Sender<T> {
         self.outgoing.clone()
     }
@@ -95,19 +132,28 @@ impl<T> BidirectionalAsyncChannel<T> {
         self.incoming.len()
     }
 
+    fn clear_discarded(&self) {
+        while self.discarded.1.try_recv().is_ok() {}
+    }
+
     fn create_pair(
         a: (async_channel::Sender<T>, async_channel::Receiver<T>),
         b: (async_channel::Sender<T>, async_channel::Receiver<T>),
     ) -> (Self, Self) {
         let (a_send, a_recv) = a;
         let (b_send, b_recv) = b;
+        let paused = Arc::new(AtomicBool::new(false));
         let a = Self {
             incoming: a_recv,
             outgoing: b_send,
+            paused: paused.clone(),
+            discarded: async_channel::unbounded(),
         };
         let b = Self {
             incoming: b_recv,
             outgoing: a_send,
+            paused,
+            discarded: async_channel::unbounded(),
         };
         (a, b)
     }
@@ -207,6 +253,26 @@ mod test {
         assert_eq!(b.try_recv(), Err(TryRecvError::Closed));
     }
 
+    #[test]
+    pub fn paused_pairs_drop_messages_in_both_directions() {
+        let (a, b) = BidirectionalAsyncChannel::<i32>::create_unbounded_pair();
+        assert!(a.try_send(1).is_ok());
+        b.set_paused(true);
+        assert!(a.is_paused());
+        assert!(a.try_send(2).is_ok());
+        assert!(futures::executor::block_on(b.send(3)).is_ok());
+        assert!(a.is_connected() && b.is_connected());
+
+        // Messages sent before pausing are still delivered.
+        assert_eq!(b.try_recv(), Ok(1));
+        assert_eq!(b.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(a.try_recv(), Err(TryRecvError::Empty));
+
+        a.set_paused(false);
+        assert!(futures::executor::block_on(a.send(4)).is_ok());
+        assert_eq!(b.try_recv(), Ok(4));
+    }
+
     #[test]
     pub fn dropping_leads_to_disconnect() {
         let (a, b) = BidirectionalAsyncChannel::<i32>::create_unbounded_pair();
@@ -17,7 +17,7 @@ impl<T: Eq + Hash> Peers<T> {
     ///
     /// [Peer]: crate::Peer
     pub fn get(&self, id: &T) -> Option<Peer> {
-        self.0.get(&id).and_then(|kv| {
+        self.0.get(id).and_then(|kv| {
             let peer = kv.value().clone();
             if peer.is_connected() {
                 Some(peer)
@@ -32,10 +32,15 @@ impl<T: Eq + Hash> Peers<T> {
         self.0.iter().filter(|kv| kv.value().is_connected()).count()
     }
 
+    /// Checks if there are no active connections managed by it.
+    pub fn is_empty(&self) -> bool {
+        !self.0.iter().any(|kv| kv.value().is_connected())
+    }
+
     /// Checks if the store has a connection to the given ID.
     pub fn contains(&self, id: &T) -> bool {
         self.0
-            .get(&id)
+            .get(id)
             .map(|kv| kv.value().is_connected())
             .unwrap_or(false)
     }
@@ -70,7 +75,7 @@ impl<T: Eq + Hash> Peers<T> {
     ///
     /// A no-op if there no Peer with the given ID.
     pub fn disconnect(&self, id: &T) {
-        if let Some((_, peer)) = self.0.remove(&id) {
+        if let Some((_, peer)) = self.0.remove(id) {
             peer.disconnect();
         }
     }
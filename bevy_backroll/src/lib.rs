@@ -31,6 +31,7 @@ use bevy_ecs::{
     system::{Commands as BevyCommands, System},
     world::World,
 };
+use std::time::{Duration, Instant};
 use tracing::{debug, error};
 
 #[cfg(feature = "steam")]
@@ -91,6 +92,24 @@ impl FrameStaller {
     }
 }
 
+/// Time spent handling each kind of Backroll command during the most recent run of a
+/// [BackrollStage]. Includes all commands issued during rollbacks. Available as a
+/// resource after the stage has run at least once.
+///
+/// [BackrollStage]: self::BackrollStage
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameTimings {
+    /// Total time spent saving the world.
+    pub save: Duration,
+    /// Total time spent loading the world.
+    pub load: Duration,
+    /// Total time spent running simulation systems.
+    pub advance: Duration,
+    /// Time spent inside [P2PSession::poll] and [P2PSession::advance_frame],
+    /// excluding the time spent handling the commands they produced.
+    pub poll: Duration,
+}
+
 /// A [Stage] that transparently runs and handles Backroll sessions.
 ///
 /// Each time the stage runs, it will poll the Backroll session, sample local player
@@ -136,6 +155,7 @@ where
         Option<Box<dyn System<In = PlayerHandle, Out = T::Input> + Send + Sync + 'static>>,
     save_world_fn: Option<Box<dyn System<In = (), Out = T::State> + Send + Sync + 'static>>,
     load_world_fn: Option<Box<dyn System<In = T::State, Out = ()> + Send + Sync + 'static>>,
+    timings: FrameTimings,
 }
 
 impl<T: Config> Default for BackrollStage<T> {
@@ -147,13 +167,20 @@ impl<T: Config> Default for BackrollStage<T> {
             input_sample_fn: None,
             save_world_fn: None,
             load_world_fn: None,
+            timings: Default::default(),
         }
     }
 }
 
 impl<T: Config> BackrollStage<T> {
+    /// Gets the time spent handling commands during the most recent run of the stage.
+    pub fn last_frame_timings(&self) -> FrameTimings {
+        self.timings
+    }
+
     fn run_commands(&mut self, commands: Commands<T>, world: &mut World) {
         for command in commands {
+            let start = Instant::now();
             match command {
                 Command::<T>::Save(save_state) => {
                     let state =
@@ -162,17 +189,20 @@ impl<T: Config> BackrollStage<T> {
                             .expect("No world save system found. Please use AppBuilder::with_world_load_system")
                             .run((), world);
                     save_state.save(state);
+                    self.timings.save += start.elapsed();
                 }
                 Command::<T>::Load(load_state) => {
                     self.load_world_fn
                         .as_mut()
                         .expect("No world load system found. Please use AppBuilder::with_world_load_system")
                         .run(load_state.load(), world);
+                    self.timings.load += start.elapsed();
                 }
                 Command::AdvanceFrame(inputs) => {
                     // Insert input via Resource
                     *world.get_resource_mut::<GameInput<T::Input>>().unwrap() = inputs;
                     self.schedule.run_once(world);
+                    self.timings.advance += start.elapsed();
                 }
                 Command::Event(evt) => {
                     debug!("Received Backroll Event: {:?}", evt);
@@ -210,9 +240,15 @@ impl<T: Config> Stage for BackrollStage<T> {
                 return;
             };
 
+            self.timings = Default::default();
+            let start = Instant::now();
+            let commands = session.poll();
+            self.timings.poll = start.elapsed();
+
             world.insert_resource(GameInput::<T::Input>::default());
-            self.run_commands(session.poll(), world);
+            self.run_commands(commands, world);
             world.remove_resource::<GameInput<T::Input>>();
+            world.insert_resource(self.timings);
 
             if self.staller.should_stall() {
                 continue;
@@ -234,8 +270,12 @@ impl<T: Config> Stage for BackrollStage<T> {
             }
 
             world.insert_resource(GameInput::<T::Input>::default());
-            self.run_commands(session.advance_frame(), world);
+            let start = Instant::now();
+            let commands = session.advance_frame();
+            self.timings.poll += start.elapsed();
+            self.run_commands(commands, world);
             world.remove_resource::<GameInput<T::Input>>();
+            world.insert_resource(self.timings);
 
             if let ShouldRun::Yes = should_run {
                 return;
@@ -453,3 +493,72 @@ impl<'a> BackrollCommands for BevyCommands<'a> {
         self.remove_resource::<P2PSession<T>>();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use backroll::{P2PSessionBuilder, Player};
+    use bevy_ecs::system::{In, IntoSystem};
+    use bevy_tasks::TaskPool;
+
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type Input = u32;
+        type State = u32;
+    }
+
+    const STEP: Duration = Duration::from_millis(2);
+
+    fn sample_input(_: In<PlayerHandle>) -> u32 {
+        0
+    }
+
+    fn save_world() -> u32 {
+        std::thread::sleep(STEP);
+        0
+    }
+
+    fn load_world(_: In<u32>) {}
+
+    fn simulate() {
+        std::thread::sleep(STEP);
+    }
+
+    #[test]
+    pub fn test_stage_records_frame_timings() {
+        let mut world = World::default();
+        world.insert_resource(Events::<Event>::default());
+        let mut builder = P2PSessionBuilder::<TestConfig>::new();
+        builder.add_player(Player::Local);
+        world.insert_resource(builder.start(TaskPool::new()).unwrap());
+
+        let mut stage = BackrollStage::<TestConfig>::default();
+        stage
+            .schedule
+            .add_stage(BACKROLL_LOGIC_UPDATE, SystemStage::single_threaded());
+        stage
+            .schedule
+            .add_system_to_stage(BACKROLL_LOGIC_UPDATE, simulate.system());
+        let mut input_sample_fn = sample_input.system();
+        input_sample_fn.initialize(&mut world);
+        stage.input_sample_fn = Some(Box::new(input_sample_fn));
+        let mut save_world_fn = save_world.system();
+        save_world_fn.initialize(&mut world);
+        stage.save_world_fn = Some(Box::new(save_world_fn));
+        let mut load_world_fn = load_world.system();
+        load_world_fn.initialize(&mut world);
+        stage.load_world_fn = Some(Box::new(load_world_fn));
+
+        stage.run(&mut world);
+
+        let timings = stage.last_frame_timings();
+        assert!(timings.save >= STEP);
+        assert!(timings.advance >= STEP);
+        // A session with only local players never rolls back.
+        assert_eq!(timings.load, Duration::ZERO);
+        let resource = world.get_resource::<FrameTimings>().unwrap();
+        assert_eq!(resource.save, timings.save);
+        assert_eq!(resource.advance, timings.advance);
+    }
+}